@@ -0,0 +1,82 @@
+//! Timing and allocation counts for the codec and the two tree layouts.
+//! This is a plain `harness = false` binary rather than a criterion bench,
+//! because criterion is not available to the build; it reports min and
+//! median wall time over a few runs, without criterion's statistics.
+
+#![allow(clippy::needless_return)]
+
+use std::alloc::{ GlobalAlloc, Layout, System };
+use std::hint::black_box;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+use image::{ DynamicImage, ImageBuffer, Rgb, RgbImage };
+use quad_compress::compressor::ImgCompressor;
+use quad_compress::quadtree::{ FlatQuadtree, Quadtree, Rect };
+
+/// Counts heap allocations so the tree layouts can be compared.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        return System.alloc(layout);
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const RANK: u32 = 512;
+const SAMPLES: usize = 10;
+
+fn synthetic_image(rank: u32) -> RgbImage {
+    return ImageBuffer::from_fn(rank, rank, |x, y| {
+        let (fx, fy) = (x as f32 / 37f32, y as f32 / 53f32);
+        let wave = (fx.sin() * fy.cos() * 100f32) as i32;
+        let noise = ((x * 7919 + y * 104729) % 13) as i32;
+        return Rgb([
+            (128 + wave + noise) as u8,
+            (x * 255 / rank) as u8,
+            if (x / 64 + y / 64) % 2 == 0 { 60 } else { 190 },
+        ]);
+    });
+}
+
+/// Runs `f` a few times and reports the fastest and median wall time, and
+/// the allocations of one run.
+fn bench<T>(name: &str, mut f: impl FnMut() -> T) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    let mut times: Vec<Duration> = (0..SAMPLES).map(|_| {
+        let start = Instant::now();
+        black_box(f());
+        return start.elapsed();
+    }).collect();
+    times.sort();
+    println!("{:<14} min {:>10.2?}  median {:>10.2?}  {:>8} allocations", name, times[0], times[SAMPLES / 2], allocations);
+}
+
+fn main() {
+    let img = DynamicImage::ImageRgb8(synthetic_image(RANK));
+    bench("build", || ImgCompressor::new(img.clone()).unwrap());
+    let compressor = ImgCompressor::new(img.clone()).unwrap();
+    bench("to_file", || compressor.to_file((2, 2, 2)));
+    let decoded = ImgCompressor::from_file(&compressor.to_file((2, 2, 2))).unwrap();
+    bench("to_image", || decoded.to_image((0, 0, 0)));
+
+    let plane: Vec<u8> = synthetic_image(1024).pixels().map(|pixel| pixel.0[0]).collect();
+    let rect = Rect { x: 0, y: 0, width: 1024, height: 1024 };
+    let mut out = vec![0; 1024 * 1024];
+    bench("boxed build", || Quadtree::new(&plane));
+    bench("flat build", || FlatQuadtree::new(&plane));
+    let (boxed, flat) = (Quadtree::new(&plane), FlatQuadtree::new(&plane));
+    bench("boxed get_rect", || boxed.get_rect(rect, 2, &mut out));
+    bench("flat get_rect", || flat.get_rect(rect, 2, &mut out));
+}
@@ -0,0 +1,392 @@
+use bitvec::prelude::Local;
+use bitvec::slice::BitSlice;
+use bitvec::vec::BitVec;
+use image::RgbImage;
+use crate::compressor::{ ColorSpace, CompressError, Cutoff, ImgCompressor };
+use crate::header::blob_bytes;
+use crate::quadtree::Quadtree;
+use crate::serialize::{ build_leaf_data, build_leaf_index, read_leaf_tree, read_terminal, BoundedReader, DecodeError, LeafCoding };
+
+pub const ANIM_MAGIC: [u8; 4] = *b"SKIA";
+
+pub const KEYFRAME_INTERVAL: u32 = 30;
+
+const KEY_FRAME: u8 = 0;
+const DELTA_FRAME: u8 = 1;
+
+type Roots = [Box<Quadtree>; 3];
+
+/// Compresses a sequence of equally sized frames into one container:
+///
+/// | bytes | field                                  |
+/// |-------|----------------------------------------|
+/// | 4     | magic `SKIA`                           |
+/// | 4 + 4 | width, height                          |
+/// | 4     | frame count                            |
+/// | 3     | luma, cb, cr cutoffs shared by frames  |
+///
+/// Each frame follows as a kind byte and, per channel, a bit stream and a
+/// data stream (u32 length, then bytes). Key frames hold the index and leaf
+/// data of `build_leaf_index` and `build_leaf_data`. Delta frames walk the
+/// tree from the root with one bit per node: clear copies the node from the
+/// previous frame, set re-encodes it, followed above 2x2 by a bit that splits
+/// it into four such nodes or makes it a terminal with four data bytes. A
+/// node is copied when none of its values is more than `copy_threshold` away
+/// from the previous frame's reconstruction.
+pub struct AnimCompressor {
+    pub keyframe_interval: u32,
+    pub copy_threshold: u8,
+    width: u32,
+    height: u32,
+    cutoffs: Cutoff,
+    count: u32,
+    frames: Vec<u8>,
+    previous: Option<Roots>,
+}
+
+impl AnimCompressor {
+    pub fn new(width: u32, height: u32, cutoffs: Cutoff) -> AnimCompressor {
+        return AnimCompressor {
+            keyframe_interval: KEYFRAME_INTERVAL,
+            copy_threshold: 0,
+            width,
+            height,
+            cutoffs,
+            count: 0,
+            frames: vec![],
+            previous: None,
+        };
+    }
+
+    pub fn push_frame(&mut self, rgb: &RgbImage) -> Result<(), CompressError> {
+        let expected = self.width as usize * self.height as usize * 3;
+        if rgb.dimensions() != (self.width, self.height) {
+            return Err(CompressError::BufferSize { expected, actual: rgb.as_raw().len() });
+        }
+        let compressor = ImgCompressor::from_rgb(rgb.as_raw(), self.width, self.height)?;
+        let sources = [&*compressor.lumin_root, &*compressor.c_blu_root, &*compressor.c_red_root];
+        let cutoffs = [self.cutoffs.0, self.cutoffs.1, self.cutoffs.2];
+        let key = key_frame(sources, cutoffs);
+        let rank = compressor.rank as usize;
+        let current = decode_frame(&mut BoundedReader::new(&key), None, rank).expect("a key frame decodes");
+        let frame = match &self.previous {
+            Some(previous) if !self.count.is_multiple_of(self.keyframe_interval.max(1)) => {
+                let delta = delta_frame(sources, &current, previous, cutoffs, self.copy_threshold);
+                if delta.len() < key.len() { Some(delta) } else { None }
+            },
+            _ => None,
+        };
+        let previous = match frame {
+            Some(delta) => {
+                let decoded = decode_frame(&mut BoundedReader::new(&delta), self.previous.as_ref(), rank).expect("a delta frame decodes");
+                self.frames.extend_from_slice(&delta);
+                decoded
+            },
+            None => {
+                self.frames.extend_from_slice(&key);
+                current
+            },
+        };
+        self.previous = Some(previous);
+        self.count += 1;
+        return Ok(());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut bytes = ANIM_MAGIC.to_vec();
+        for value in [self.width, self.height, self.count].iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[self.cutoffs.0, self.cutoffs.1, self.cutoffs.2]);
+        bytes.extend_from_slice(&self.frames);
+        return bytes;
+    }
+}
+
+fn key_frame(sources: [&Quadtree; 3], cutoffs: [u8; 3]) -> Vec<u8> {
+    let mut bytes = vec![KEY_FRAME];
+    for (source, cutoff) in sources.iter().zip(cutoffs.iter()) {
+        let mut index: BitVec<Local, u8> = BitVec::new();
+        let mut data = vec![];
+        build_leaf_index(source, &mut index, cutoff, LeafCoding::default());
+        build_leaf_data(source, &mut data, cutoff, LeafCoding::default());
+        bytes.extend_from_slice(&blob_bytes(index.as_slice()));
+        bytes.extend_from_slice(&blob_bytes(&data));
+    }
+    return bytes;
+}
+
+fn delta_frame(sources: [&Quadtree; 3], current: &Roots, previous: &Roots, cutoffs: [u8; 3], threshold: u8) -> Vec<u8> {
+    let mut bytes = vec![DELTA_FRAME];
+    for channel in 0..3 {
+        let mut bits: BitVec<Local, u8> = BitVec::new();
+        let mut data = vec![];
+        let cutoff = (cutoffs[channel], threshold);
+        delta_node(sources[channel], &current[channel], &previous[channel], cutoff, &mut bits, &mut data);
+        bytes.extend_from_slice(&blob_bytes(bits.as_slice()));
+        bytes.extend_from_slice(&blob_bytes(&data));
+    }
+    return bytes;
+}
+
+fn children(quadtree: &Quadtree) -> Option<[&Quadtree; 4]> {
+    return match quadtree {
+        Quadtree::Branch(a, b, c, d, _, _) => Some([a, b, c, d]),
+        Quadtree::Leaf(..) => None,
+    };
+}
+
+/// Whether every value of `current` is within `threshold` of `previous`; the
+/// averages and extremes are compared first since they can only move less.
+fn within(current: &Quadtree, previous: &Quadtree, threshold: u8) -> bool {
+    let close = |a: u8, b: u8| a.abs_diff(b) <= threshold;
+    if !close(current.average(), previous.average()) || !close(current.low(), previous.low()) || !close(current.high(), previous.high()) {
+        return false;
+    }
+    return match (current, previous) {
+        (Quadtree::Leaf(a, b, c, d), Quadtree::Leaf(e, f, g, h)) => close(*a, *e) && close(*b, *f) && close(*c, *g) && close(*d, *h),
+        _ => match (children(current), children(previous)) {
+            (Some(currents), Some(previouses)) => (0..4).all(|i| within(currents[i], previouses[i], threshold)),
+            _ => false,
+        },
+    };
+}
+
+/// `current` is the source as a key frame would reconstruct it; nodes close
+/// enough to `previous` are copied.
+fn delta_node(
+    source: &Quadtree,
+    current: &Quadtree,
+    previous: &Quadtree,
+    (cutoff, threshold): (u8, u8),
+    bits: &mut BitVec<Local, u8>,
+    data: &mut Vec<u8>
+) {
+    if within(current, previous, threshold) {
+        bits.push(false);
+        return;
+    }
+    bits.push(true);
+    let split = match source {
+        Quadtree::Branch(_, _, _, _, _, meta) => meta.high - meta.low >= cutoff,
+        Quadtree::Leaf(..) => false,
+    };
+    if source.size() > 2 {
+        bits.push(split);
+    }
+    match (split, children(source), children(current), children(previous)) {
+        (true, Some(sources), Some(currents), Some(previouses)) => {
+            for i in 0..4 {
+                delta_node(sources[i], currents[i], previouses[i], (cutoff, threshold), bits, data);
+            }
+        },
+        _ => build_leaf_data(source, data, &cutoff, LeafCoding::default()),
+    }
+}
+
+fn read_bit(bits: &BitSlice<Local, u8>, position: &mut usize) -> Result<bool, DecodeError> {
+    let bit = *bits.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    return Ok(bit);
+}
+
+fn read_delta(
+    bits: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    previous: &Quadtree
+) -> Result<Box<Quadtree>, DecodeError> {
+    if !read_bit(bits, position)? {
+        return Ok(Box::new(previous.clone()));
+    }
+    let size = previous.size();
+    let split = size > 2 && read_bit(bits, position)?;
+    let previouses = match children(previous) {
+        Some(previouses) if split => previouses,
+        _ => return read_terminal(false, size, data),
+    };
+    let a = read_delta(bits, position, data, previouses[0])?;
+    let b = read_delta(bits, position, data, previouses[1])?;
+    let c = read_delta(bits, position, data, previouses[2])?;
+    let d = read_delta(bits, position, data, previouses[3])?;
+    return Ok(Quadtree::join(a, b, c, d));
+}
+
+fn decode_frame(reader: &mut BoundedReader, previous: Option<&Roots>, rank: usize) -> Result<Roots, DecodeError> {
+    let kind = reader.section(1)?[0];
+    let mut channel = |channel: usize| -> Result<Box<Quadtree>, DecodeError> {
+        let bits = BitSlice::<Local, u8>::from_slice(reader.blob()?);
+        let data = &mut reader.blob()?.iter().copied();
+        return match (kind, previous) {
+            (KEY_FRAME, _) => read_leaf_tree(bits, data, rank),
+            (DELTA_FRAME, Some(previous)) => read_delta(bits, &mut 0, data, &previous[channel]),
+            _ => Err(DecodeError::UnexpectedEof),
+        };
+    };
+    return Ok([channel(0)?, channel(1)?, channel(2)?]);
+}
+
+/// Reads an `AnimCompressor` container; `frames` decodes it front to back.
+pub struct AnimDecoder<'a> {
+    width: u32,
+    height: u32,
+    count: u32,
+    body: &'a [u8],
+}
+
+impl<'a> AnimDecoder<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<AnimDecoder<'a>, DecodeError> {
+        let mut reader = BoundedReader::new(bytes);
+        let mut u32 = || -> Result<u32, DecodeError> {
+            let b = reader.section(4)?;
+            return Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        };
+        let magic = u32()?.to_le_bytes();
+        if magic != ANIM_MAGIC {
+            return Err(DecodeError::BadMagic(magic));
+        }
+        let (width, height, count) = (u32()?, u32()?, u32()?);
+        reader.section(3)?;
+        return Ok(AnimDecoder { width, height, count, body: reader.rest() });
+    }
+
+    pub fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    pub fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        return self.count;
+    }
+
+    pub fn frames(&self) -> AnimFrames<'a> {
+        return AnimFrames {
+            reader: BoundedReader::new(self.body),
+            width: self.width,
+            height: self.height,
+            remaining: self.count,
+            previous: None,
+        };
+    }
+}
+
+pub struct AnimFrames<'a> {
+    reader: BoundedReader<'a>,
+    width: u32,
+    height: u32,
+    remaining: u32,
+    previous: Option<Roots>,
+}
+
+impl<'a> Iterator for AnimFrames<'a> {
+    type Item = Result<RgbImage, DecodeError>;
+
+    fn next(&mut self) -> Option<Result<RgbImage, DecodeError>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let rank = (self.width.max(self.height) as usize).next_power_of_two().max(2);
+        let roots = match decode_frame(&mut self.reader, self.previous.as_ref(), rank) {
+            Ok(roots) => roots,
+            Err(error) => {
+                self.remaining = 0;
+                return Some(Err(error));
+            },
+        };
+        let compressor = ImgCompressor::from_roots(roots.clone(), rank as u32, (self.width, self.height), (128, 128, 128), ColorSpace::YCbCr);
+        self.previous = Some(roots);
+        return Some(Ok(compressor.to_image((0, 0, 0))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ ImageBuffer, Rgb };
+    use super::*;
+
+    fn frame(shift: u32) -> RgbImage {
+        return ImageBuffer::from_fn(48, 40, |x, y| {
+            let lit = (x + shift) % 48 < 12 && (8..20).contains(&y);
+            return if lit { Rgb([240, 40, 40]) } else { Rgb([(x * 5) as u8, (y * 6) as u8, 90]) };
+        });
+    }
+
+    fn decode_all(bytes: &[u8]) -> Vec<RgbImage> {
+        return AnimDecoder::parse(bytes).unwrap().frames().map(|frame| frame.unwrap()).collect();
+    }
+
+    #[test]
+    fn three_frames_decode_back_from_key_and_delta_frames() {
+        let frames = [frame(0), frame(4), frame(4)];
+        let mut keys = AnimCompressor::new(48, 40, (0, 0, 0));
+        keys.keyframe_interval = 1;
+        let mut deltas = AnimCompressor::new(48, 40, (0, 0, 0));
+        for frame in frames.iter() {
+            keys.push_frame(frame).unwrap();
+            deltas.push_frame(frame).unwrap();
+        }
+        let (keys, deltas) = (keys.finish(), deltas.finish());
+        assert_eq!(AnimDecoder::parse(&deltas).unwrap().frame_count(), 3);
+        assert!(deltas.len() < keys.len());
+        let expected: Vec<RgbImage> = frames.iter()
+            .map(|frame| crate::testutil::compressor(frame).to_image((0, 0, 0)))
+            .collect();
+        assert_eq!(decode_all(&keys), expected);
+        assert_eq!(decode_all(&deltas), expected);
+    }
+
+    #[test]
+    fn a_static_background_is_copied_between_frames() {
+        let scene = |t: u32| -> RgbImage {
+            return ImageBuffer::from_fn(64, 64, |x, y| {
+                if x >= 8 * t && x < 8 * t + 16 && (24..40).contains(&y) {
+                    return Rgb([250, 250, 0]);
+                }
+                let noise = ((x * 31 + y * 17 + t * 7) % 3) as u8;
+                return Rgb([(x * 3) as u8 + noise, (y * 3) as u8, 120 + noise]);
+            });
+        };
+        let mut keys = AnimCompressor::new(64, 64, (0, 0, 0));
+        keys.keyframe_interval = 1;
+        let mut deltas = AnimCompressor::new(64, 64, (0, 0, 0));
+        deltas.copy_threshold = 4;
+        for t in 0..5 {
+            keys.push_frame(&scene(t)).unwrap();
+            deltas.push_frame(&scene(t)).unwrap();
+        }
+        let (keys, deltas) = (keys.finish(), deltas.finish());
+        assert!(deltas.len() * 3 < keys.len(), "{} vs {} bytes", deltas.len(), keys.len());
+        let frames = decode_all(&deltas);
+        let first = crate::testutil::compressor(&scene(0)).to_image((0, 0, 0));
+        for (t, frame) in frames.iter().enumerate() {
+            let t = t as u32;
+            let key = crate::testutil::compressor(&scene(t)).to_image((0, 0, 0));
+            for (x, y, pixel) in frame.enumerate_pixels() {
+                if (x, y) == (8 * t + 4, 30) {
+                    assert_eq!(pixel, key.get_pixel(x, y));
+                }
+                if x >= 48 && y < 16 {
+                    assert_eq!(pixel, first.get_pixel(x, y));
+                }
+                let error = pixel.0.iter().zip(key.get_pixel(x, y).0.iter()).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+                assert!(error <= 6, "frame {} pixel {},{} off by {}", t, x, y, error);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_frames_and_foreign_bytes() {
+        let mut compressor = AnimCompressor::new(48, 40, (4, 4, 4));
+        assert!(compressor.push_frame(&RgbImage::new(40, 48)).is_err());
+        compressor.push_frame(&frame(0)).unwrap();
+        let bytes = compressor.finish();
+        assert_eq!(AnimDecoder::parse(&bytes[..8]).map(|_| ()), Err(DecodeError::OutOfBounds { offset: 8, len: 4, end: 8 }));
+        assert_eq!(AnimDecoder::parse(b"SKIQ....").map(|_| ()), Err(DecodeError::BadMagic(*b"SKIQ")));
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(AnimDecoder::parse(truncated).unwrap().frames().next().unwrap().is_err());
+    }
+}
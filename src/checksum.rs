@@ -0,0 +1,93 @@
+use std::io::{ self, Read };
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut value = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 != 0 { (value >> 1) ^ POLYNOMIAL } else { value >> 1 };
+            bit += 1;
+        }
+        table[i] = value;
+        i += 1;
+    }
+    return table;
+}
+
+/// Running CRC-32 (the zlib / PNG polynomial) for the `.ski` file footer.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        return Crc32 { state: !0 };
+    }
+}
+
+impl Crc32 {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state = TABLE[((self.state ^ *byte as u32) & 0xFF) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        return !self.state;
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::default();
+    crc.update(bytes);
+    return crc.finish();
+}
+
+/// Passes reads through while hashing every byte that goes by.
+pub struct CrcReader<R> {
+    inner: R,
+    crc: Crc32,
+}
+
+impl<R: Read> CrcReader<R> {
+    pub fn new(inner: R) -> CrcReader<R> {
+        return CrcReader { inner, crc: Crc32::default() };
+    }
+
+    pub fn checksum(&self) -> u32 {
+        return self.crc.finish();
+    }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        return Ok(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_reference_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let mut crc = Crc32::default();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+        let mut reader = CrcReader::new(&b"123456789"[..]);
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!((read.len(), reader.checksum()), (9, 0xCBF4_3926));
+    }
+}
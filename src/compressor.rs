@@ -1,12 +1,36 @@
+use std::borrow::Cow;
+use std::io::{ self, Read, Write };
+use std::ops::RangeInclusive;
+use log::{ debug, log_enabled, trace, warn, Level };
 use bitvec::prelude::Local;
+use bitvec::slice::BitSlice;
 use bitvec::vec::BitVec;
-use image::{ RgbImage, DynamicImage, ImageBuffer, Pixel };
-use image::error::ImageResult;
-use crate::quadtree::Quadtree;
-use crate::serialize::{ build_leaf_index, build_leaf_data };
+use image::{ ColorType, GrayImage, Rgb, RgbImage, DynamicImage, ImageBuffer, Luma, Pixel };
+use crate::checksum::{ crc32, Crc32, CrcReader };
+use crate::dither::Dither;
+use crate::entropy::{ self, codec_for_id, CompressionLevel, Deflate, EntropyBackend, EntropyCodec, EntropyError };
+use crate::lut::Lut3d;
+use crate::mask::{ ImportanceMask, MseBound, RegionOfInterest };
+use crate::metrics::{ psnr, ssim_rgb };
+use crate::palette::{ median_cut, nearest, palette_bytes, quantize, read_palette, Color };
+use crate::quadtree::{pad_to_pow2, CutoffMap, Interpolation, MinLeaf, Point, Quadtree, Rect, Region, TreeStats};
+use crate::header::{
+    blob_bytes, parse_header, FileHeader, FLAG_BIT_PACKED, FLAG_CHANNEL_CODECS, FLAG_CHECKSUM, FLAG_HALF_CHROMA, FLAG_HUFFMAN, FLAG_DITHER, FLAG_EXIF, FLAG_GRAYSCALE, FLAG_ICC, FLAG_PALETTE, FLAG_PLANAR_LEAVES, FLAG_RAW_CHANNELS, FLAG_RLE_INDEX,
+    FLAG_SIBLING_DELTAS, FLAG_SINGLE_STREAM, FLAG_VLC_RESIDUALS, FLAG_WIDE_SAMPLES,
+    HEADER_LEN, MAGIC, VERSION
+};
+use crate::serialize::{
+    build_leaf_index, build_leaf_data, coarsen_leaf_tree, decode_residuals, encode_residuals, huffman_decode, huffman_encode, interleave, planarize, read_leaf_tree,
+    join_stream, pack_values, read_leaf_region, read_leaf_thumbnail, rle_decode_index, rle_encode_index, salvage_leaf_tree, split_stream, split_stream_lenient, undo_sibling_deltas,
+    undo_sibling_deltas_lenient, unpack_values, BoundedReader,
+    DecodeError, HuffTable, LeafCoding
+};
 
 type Pix = (u8, u8, u8, u8);
+const CHROMA_SCALE: u16 = 2;
 pub type Cutoff = (u8, u8, u8);
+/// A channel's index bits, plain leaf values and rank, as stored.
+type StoredStream = (BitVec<Local, u8>, Vec<u8>, usize);
 
 fn clamp_u8(x: f32) -> u8 {
     if x < 0f32 { return 0u8 }
@@ -38,81 +62,2879 @@ fn ycca_to_rgba(ycc: Pix) -> Pix {
     );
 }
 
+fn rgb_to_ycocg(rgb: Pix) -> Pix {
+    let (r, g, b) = (rgb.0 as i32, rgb.1 as i32, rgb.2 as i32);
+    let y = (r + 2 * g + b + 2) >> 2;
+    let co = ((r - b + 1) >> 1) + 128;
+    let cg = ((2 * g - r - b + 2) >> 2) + 128;
+    return (y as u8, co.clamp(0, 255) as u8, cg.clamp(0, 255) as u8, rgb.3);
+}
+
+fn ycocg_to_rgba(ycc: Pix) -> Pix {
+    let (y, co, cg) = (ycc.0 as i32, ycc.1 as i32 - 128, ycc.2 as i32 - 128);
+    let t = y - cg;
+    let channel = |v: i32| v.clamp(0, 255) as u8;
+    return (channel(t + co), channel(y + cg), channel(t - co), ycc.3);
+}
+
+fn half(v: u8) -> u8 {
+    return ((v as i8) >> 1) as u8;
+}
+
+fn rgb_to_ycocg_r(rgb: Pix) -> Pix {
+    let co = rgb.0.wrapping_sub(rgb.2);
+    let t = rgb.2.wrapping_add(half(co));
+    let cg = rgb.1.wrapping_sub(t);
+    let y = t.wrapping_add(half(cg));
+    return (y, co.wrapping_add(128), cg.wrapping_add(128), rgb.3);
+}
+
+fn ycocg_r_to_rgba(ycc: Pix) -> Pix {
+    let co = ycc.1.wrapping_sub(128);
+    let cg = ycc.2.wrapping_sub(128);
+    let t = ycc.0.wrapping_sub(half(cg));
+    let g = cg.wrapping_add(t);
+    let b = t.wrapping_sub(half(co));
+    return (b.wrapping_add(co), g, b, ycc.3);
+}
+
+/// `YCoCgR` is the lifting form of YCoCg computed modulo 256, so it is
+/// exactly invertible. At cutoff 0 no node collapses and every leaf keeps
+/// its four samples, so together they reconstruct the input bit for bit.
+/// `YCoCg` is its plain integer form, which rounds but needs no wrapping
+/// arithmetic. `Rgb` skips the transform and builds the trees on R, G and B
+/// directly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    YCbCr,
+    YCoCgR,
+    Rgb,
+    YCoCg,
+}
+
+impl ColorSpace {
+    pub fn id(self) -> u8 {
+        return match self {
+            ColorSpace::YCbCr => 0,
+            ColorSpace::YCoCgR => 1,
+            ColorSpace::Rgb => 2,
+            ColorSpace::YCoCg => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<ColorSpace> {
+        return match id {
+            0 => Some(ColorSpace::YCbCr),
+            1 => Some(ColorSpace::YCoCgR),
+            2 => Some(ColorSpace::Rgb),
+            3 => Some(ColorSpace::YCoCg),
+            _ => None,
+        }
+    }
+
+    fn forward(self, rgb: Pix) -> Pix {
+        return match self {
+            ColorSpace::YCbCr => rgb_to_ycc(rgb),
+            ColorSpace::YCoCgR => rgb_to_ycocg_r(rgb),
+            ColorSpace::Rgb => rgb,
+            ColorSpace::YCoCg => rgb_to_ycocg(rgb),
+        }
+    }
+
+    fn inverse(self, ycc: Pix) -> Pix {
+        return match self {
+            ColorSpace::YCbCr => ycca_to_rgba(ycc),
+            ColorSpace::YCoCgR => ycocg_r_to_rgba(ycc),
+            ColorSpace::Rgb => ycc,
+            ColorSpace::YCoCg => ycocg_to_rgba(ycc),
+        }
+    }
+}
+
+fn median(plane: &[u8]) -> u8 {
+    let mut sorted = plane.to_vec();
+    sorted.sort_unstable();
+    return sorted[sorted.len() / 2];
+}
+
+#[cfg(feature = "rayon")]
+fn join3<A: Send, B: Send, C: Send>(
+    a: impl FnOnce() -> A + Send,
+    b: impl FnOnce() -> B + Send,
+    c: impl FnOnce() -> C + Send
+) -> (A, B, C) {
+    let (a, (b, c)) = rayon::join(a, || rayon::join(b, c));
+    return (a, b, c);
+}
+
+#[cfg(not(feature = "rayon"))]
+fn join3<A, B, C>(a: impl FnOnce() -> A, b: impl FnOnce() -> B, c: impl FnOnce() -> C) -> (A, B, C) {
+    return (a(), b(), c());
+}
+
+fn scaled_cutoffs(luma: u8) -> Cutoff {
+    let chroma = (luma as u16 * CHROMA_SCALE).min(255) as u8;
+    return (luma, chroma, chroma);
+}
+
+fn halve(quadtree_root: &Quadtree) -> Box<Quadtree> {
+    let size = quadtree_root.size() / 2;
+    return Quadtree::from_fn(size, |(x, y)| {
+        let sum: u16 = [(0, 0), (1, 0), (0, 1), (1, 1)].iter()
+            .map(|(dx, dy)| quadtree_root.get((2 * x + dx, 2 * y + dy)) as u16)
+            .sum();
+        return ((sum + 2) / 4) as u8;
+    });
+}
+
+fn neutral_chroma() -> Box<Quadtree> {
+    return Box::new(Quadtree::Leaf(128, 128, 128, 128));
+}
+
+fn subtract_prediction(plane: &mut [u8], prediction: u8) {
+    for value in plane.iter_mut() {
+        *value = value.wrapping_sub(prediction).wrapping_add(128);
+    }
+}
+
+fn add_prediction(residual: u8, prediction: u8) -> u8 {
+    return residual.wrapping_add(prediction).wrapping_sub(128);
+}
+
+pub fn is_power_of_two(n: u32) -> bool {
+    return n.is_power_of_two();
+}
+
+pub fn is_square(width: u32, height: u32) -> bool {
+    return width == height;
+}
+
+pub fn next_compressible(width: u32, height: u32) -> (u32, u32) {
+    let rank = width.max(height).next_power_of_two().max(2);
+    return (rank, rank);
+}
+
+fn non_empty((width, height): (u32, u32)) -> Result<(), CompressError> {
+    return if width == 0 || height == 0 { Err(CompressError::EmptyImage) } else { Ok(()) };
+}
+
+fn non_empty_rgb(img: DynamicImage) -> Result<RgbImage, CompressError> {
+    let rgb = img.to_rgb8();
+    non_empty(rgb.dimensions())?;
+    return Ok(rgb);
+}
+
+fn validate_min_leaf(min_leaf: usize) -> Result<(), CompressError> {
+    return if min_leaf < 2 || !min_leaf.is_power_of_two() { Err(CompressError::InvalidMinLeaf(min_leaf)) } else { Ok(()) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Normal,
+    MirrorHorizontal,
+    Rotate180,
+    MirrorVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    pub fn from_exif(value: u8) -> Option<Orientation> {
+        use Orientation::*;
+        return [Normal, MirrorHorizontal, Rotate180, MirrorVertical, Transpose, Rotate90, Transverse, Rotate270]
+            .get((value as usize).wrapping_sub(1))
+            .cloned();
+    }
+
+    fn swaps_axes(self) -> bool {
+        use Orientation::*;
+        return matches!(self, Transpose | Rotate90 | Transverse | Rotate270);
+    }
+
+    fn source(self, (x, y): (u32, u32), (width, height): (u32, u32)) -> (u32, u32) {
+        use Orientation::*;
+        return match self {
+            Normal => (x, y),
+            MirrorHorizontal => (width - 1 - x, y),
+            Rotate180 => (width - 1 - x, height - 1 - y),
+            MirrorVertical => (x, height - 1 - y),
+            Transpose => (y, x),
+            Rotate90 => (y, height - 1 - x),
+            Transverse => (width - 1 - y, height - 1 - x),
+            Rotate270 => (width - 1 - y, x),
+        };
+    }
+}
+
+pub fn decode_as(bytes: &[u8], color: ColorType) -> Result<DynamicImage, DecodeError> {
+    let decoded = ImgCompressor::from_file(bytes)?;
+    return match color {
+        ColorType::L8 if decoded.raw_luma() => Ok(DynamicImage::ImageLuma8(decoded.luma_image())),
+        ColorType::L8 => Ok(DynamicImage::ImageLuma8(DynamicImage::ImageRgb8(decoded.to_image((0, 0, 0))).to_luma8())),
+        ColorType::Rgb8 => Ok(DynamicImage::ImageRgb8(decoded.to_image((0, 0, 0)))),
+        ColorType::Rgba8 => Ok(DynamicImage::ImageRgba8(DynamicImage::ImageRgb8(decoded.to_image((0, 0, 0))).to_rgba8())),
+        other => Err(DecodeError::UnsupportedColorType(other)),
+    };
+}
+
+/// Rewrites a file at coarser cutoffs by walking each channel's stored
+/// index and data, never rebuilding the pixels.
+pub fn transcode(bytes: &[u8], new_cutoffs: Cutoff) -> Result<Vec<u8>, DecodeError> {
+    let layout = Layout::parse(bytes)?;
+    let stored = layout.header.cutoffs;
+    if new_cutoffs.0 < stored.0 || new_cutoffs.1 < stored.1 || new_cutoffs.2 < stored.2 {
+        return Err(DecodeError::CutoffBelowStored { stored, requested: new_cutoffs });
+    }
+    let flags = layout.header.flags;
+    let mut roots = [neutral_chroma(), neutral_chroma(), neutral_chroma()];
+    let mut streams = [None, None, None];
+    for channel in 0..3 {
+        if flags & FLAG_GRAYSCALE != 0 && channel > 0 {
+            continue;
+        }
+        if flags & FLAG_RAW_CHANNELS[channel] != 0 {
+            roots[channel] = layout.channel(channel)?;
+        } else {
+            let (index, leaves) = layout.stream(channel)?;
+            streams[channel] = Some((index, leaves, layout.rank(channel)));
+        }
+    }
+    return ImgCompressor::from_layout(layout, roots)?.to_file_coarsened(new_cutoffs, streams);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Luma,
+    ChromaBlue,
+    ChromaRed,
+}
+
+pub fn decode_channel(bytes: &[u8], channel: Channel) -> Result<GrayImage, DecodeError> {
+    let i = match channel {
+        Channel::Luma => 0,
+        Channel::ChromaBlue => 1,
+        Channel::ChromaRed => 2,
+    };
+    let layout = Layout::parse_channel(bytes, i)?;
+    let header = &layout.header;
+    let prediction = [header.prediction.0, header.prediction.1, header.prediction.2][i];
+    let root = layout.channel(i)?;
+    let scale = header.rank as usize / root.size();
+    return Ok(ImageBuffer::from_fn(header.width, header.height, |x, y| {
+        Luma([add_prediction(root.get((x as usize / scale, y as usize / scale)), prediction)])
+    }));
+}
+
+/// Reads exactly `len` bytes, growing the buffer only as data arrives so a
+/// corrupt length cannot force a huge allocation.
+fn read_section(r: &mut impl Read, len: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut bytes = vec![];
+    r.take(len as u64).read_to_end(&mut bytes).map_err(|error| DecodeError::Io(error.kind()))?;
+    if bytes.len() < len {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    return Ok(bytes);
+}
+
+fn read_prelude(r: &mut impl Read, flags: u32) -> Result<Vec<u8>, DecodeError> {
+    let mut prelude = vec![];
+    if flags & FLAG_PALETTE != 0 {
+        let count = read_section(r, 1)?;
+        prelude.extend_from_slice(&count);
+        prelude.extend(read_section(r, 3 * (count[0] as usize + 1))?);
+    }
+    for flag in [FLAG_EXIF, FLAG_ICC].iter() {
+        if flags & flag != 0 {
+            let len = read_section(r, 4)?;
+            prelude.extend_from_slice(&len);
+            prelude.extend(read_section(r, u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)?);
+        }
+    }
+    if flags & FLAG_DITHER != 0 {
+        prelude.extend(read_section(r, 5)?);
+    }
+    if flags & FLAG_CHANNEL_CODECS != 0 {
+        prelude.extend(read_section(r, 3)?);
+    }
+    return Ok(prelude);
+}
+
+/// Huffman codes a data section, keeping the result and `flag` only when
+/// it deflates smaller than the section as it is.
+fn huffman_section(leaf: Vec<u8>, flag: u32) -> (Vec<u8>, u32) {
+    let (bits, table) = huffman_encode(&leaf);
+    let coded = [table.to_bytes(), bits].concat();
+    return if Deflate.encode(&coded).len() < Deflate.encode(&leaf).len() { (coded, flag) } else { (leaf, 0) };
+}
+
+fn huffman_leaves(section: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let (table, bits) = HuffTable::read(section)?;
+    return huffman_decode(bits, &table);
+}
+
+fn verify_checksum(footer: &[u8], computed: u32) -> Result<(), DecodeError> {
+    if footer.len() < 4 {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let stored = u32::from_le_bytes([footer[0], footer[1], footer[2], footer[3]]);
+    return if stored == computed { Ok(()) } else { Err(DecodeError::ChecksumMismatch { stored, computed }) };
+}
+
+struct Layout<'a> {
+    header: FileHeader,
+    palette: Option<Vec<Color>>,
+    exif: Option<Vec<u8>>,
+    icc: Option<Vec<u8>>,
+    dither: Option<Dither>,
+    codecs: Option<[u8; 3]>,
+    indexes: Vec<Cow<'a, [u8]>>,
+    sections: Vec<Cow<'a, [u8]>>,
+}
+
+impl<'a> Layout<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Layout<'a>, DecodeError> {
+        return Layout::parse_with(bytes, false);
+    }
+
+    fn parse_with(bytes: &'a [u8], lenient: bool) -> Result<Layout<'a>, DecodeError> {
+        return Layout::parse_channels(bytes, lenient, [true; 3]);
+    }
+
+    /// Parses only the index and data sections of `channel`; the others are
+    /// sliced past and left empty.
+    fn parse_channel(bytes: &'a [u8], channel: usize) -> Result<Layout<'a>, DecodeError> {
+        let mut wanted = [false; 3];
+        wanted[channel] = true;
+        return Layout::parse_channels(bytes, false, wanted);
+    }
+
+    fn parse_channels(bytes: &'a [u8], lenient: bool, wanted: [bool; 3]) -> Result<Layout<'a>, DecodeError> {
+        let (header, body) = parse_header(bytes)?;
+        let (mut layout, body) = Layout::prelude(header, body)?;
+        let mut reader = BoundedReader::new(body);
+        let lens: Vec<u32> = layout.header.index_lens.iter().chain(layout.header.data_lens.iter()).copied().collect();
+        for (section, len) in lens.into_iter().enumerate() {
+            let bytes = match reader.section(len as usize) {
+                Err(_) if lenient => Ok(reader.rest()),
+                result => result,
+            }?;
+            if !wanted[section % 3] {
+                let skipped = if section < 3 { &mut layout.indexes } else { &mut layout.sections };
+                skipped.push(Cow::Borrowed(&[]));
+                continue;
+            }
+            layout.push_section(section, Cow::Borrowed(bytes), lenient)?;
+        }
+        if !lenient && layout.header.flags & FLAG_CHECKSUM != 0 {
+            let (payload, footer) = bytes.split_at(bytes.len().max(HEADER_LEN + 4) - 4);
+            verify_checksum(footer, crc32(payload))?;
+        }
+        return Ok(layout);
+    }
+
+    /// Reads the palette, blobs, dither and codec ids that follow the header,
+    /// returning a layout without sections and the bytes after the prelude.
+    fn prelude(header: FileHeader, body: &'a [u8]) -> Result<(Layout<'a>, &'a [u8]), DecodeError> {
+        if header.flags & FLAG_WIDE_SAMPLES != 0 {
+            return Err(DecodeError::UnsupportedBitDepth(16));
+        }
+        let (palette, body) = if header.flags & FLAG_PALETTE != 0 {
+            let (palette, rest) = read_palette(body)?;
+            (Some(palette), rest)
+        } else {
+            (None, body)
+        };
+        let mut reader = BoundedReader::new(body);
+        let mut blob = |flag: u32| -> Result<Option<Vec<u8>>, DecodeError> {
+            return if header.flags & flag != 0 { Ok(Some(reader.blob()?.to_vec())) } else { Ok(None) };
+        };
+        let exif = blob(FLAG_EXIF)?;
+        let icc = blob(FLAG_ICC)?;
+        let dither = if header.flags & FLAG_DITHER != 0 { Some(Dither::read(&mut reader)?) } else { None };
+        let codecs = if header.flags & FLAG_CHANNEL_CODECS != 0 {
+            let ids = reader.section(3)?;
+            Some([ids[0], ids[1], ids[2]])
+        } else {
+            None
+        };
+        let layout = Layout { header, palette, exif, icc, dither, codecs, indexes: vec![], sections: vec![] };
+        return Ok((layout, reader.rest()));
+    }
+
+    /// Adds the next of the six sections, undoing its channel's codec and run
+    /// length index coding.
+    fn push_section(&mut self, section: usize, bytes: Cow<'a, [u8]>, lenient: bool) -> Result<(), DecodeError> {
+        let channel = section % 3;
+        let bytes = match self.codecs {
+            Some(ids) => {
+                let codec = codec_for_id(ids[channel]).ok_or(EntropyError::UnknownCodec(ids[channel]))?;
+                match codec.decode(&bytes) {
+                    Err(_) if lenient => Cow::Owned(vec![]),
+                    result => Cow::Owned(result?),
+                }
+            },
+            None => bytes,
+        };
+        if section >= 3 {
+            self.sections.push(bytes);
+            return Ok(());
+        }
+        if self.header.flags & FLAG_RLE_INDEX[channel] == 0 {
+            self.indexes.push(bytes);
+            return Ok(());
+        }
+        let bits = match rle_decode_index(&bytes) {
+            Err(_) if lenient => BitVec::new(),
+            result => result?,
+        };
+        self.indexes.push(Cow::Owned(bits.into_vec()));
+        return Ok(());
+    }
+
+    fn rank(&self, channel: usize) -> usize {
+        let rank = self.header.rank as usize;
+        return if channel > 0 && self.header.flags & FLAG_HALF_CHROMA != 0 { rank / 2 } else { rank };
+    }
+
+    fn salvage_channel(&self, channel: usize) -> (Box<Quadtree>, Vec<Region>) {
+        let flags = self.header.flags;
+        if flags & FLAG_GRAYSCALE != 0 && channel > 0 {
+            return (neutral_chroma(), vec![]);
+        }
+        let rank = self.rank(channel);
+        let leaves = &self.sections[channel][..];
+        if flags & FLAG_RAW_CHANNELS[channel] != 0 {
+            let mut plane = leaves.to_vec();
+            let damaged = if plane.len() < rank * rank { vec![Region { x: 0, y: 0, size: rank }] } else { vec![] };
+            plane.resize(rank * rank, *plane.last().unwrap_or(&128));
+            return (Quadtree::new(&plane), damaged);
+        }
+        if flags & FLAG_SINGLE_STREAM != 0 {
+            let (index, data) = split_stream_lenient(leaves, rank);
+            return salvage_leaf_tree(&index, &mut data.into_iter(), rank);
+        }
+        let leaves = if flags & FLAG_HUFFMAN[channel] != 0 { huffman_leaves(leaves).unwrap_or_default() } else { leaves.to_vec() };
+        let leaves = if flags & FLAG_BIT_PACKED != 0 { unpack_values(&leaves).unwrap_or_default() } else { leaves };
+        let leaves = if flags & FLAG_VLC_RESIDUALS != 0 { decode_residuals(&leaves).unwrap_or_default() } else { leaves };
+        let leaves = if flags & FLAG_PLANAR_LEAVES != 0 { interleave(&leaves) } else { leaves };
+        let index = BitSlice::from_slice(&self.indexes[channel]);
+        let leaves = if flags & FLAG_SIBLING_DELTAS != 0 { undo_sibling_deltas_lenient(index, &leaves, rank) } else { leaves };
+        return salvage_leaf_tree(index, &mut leaves.into_iter(), rank);
+    }
+
+    fn channel(&self, channel: usize) -> Result<Box<Quadtree>, DecodeError> {
+        let flags = self.header.flags;
+        if flags & FLAG_GRAYSCALE != 0 && channel > 0 {
+            return Ok(neutral_chroma());
+        }
+        let rank = self.rank(channel);
+        let leaves = &self.sections[channel][..];
+        trace!("channel {} index {} bytes data {} bytes", channel, self.indexes[channel].len(), leaves.len());
+        if flags & FLAG_RAW_CHANNELS[channel] != 0 {
+            if leaves.len() != rank * rank {
+                return Err(DecodeError::UnexpectedEof);
+            }
+            return Ok(Quadtree::new(&leaves.to_vec()));
+        }
+        let (index, leaves) = self.stream(channel)?;
+        return read_leaf_tree(&index, &mut leaves.into_iter(), rank);
+    }
+
+    /// Index bits and plain leaf values of a channel coded as a tree.
+    fn stream(&self, channel: usize) -> Result<(BitVec<Local, u8>, Vec<u8>), DecodeError> {
+        let flags = self.header.flags;
+        let leaves = &self.sections[channel][..];
+        if flags & FLAG_SINGLE_STREAM != 0 {
+            return split_stream(leaves, self.rank(channel));
+        }
+        let leaves = if flags & FLAG_HUFFMAN[channel] != 0 { huffman_leaves(leaves)? } else { leaves.to_vec() };
+        let leaves = if flags & FLAG_BIT_PACKED != 0 { unpack_values(&leaves)? } else { leaves };
+        let leaves = if flags & FLAG_VLC_RESIDUALS != 0 { decode_residuals(&leaves)? } else { leaves };
+        let leaves = if flags & FLAG_PLANAR_LEAVES != 0 { interleave(&leaves) } else { leaves };
+        let index = BitVec::from_slice(&self.indexes[channel]);
+        let leaves = match flags & FLAG_SIBLING_DELTAS != 0 {
+            true => undo_sibling_deltas(&index, &leaves, self.rank(channel))?,
+            false => leaves,
+        };
+        return Ok((index, leaves));
+    }
+
+    /// The values of one channel inside `area`, read without building the
+    /// subtrees that lie outside it.
+    fn region(&self, channel: usize, area: Rect) -> Result<Vec<u8>, DecodeError> {
+        let flags = self.header.flags;
+        let mut plane = vec![0; area.width * area.height];
+        if flags & FLAG_GRAYSCALE != 0 && channel > 0 {
+            plane.fill(neutral_chroma().average());
+        } else if flags & FLAG_RAW_CHANNELS[channel] != 0 {
+            let root = self.channel(channel)?;
+            for (i, value) in plane.iter_mut().enumerate() {
+                *value = root.get((area.x + i % area.width, area.y + i / area.width));
+            }
+        } else {
+            let (index, leaves) = self.stream(channel)?;
+            read_leaf_region(&index, &mut leaves.into_iter(), self.rank(channel), area, &mut plane)?;
+        }
+        return Ok(plane);
+    }
+
+    /// One value per `pitch` block of a channel, `pitch` in tree pixels.
+    fn thumbnail(&self, channel: usize, pitch: usize) -> Result<Vec<u8>, DecodeError> {
+        let (flags, rank) = (self.header.flags, self.rank(channel));
+        if pitch == 1 || flags & FLAG_GRAYSCALE != 0 && channel > 0 {
+            return self.region(channel, Rect { x: 0, y: 0, width: rank, height: rank });
+        }
+        if flags & FLAG_RAW_CHANNELS[channel] != 0 {
+            let root = self.channel(channel)?;
+            let side = rank / pitch;
+            return Ok((0..side * side).map(|i| root.node_at((i % side * pitch, i / side * pitch), pitch).average()).collect());
+        }
+        let (index, leaves) = self.stream(channel)?;
+        return read_leaf_thumbnail(&index, &mut leaves.into_iter(), rank, pitch);
+    }
+}
+
+/// Decodes only the pixels of `rect`, clipped to the image.
+pub fn decode_region(bytes: &[u8], rect: Rect) -> Result<RgbImage, DecodeError> {
+    let layout = Layout::parse(bytes)?;
+    let (width, height) = (layout.header.width as usize, layout.header.height as usize);
+    let rect = Rect { width: rect.width.min(width.saturating_sub(rect.x)), height: rect.height.min(height.saturating_sub(rect.y)), ..rect };
+    let half = layout.header.flags & FLAG_HALF_CHROMA != 0;
+    let scale = |channel: usize| if channel > 0 && half { 2 } else { 1 };
+    let mut planes = vec![];
+    for channel in 0..3 {
+        let area = if scale(channel) == 2 { rect.halved() } else { rect };
+        planes.push((area, layout.region(channel, area)?));
+    }
+    let decoded = ImgCompressor::from_layout(layout, [neutral_chroma(), neutral_chroma(), neutral_chroma()])?;
+    return Ok(ImageBuffer::from_fn(rect.width as u32, rect.height as u32, |dx, dy| {
+        let p = (rect.x + dx as usize, rect.y + dy as usize);
+        let values = [0, 1, 2].map(|channel| {
+            let (area, plane) = &planes[channel];
+            let (x, y) = (p.0 / scale(channel), p.1 / scale(channel));
+            return plane[x - area.x + (y - area.y) * area.width];
+        });
+        return decoded.color(p, values);
+    }));
+}
+
+/// Decodes a preview no larger than `max_side` on either axis by stopping at
+/// the tree depth whose blocks match the preview's pixel pitch.
+pub fn decode_thumbnail(bytes: &[u8], max_side: u32) -> Result<RgbImage, DecodeError> {
+    let layout = Layout::parse(bytes)?;
+    let (width, height, rank) = (layout.header.width, layout.header.height, layout.header.rank);
+    let mut pitch = 1;
+    while width.div_ceil(pitch).max(height.div_ceil(pitch)) > max_side.max(1) && pitch < rank {
+        pitch *= 2;
+    }
+    let half = layout.header.flags & FLAG_HALF_CHROMA != 0;
+    let mut planes = vec![];
+    for channel in 0..3 {
+        let scale = if channel > 0 && half { 2 } else { 1 };
+        let plane = if scale > pitch {
+            let (side, chroma) = (rank as usize, layout.thumbnail(channel, 1)?);
+            (0..side * side).map(|i| chroma[i % side / 2 + i / side / 2 * (side / 2)]).collect()
+        } else {
+            layout.thumbnail(channel, (pitch / scale) as usize)?
+        };
+        planes.push(plane);
+    }
+    let side = (rank / pitch) as usize;
+    let decoded = ImgCompressor::from_layout(layout, [neutral_chroma(), neutral_chroma(), neutral_chroma()])?;
+    return Ok(ImageBuffer::from_fn(width.div_ceil(pitch), height.div_ceil(pitch), |x, y| {
+        let i = x as usize + y as usize * side;
+        return decoded.color(((x * pitch) as usize, (y * pitch) as usize), [planes[0][i], planes[1][i], planes[2][i]]);
+    }));
+}
+
+pub fn decode_lenient(bytes: &[u8]) -> Result<(RgbImage, Vec<Region>), DecodeError> {
+    let (decoded, damaged) = ImgCompressor::salvage(bytes)?;
+    return Ok((decoded.to_image((0, 0, 0)), damaged));
+}
+
+/// `Half` stores both chroma trees at half the luma rank, like JPEG's 4:2:0,
+/// and upsamples them when rendering.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChromaSubsampling {
+    Full,
+    Half,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Panic,
+    Error,
+    PadToFit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LossySetting {
+    Cutoff(Cutoff),
+    ColorTransform(ColorSpace),
+    DeadZone(u8),
+    Palette,
+    Dither,
+    MinLeaf(usize),
+    ChromaSubsampling,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CompressError {
+    /// The strict constructors need a square with a power-of-two side;
+    /// `new` pads instead and never returns this.
+    NotSquare { width: u32, height: u32 },
+    EmptyImage,
+    InvalidMinLeaf(usize),
+    Lossy(LossySetting),
+    BufferSize { expected: usize, actual: usize },
+    InvalidTileSize(u32),
+}
+
+pub struct SizedFile {
+    pub cutoffs: Cutoff,
+    pub bytes: Vec<u8>,
+    pub reached: bool,
+}
+
+/// Options for building and encoding an image, set builder-style and used by
+/// `ImgCompressor::with_config` and `CompressConfig::encode`. The defaults
+/// are those of `ImgCompressor::new` and cutoffs of 2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressConfig {
+    pub color_space: ColorSpace,
+    pub chroma: ChromaSubsampling,
+    pub min_leaf: usize,
+    pub interpolation: Interpolation,
+    pub cutoffs: Cutoff,
+    pub backend: EntropyBackend,
+    pub level: CompressionLevel,
+}
+
+impl Default for CompressConfig {
+    fn default() -> CompressConfig {
+        return CompressConfig {
+            color_space: ColorSpace::YCbCr,
+            chroma: ChromaSubsampling::Full,
+            min_leaf: 2,
+            interpolation: Interpolation::Bilinear,
+            cutoffs: (2, 2, 2),
+            backend: EntropyBackend::Deflate,
+            level: CompressionLevel::Default,
+        };
+    }
+}
+
+impl CompressConfig {
+    pub fn new() -> CompressConfig {
+        return CompressConfig::default();
+    }
+
+    pub fn color_space(self, color_space: ColorSpace) -> CompressConfig {
+        return CompressConfig { color_space, ..self };
+    }
+
+    pub fn chroma(self, chroma: ChromaSubsampling) -> CompressConfig {
+        return CompressConfig { chroma, ..self };
+    }
+
+    pub fn min_leaf(self, min_leaf: usize) -> CompressConfig {
+        return CompressConfig { min_leaf, ..self };
+    }
+
+    pub fn interpolation(self, interpolation: Interpolation) -> CompressConfig {
+        return CompressConfig { interpolation, ..self };
+    }
+
+    pub fn cutoffs(self, cutoffs: Cutoff) -> CompressConfig {
+        return CompressConfig { cutoffs, ..self };
+    }
+
+    pub fn backend(self, backend: EntropyBackend, level: CompressionLevel) -> CompressConfig {
+        return CompressConfig { backend, level, ..self };
+    }
+
+    /// Builds the trees and writes an entropy-coded `.ski` file at the
+    /// configured cutoffs.
+    pub fn encode(&self, img: DynamicImage) -> Result<Vec<u8>, CompressError> {
+        let compressor = ImgCompressor::with_config(img, self)?;
+        return Ok(entropy::encode(&*self.backend.codec(self.level), &compressor.to_file(self.cutoffs)));
+    }
+}
+
+/// Sizes of one encoding; `ratio` is raw RGB bytes over deflated bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionReport {
+    pub raw_bytes: usize,
+    pub serialized_bytes: usize,
+    pub deflated_bytes: usize,
+    pub ratio: f64,
+}
+
 pub struct ImgCompressor {
     pub lumin_root: Box<Quadtree>,
     pub c_blu_root: Box<Quadtree>,
     pub c_red_root: Box<Quadtree>,
-    pub rank: u32
+    pub rank: u32,
+    pub width: u32,
+    pub height: u32,
+    pub lossless: bool,
+    pub prediction: (u8, u8, u8),
+    pub color_space: ColorSpace,
+    pub planar_leaves: bool,
+    pub residual_vlc: bool,
+    pub packed_leaves: bool,
+    pub dead_zone: u8,
+    pub clamp_interpolation: bool,
+    pub single_stream: bool,
+    pub palette: Option<Vec<Color>>,
+    pub exif: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+    pub dither: Option<Dither>,
+    pub channel_codecs: Option<[u8; 3]>,
+    pub strict: bool,
+    pub grayscale: bool,
+    pub min_leaf: usize,
+    pub interpolation: Interpolation,
+    pub bit_packed_values: bool,
+    pub rle_index: bool,
+    pub chroma_subsampling: ChromaSubsampling,
+    pub huffman_leaves: bool,
+    pub sibling_deltas: bool,
 }
 
 impl ImgCompressor {
-    pub fn new(img_res: ImageResult<DynamicImage>) -> ImgCompressor {
-        let rgb = img_res.unwrap().to_rgb8();
-        let pixel_buffer = rgb.pixels();
-        let pixel_len = pixel_buffer.len();
-        let mut lumin = vec![0u8; pixel_buffer.len()];
-        let mut c_blu = vec![0u8; pixel_buffer.len()];
-        let mut c_red = vec![0u8; pixel_buffer.len()];
-        for (i, pixel) in pixel_buffer.enumerate() {
-            let ycca = rgb_to_ycc(pixel.channels4());
-            lumin[i] = ycca.0;
-            c_blu[i] = ycca.1;
-            c_red[i] = ycca.2;
-        }
-        let rank = (pixel_len as f32).sqrt() as u32;
-        assert!(pixel_len as u32 == rank * rank);
-        let lumin_root = Quadtree::new(&lumin);
-        let c_blu_root = Quadtree::new(&c_blu);
-        let c_red_root = Quadtree::new(&c_red);
-        return ImgCompressor { lumin_root, c_blu_root, c_red_root, rank };
+    /// Works on 8-bit channels; 16-bit images are truncated here, so use
+    /// `WideCompressor` to keep their full precision. Any other size is
+    /// padded to a power-of-two square, so the only error is `EmptyImage`.
+    pub fn new(img: DynamicImage) -> Result<ImgCompressor, CompressError> {
+        return Ok(ImgCompressor::build(non_empty_rgb(img)?, false, ColorSpace::YCbCr));
+    }
+
+    /// Builds from interleaved 8-bit RGB samples, `width * height * 3` bytes.
+    pub fn from_rgb(pixels: &[u8], width: u32, height: u32) -> Result<ImgCompressor, CompressError> {
+        if width == 0 || height == 0 {
+            return Err(CompressError::EmptyImage);
+        }
+        let expected = width as usize * height as usize * 3;
+        if pixels.len() != expected {
+            return Err(CompressError::BufferSize { expected, actual: pixels.len() });
+        }
+        let rgb = RgbImage::from_raw(width, height, pixels.to_vec()).ok_or(CompressError::EmptyImage)?;
+        return Ok(ImgCompressor::build(rgb, false, ColorSpace::YCbCr));
+    }
+
+    /// Builds from ready-made `YCbCr` planes of `rank * rank` samples each.
+    pub fn from_channels(lumin: Vec<u8>, c_blu: Vec<u8>, c_red: Vec<u8>, rank: u32) -> Result<ImgCompressor, CompressError> {
+        if rank < 2 || !rank.is_power_of_two() {
+            return Err(CompressError::NotSquare { width: rank, height: rank });
+        }
+        let expected = (rank * rank) as usize;
+        if let Some(plane) = [&lumin, &c_blu, &c_red].iter().find(|plane| plane.len() != expected) {
+            return Err(CompressError::BufferSize { expected, actual: plane.len() });
+        }
+        let roots = [Quadtree::new(&lumin), Quadtree::new(&c_blu), Quadtree::new(&c_red)];
+        return Ok(ImgCompressor::from_roots(roots, rank, (rank, rank), (128, 128, 128), ColorSpace::YCbCr));
+    }
+
+    pub fn with_config(img: DynamicImage, config: &CompressConfig) -> Result<ImgCompressor, CompressError> {
+        validate_min_leaf(config.min_leaf)?;
+        let compressor = ImgCompressor::build(non_empty_rgb(img)?, false, config.color_space);
+        let compressor = ImgCompressor { min_leaf: config.min_leaf, interpolation: config.interpolation, ..compressor };
+        return Ok(compressor.subsampled(config.chroma));
+    }
+
+    pub fn new_with_min_leaf(img: DynamicImage, min_leaf: usize) -> Result<ImgCompressor, CompressError> {
+        validate_min_leaf(min_leaf)?;
+        return Ok(ImgCompressor { min_leaf, ..ImgCompressor::new(img)? });
+    }
+
+    pub fn new_with_subsampling(img: DynamicImage, subsampling: ChromaSubsampling) -> Result<ImgCompressor, CompressError> {
+        return Ok(ImgCompressor::new(img)?.subsampled(subsampling));
+    }
+
+    /// Rebuilds both chroma trees at half rank for `Half`; trees below rank 4
+    /// have no half-rank form and stay full.
+    fn subsampled(self, subsampling: ChromaSubsampling) -> ImgCompressor {
+        if subsampling == ChromaSubsampling::Full || self.rank < 4 {
+            return self;
+        }
+        let (c_blu_root, c_red_root) = (halve(&self.c_blu_root), halve(&self.c_red_root));
+        return ImgCompressor { c_blu_root, c_red_root, chroma_subsampling: subsampling, ..self };
+    }
+
+    pub fn new_with_policy(img: DynamicImage, policy: Policy) -> Result<ImgCompressor, CompressError> {
+        let rgb = non_empty_rgb(img)?;
+        let (width, height) = rgb.dimensions();
+        if policy != Policy::PadToFit && !(is_square(width, height) && is_power_of_two(width) && width >= 2) {
+            if policy == Policy::Panic {
+                panic!("image is {}x{}, but the quadtree needs a square power-of-two side; pad it to {:?} or use Policy::PadToFit",
+                    width, height, next_compressible(width, height));
+            }
+            return Err(CompressError::NotSquare { width, height });
+        }
+        return Ok(ImgCompressor::build(rgb, false, ColorSpace::YCbCr));
+    }
+
+    pub fn new_with_color_space(img: DynamicImage, color_space: ColorSpace) -> Result<ImgCompressor, CompressError> {
+        return Ok(ImgCompressor::build(non_empty_rgb(img)?, false, color_space));
+    }
+
+    pub fn new_predicted(img: DynamicImage) -> Result<ImgCompressor, CompressError> {
+        return Ok(ImgCompressor::build(non_empty_rgb(img)?, true, ColorSpace::YCbCr));
+    }
+
+    pub fn new_paletted(img: DynamicImage, colors: usize) -> Result<ImgCompressor, CompressError> {
+        let rgb = non_empty_rgb(img)?;
+        let palette = median_cut(&rgb, colors);
+        let compressor = ImgCompressor::build(quantize(&rgb, &palette), false, ColorSpace::YCbCr);
+        return Ok(ImgCompressor { palette: Some(palette), ..compressor });
+    }
+
+    fn build(rgb: RgbImage, predict: bool, color_space: ColorSpace) -> ImgCompressor {
+        let (width, height) = rgb.dimensions();
+        let mut planes = [vec![], vec![], vec![]];
+        for pixel in rgb.pixels() {
+            let ycca = color_space.forward(pixel.channels4());
+            planes[0].push(ycca.0);
+            planes[1].push(ycca.1);
+            planes[2].push(ycca.2);
+        }
+        let (mut lumin, rank) = pad_to_pow2(&planes[0], width as usize, height as usize);
+        let (mut c_blu, _) = pad_to_pow2(&planes[1], width as usize, height as usize);
+        let (mut c_red, _) = pad_to_pow2(&planes[2], width as usize, height as usize);
+        let rank = rank as u32;
+        let prediction = if predict {
+            (median(&lumin), median(&c_blu), median(&c_red))
+        } else {
+            (128, 128, 128)
+        };
+        subtract_prediction(&mut lumin, prediction.0);
+        subtract_prediction(&mut c_blu, prediction.1);
+        subtract_prediction(&mut c_red, prediction.2);
+        let (lumin_root, c_blu_root, c_red_root) = join3(|| Quadtree::new(&lumin), || Quadtree::new(&c_blu), || Quadtree::new(&c_red));
+        let roots = [lumin_root, c_blu_root, c_red_root];
+        return ImgCompressor::from_roots(roots, rank, (width, height), prediction, color_space);
+    }
+
+    pub fn new_grayscale(img: DynamicImage) -> Result<ImgCompressor, CompressError> {
+        let gray = img.to_luma8();
+        non_empty(gray.dimensions())?;
+        let (width, height) = gray.dimensions();
+        let (lumin, rank) = pad_to_pow2(gray.as_raw(), width as usize, height as usize);
+        let roots = [Quadtree::new(&lumin), neutral_chroma(), neutral_chroma()];
+        let compressor = ImgCompressor::from_roots(roots, rank as u32, (width, height), (128, 128, 128), ColorSpace::YCbCr);
+        return Ok(ImgCompressor { grayscale: true, ..compressor });
+    }
+
+    /// Wraps already built trees, as `new` does after building them.
+    pub fn from_roots(roots: [Box<Quadtree>; 3], rank: u32, (width, height): (u32, u32), prediction: (u8, u8, u8), color_space: ColorSpace) -> ImgCompressor {
+        let [lumin_root, c_blu_root, c_red_root] = roots;
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "built {}x{} image at rank {}, nodes {} {} {}",
+                width, height, rank, lumin_root.node_count(), c_blu_root.node_count(), c_red_root.node_count()
+            );
+        }
+        return ImgCompressor {
+            lumin_root, c_blu_root, c_red_root, rank, width, height,
+            lossless: false, prediction, color_space, planar_leaves: false, residual_vlc: false, packed_leaves: false,
+            dead_zone: 0, clamp_interpolation: false, single_stream: false,
+            palette: None, exif: None, icc: None, dither: None, channel_codecs: None, strict: false, grayscale: false, min_leaf: 2,
+            interpolation: Interpolation::Bilinear, bit_packed_values: false, rle_index: false,
+            chroma_subsampling: ChromaSubsampling::Full, huffman_leaves: false, sibling_deltas: false
+        };
+    }
+
+    /// Rebuilds the three channel trees from a `to_file` payload; the section
+    /// lengths and byte layout are described on `FileHeader`.
+    pub fn from_file(bytes: &[u8]) -> Result<ImgCompressor, DecodeError> {
+        let layout = Layout::parse(bytes)?;
+        debug!("decoding rank {} with flags {:#034b}", layout.header.rank, layout.header.flags);
+        let roots = [layout.channel(0)?, layout.channel(1)?, layout.channel(2)?];
+        return ImgCompressor::from_layout(layout, roots);
+    }
+
+    /// Decodes a `to_file` payload from `r`, keeping only the indexes and one
+    /// channel's leaf data in memory at a time.
+    pub fn read_from(r: &mut impl Read) -> Result<RgbImage, DecodeError> {
+        let r = &mut CrcReader::new(r);
+        let (header, _) = parse_header(&read_section(r, HEADER_LEN)?)?;
+        let prelude = read_prelude(r, header.flags)?;
+        let (index_lens, data_lens) = (header.index_lens, header.data_lens);
+        let (mut layout, _) = Layout::prelude(header, &prelude)?;
+        for (channel, len) in index_lens.iter().enumerate() {
+            layout.push_section(channel, Cow::Owned(read_section(r, *len as usize)?), false)?;
+        }
+        let mut channel = |i: usize| -> Result<Box<Quadtree>, DecodeError> {
+            layout.push_section(i + 3, Cow::Owned(read_section(r, data_lens[i] as usize)?), false)?;
+            let root = layout.channel(i)?;
+            layout.sections[i] = Cow::Owned(vec![]);
+            return Ok(root);
+        };
+        let roots = [channel(0)?, channel(1)?, channel(2)?];
+        if layout.header.flags & FLAG_CHECKSUM != 0 {
+            let computed = r.checksum();
+            verify_checksum(&read_section(r, 4)?, computed)?;
+        }
+        return Ok(ImgCompressor::from_layout(layout, roots)?.to_image((0, 0, 0)));
+    }
+
+    fn salvage(bytes: &[u8]) -> Result<(ImgCompressor, Vec<Region>), DecodeError> {
+        let layout = Layout::parse_with(bytes, true)?;
+        let (lumin, mut damaged) = layout.salvage_channel(0);
+        let (c_blu, c_blu_damaged) = layout.salvage_channel(1);
+        let (c_red, c_red_damaged) = layout.salvage_channel(2);
+        damaged.extend(c_blu_damaged);
+        damaged.extend(c_red_damaged);
+        return Ok((ImgCompressor::from_layout(layout, [lumin, c_blu, c_red])?, damaged));
+    }
+
+    fn from_layout(layout: Layout, roots: [Box<Quadtree>; 3]) -> Result<ImgCompressor, DecodeError> {
+        let header = &layout.header;
+        let color_space = ColorSpace::from_id(header.color_space)
+            .ok_or(DecodeError::UnknownColorSpace(header.color_space))?;
+        let [lumin_root, c_blu_root, c_red_root] = roots;
+        return Ok(ImgCompressor {
+            lumin_root,
+            c_blu_root,
+            c_red_root,
+            rank: header.rank,
+            width: header.width,
+            height: header.height,
+            lossless: true,
+            prediction: header.prediction,
+            color_space,
+            planar_leaves: header.flags & FLAG_PLANAR_LEAVES != 0,
+            residual_vlc: header.flags & FLAG_VLC_RESIDUALS != 0,
+            packed_leaves: false,
+            dead_zone: 0,
+            clamp_interpolation: false,
+            single_stream: header.flags & FLAG_SINGLE_STREAM != 0,
+            palette: layout.palette,
+            exif: layout.exif,
+            icc: layout.icc,
+            dither: layout.dither,
+            channel_codecs: layout.codecs,
+            strict: false,
+            grayscale: header.flags & FLAG_GRAYSCALE != 0,
+            min_leaf: 2,
+            interpolation: Interpolation::Bilinear,
+            bit_packed_values: header.flags & FLAG_BIT_PACKED != 0,
+            huffman_leaves: FLAG_HUFFMAN.iter().any(|flag| header.flags & flag != 0),
+            rle_index: FLAG_RLE_INDEX.iter().any(|flag| header.flags & flag != 0),
+            chroma_subsampling: if header.flags & FLAG_HALF_CHROMA != 0 { ChromaSubsampling::Half } else { ChromaSubsampling::Full },
+            sibling_deltas: header.flags & FLAG_SIBLING_DELTAS != 0,
+        });
+    }
+
+    pub fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    pub fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    /// Builds on the reversible `YCoCgR` transform and ignores requested
+    /// cutoffs, so every reconstruction is the original image.
+    pub fn new_lossless(img: DynamicImage) -> Result<ImgCompressor, CompressError> {
+        let compressor = ImgCompressor::build(non_empty_rgb(img)?, false, ColorSpace::YCoCgR);
+        return Ok(ImgCompressor { lossless: true, ..compressor });
+    }
+
+    fn effective(&self, cutoffs: Cutoff) -> Cutoff {
+        return if self.lossless { (0, 0, 0) } else { cutoffs };
     }
 
     fn predicted_capacity(&self) -> usize {
         return (self.rank * self.rank) as usize;
     }
 
-    fn leaf_index(&self, quadtree_root: &Box<Quadtree>, cutoff: u8) -> BitVec<Local, u8> {
+    fn min_leaf<'a>(&self, cutoff: &'a dyn CutoffMap) -> MinLeaf<'a> {
+        return MinLeaf { base: cutoff, min_leaf: self.min_leaf };
+    }
+
+    fn leaf_index(&self, quadtree_root: &Quadtree, cutoff: &dyn CutoffMap) -> BitVec<Local, u8> {
         let mut quad_index: BitVec<Local, u8> = BitVec::with_capacity(self.predicted_capacity());
-        build_leaf_index(quadtree_root, &mut quad_index, cutoff);
+        build_leaf_index(quadtree_root, &mut quad_index, &self.min_leaf(cutoff), self.leaf_coding());
         return quad_index;
     }
 
-    fn leaf_data(&self, quadtree_root: &Box<Quadtree>, cutoff: u8) -> Vec<u8> {
+    fn leaf_data(&self, quadtree_root: &Quadtree, cutoff: &dyn CutoffMap) -> Vec<u8> {
         let mut leaf_data = Vec::with_capacity(self.predicted_capacity());
-        build_leaf_data(quadtree_root, &mut leaf_data, cutoff);
+        build_leaf_data(quadtree_root, &mut leaf_data, &self.min_leaf(cutoff), self.leaf_coding());
         return leaf_data;
     }
 
+    /// Per-channel tree statistics at `cutoffs`, luma first.
+    pub fn stats(&self, cutoffs: Cutoff) -> [TreeStats; 3] {
+        let cutoffs = self.effective(cutoffs);
+        return [
+            self.lumin_root.stats(&self.min_leaf(&cutoffs.0)),
+            self.c_blu_root.stats(&self.min_leaf(&cutoffs.1)),
+            self.c_red_root.stats(&self.min_leaf(&cutoffs.2)),
+        ];
+    }
+
+    /// Per-channel cutoffs that shrink as the channel's standard deviation
+    /// grows, so flat channels collapse aggressively and detailed ones keep
+    /// their nodes; `strength` scales all three.
+    pub fn auto_cutoffs(&self, strength: f32) -> Cutoff {
+        let cutoff = |root: &Quadtree| {
+            let plane = self.raw_plane(root);
+            let mean = root.sum() as f32 / plane.len() as f32;
+            let variance = plane.iter().map(|v| (*v as f32 - mean).powi(2)).sum::<f32>() / plane.len() as f32;
+            return (strength * 255f32 / (1f32 + variance.sqrt())).round().clamp(0f32, 255f32) as u8;
+        };
+        return (cutoff(&self.lumin_root), cutoff(&self.c_blu_root), cutoff(&self.c_red_root));
+    }
+
+    pub fn edge_mask(&self) -> ImportanceMask {
+        let rank = self.rank as usize;
+        let luma = (0..rank*rank).map(|i| self.lumin_root.get((i % rank, i / rank))).collect();
+        return ImportanceMask::from_edges(&luma);
+    }
+
     pub fn to_file(&self, cutoffs: Cutoff) -> Vec<u8> {
-        let r_index = self.leaf_index(&self.lumin_root, cutoffs.0).into_vec();
-        let g_index = self.leaf_index(&self.c_blu_root, cutoffs.1).into_vec();
-        let b_index = self.leaf_index(&self.c_red_root, cutoffs.2).into_vec();
-        let r_leaf = self.leaf_data(&self.lumin_root, cutoffs.0);
-        let g_leaf = self.leaf_data(&self.c_blu_root, cutoffs.1);
-        let b_leaf = self.leaf_data(&self.c_red_root, cutoffs.2);
-        let bytes = [
-            &r_index[..],
-            &g_index[..],
-            &b_index[..],
-            &r_leaf[..],
-            &g_leaf[..],
-            &b_leaf[..]
-        ].concat();
-        return bytes;
+        let cutoffs = self.effective(cutoffs);
+        return self.to_file_mapped(cutoffs, [&cutoffs.0, &cutoffs.1, &cutoffs.2], false);
     }
 
-    pub fn to_image(&self, cutoffs: Cutoff) -> RgbImage {
-        let rank = self.rank;
-        let img = ImageBuffer::from_fn(rank, rank, |x, y| {
-            let p = (x as usize, y as usize);
-            let rgb = ycca_to_rgba((
-                self.lumin_root.get_approx(p, cutoffs.0),
-                self.c_blu_root.get_approx(p, cutoffs.1),
-                self.c_red_root.get_approx(p, cutoffs.2),
-                0
-            ));
-            image::Rgb([rgb.0,rgb.1,rgb.2])
-        });
-        return img;
+    /// Writes the same bytes as `to_file`, one section at a time, so only a
+    /// single channel's leaf data is held in memory.
+    pub fn write_to(&self, w: &mut impl Write, cutoffs: Cutoff) -> io::Result<()> {
+        for chunk in self.chunks(cutoffs) {
+            w.write_all(&chunk)?;
+        }
+        return Ok(());
+    }
+
+    pub fn to_compressed_file(&self, cutoffs: Cutoff, backend: EntropyBackend) -> Vec<u8> {
+        return entropy::encode(&*backend.codec(CompressionLevel::Default), &self.to_file(cutoffs));
+    }
+
+    pub fn from_compressed_file(bytes: &[u8]) -> Result<ImgCompressor, DecodeError> {
+        return ImgCompressor::from_file(&entropy::decode(bytes)?);
+    }
+
+    pub fn try_to_file(&self, cutoffs: Cutoff) -> Result<Vec<u8>, CompressError> {
+        if self.strict {
+            if let Some(setting) = self.lossy_setting(cutoffs) {
+                return Err(CompressError::Lossy(setting));
+            }
+        }
+        return Ok(self.to_file(cutoffs));
+    }
+
+    fn lossy_setting(&self, cutoffs: Cutoff) -> Option<LossySetting> {
+        let cutoffs = self.effective(cutoffs);
+        if cutoffs != (0, 0, 0) {
+            return Some(LossySetting::Cutoff(cutoffs));
+        }
+        if self.color_space == ColorSpace::YCbCr || self.color_space == ColorSpace::YCoCg {
+            return Some(LossySetting::ColorTransform(self.color_space));
+        }
+        if self.dead_zone > 1 {
+            return Some(LossySetting::DeadZone(self.dead_zone));
+        }
+        if self.palette.is_some() {
+            return Some(LossySetting::Palette);
+        }
+        if self.dither.is_some() {
+            return Some(LossySetting::Dither);
+        }
+        if self.min_leaf > 2 {
+            return Some(LossySetting::MinLeaf(self.min_leaf));
+        }
+        if self.chroma_subsampling == ChromaSubsampling::Half {
+            return Some(LossySetting::ChromaSubsampling);
+        }
+        return None;
+    }
+
+    pub fn compress_and_verify(&self, cutoffs: Cutoff) -> Vec<u8> {
+        let cutoffs = self.effective(cutoffs);
+        return self.to_file_mapped(cutoffs, [&cutoffs.0, &cutoffs.1, &cutoffs.2], true);
+    }
+
+    pub fn to_file_masked(&self, cutoffs: Cutoff, mask: &ImportanceMask) -> Vec<u8> {
+        let cutoffs = self.effective(cutoffs);
+        return self.to_file_mapped(cutoffs, [
+            &mask.with_cutoff(cutoffs.0),
+            &mask.with_cutoff(cutoffs.1),
+            &mask.with_cutoff(cutoffs.2)
+        ], false);
+    }
+
+    /// Encodes `rect` with `roi_cutoffs` and the rest of the image with `cutoffs`.
+    pub fn to_file_roi(&self, cutoffs: Cutoff, rect: Rect, roi_cutoffs: Cutoff) -> Vec<u8> {
+        let (cutoffs, roi_cutoffs) = (self.effective(cutoffs), self.effective(roi_cutoffs));
+        let chroma_rect = if self.chroma_subsampling == ChromaSubsampling::Half { rect.halved() } else { rect };
+        return self.to_file_mapped(cutoffs, [
+            &RegionOfInterest { rect, inside: roi_cutoffs.0, outside: cutoffs.0 },
+            &RegionOfInterest { rect: chroma_rect, inside: roi_cutoffs.1, outside: cutoffs.1 },
+            &RegionOfInterest { rect: chroma_rect, inside: roi_cutoffs.2, outside: cutoffs.2 }
+        ], false);
+    }
+
+    pub fn to_file_bounded(&self, max_mse: f64) -> Vec<u8> {
+        let roots = [&self.lumin_root, &self.c_blu_root, &self.c_red_root];
+        let bounds: Vec<MseBound> = roots.iter()
+            .map(|root| MseBound::new(root, &self.raw_plane(root), max_mse))
+            .collect();
+        return self.to_file_mapped((0, 0, 0), [&bounds[0], &bounds[1], &bounds[2]], false);
+    }
+
+    fn ordered(&self, leaf_data: Vec<u8>) -> Vec<u8> {
+        let leaf_data = if self.planar() { planarize(&leaf_data) } else { leaf_data };
+        return if self.vlc() { encode_residuals(&leaf_data) } else { leaf_data };
+    }
+
+    fn leaf_coding(&self) -> LeafCoding {
+        return LeafCoding { packed: self.packed_leaves, dead_zone: self.dead_zone, sibling_deltas: self.deltas() };
+    }
+
+    fn planar(&self) -> bool {
+        return self.planar_leaves && !self.packed_leaves && !self.single_stream && !self.deltas();
+    }
+
+    fn deltas(&self) -> bool {
+        return self.sibling_deltas && !self.single_stream;
+    }
+
+    fn vlc(&self) -> bool {
+        return self.residual_vlc && !self.single_stream;
+    }
+
+    fn bit_packed(&self) -> bool {
+        return self.bit_packed_values && !self.single_stream;
+    }
+
+    fn flags(&self) -> u32 {
+        let planar = if self.planar() { FLAG_PLANAR_LEAVES } else { 0 };
+        let residual = if self.vlc() { FLAG_VLC_RESIDUALS } else { 0 };
+        let palette = if self.palette.is_some() { FLAG_PALETTE } else { 0 };
+        let exif = if self.exif.is_some() { FLAG_EXIF } else { 0 };
+        let icc = if self.icc.is_some() { FLAG_ICC } else { 0 };
+        let single = if self.single_stream { FLAG_SINGLE_STREAM } else { 0 };
+        let dither = if self.dither.is_some() { FLAG_DITHER } else { 0 };
+        let codecs = if self.channel_codecs.is_some() { FLAG_CHANNEL_CODECS } else { 0 };
+        let grayscale = if self.grayscale { FLAG_GRAYSCALE } else { 0 };
+        let bit_packed = if self.bit_packed() { FLAG_BIT_PACKED } else { 0 };
+        let half_chroma = if self.chroma_subsampling == ChromaSubsampling::Half { FLAG_HALF_CHROMA } else { 0 };
+        let deltas = if self.deltas() { FLAG_SIBLING_DELTAS } else { 0 };
+        return planar | residual | palette | exif | icc | single | dither | codecs | grayscale | bit_packed | half_chroma | deltas | FLAG_CHECKSUM;
+    }
+
+    fn raw_plane(&self, quadtree_root: &Quadtree) -> Vec<u8> {
+        let rank = quadtree_root.size();
+        return (0..rank*rank).map(|i| quadtree_root.get((i % rank, i / rank))).collect();
+    }
+
+    /// Index and data sections of one channel, plus the channel's own flags.
+    fn channel_sections(&self, channel: usize, quadtree_root: &Quadtree, cutoff: &dyn CutoffMap) -> (Vec<u8>, Vec<u8>, u32) {
+        let index = self.leaf_index(quadtree_root, cutoff);
+        let leaf_data = self.leaf_data(quadtree_root, cutoff);
+        return self.encode_sections(channel, index, leaf_data, quadtree_root.size());
+    }
+
+    /// `channel_sections` for a tree already written out as index bits and
+    /// plain leaf values.
+    fn encode_sections(&self, channel: usize, index: BitVec<Local, u8>, leaf_data: Vec<u8>, rank: usize) -> (Vec<u8>, Vec<u8>, u32) {
+        let leaf = self.ordered(leaf_data);
+        if self.single_stream {
+            return (self.coded(channel, vec![]), self.coded(channel, join_stream(&index, &leaf, rank)), 0);
+        }
+        let leaf = if self.bit_packed() { pack_values(&leaf) } else { leaf };
+        let (leaf, huffman) = match self.huffman_leaves {
+            true => huffman_section(leaf, FLAG_HUFFMAN[channel]),
+            false => (leaf, 0),
+        };
+        let runs = if self.rle_index { Some(rle_encode_index(&index)) } else { None };
+        let index = index.into_vec();
+        return match runs {
+            Some(runs) if Deflate.encode(&runs).len() < Deflate.encode(&index).len() => {
+                (self.coded(channel, runs), self.coded(channel, leaf), FLAG_RLE_INDEX[channel] | huffman)
+            },
+            _ => (self.coded(channel, index), self.coded(channel, leaf), huffman),
+        };
+    }
+
+    fn coded(&self, channel: usize, section: Vec<u8>) -> Vec<u8> {
+        let codec: Option<&dyn EntropyCodec> = self.channel_codecs.and_then(|ids| codec_for_id(ids[channel]));
+        return match codec {
+            Some(codec) => codec.encode(&section),
+            None => section,
+        };
+    }
+
+    fn to_file_mapped(&self, header_cutoffs: Cutoff, cutoffs: [&dyn CutoffMap; 3], raw_fallback: bool) -> Vec<u8> {
+        let roots = [&self.lumin_root, &self.c_blu_root, &self.c_red_root];
+        let mut flags = self.flags();
+        let section = |channel: usize| -> (Vec<u8>, Vec<u8>, u32) {
+            if self.grayscale && channel > 0 {
+                return (vec![], vec![], 0);
+            }
+            return self.channel_sections(channel, roots[channel], cutoffs[channel]);
+        };
+        let (luma, c_blu, c_red) = join3(|| section(0), || section(1), || section(2));
+        let mut channels = vec![];
+        for (channel, (index, leaf, channel_flags)) in vec![luma, c_blu, c_red].into_iter().enumerate() {
+            if raw_fallback && index.len() + leaf.len() > roots[channel].size().pow(2) {
+                debug!("channel {} expands to {} bytes, storing raw", channel, index.len() + leaf.len());
+                flags |= FLAG_RAW_CHANNELS[channel];
+                channels.push((self.coded(channel, vec![]), self.coded(channel, self.raw_plane(roots[channel]))));
+            } else {
+                flags |= channel_flags;
+                channels.push((index, leaf));
+            }
+        }
+        return self.assemble(flags, header_cutoffs, &channels);
+    }
+
+    /// `to_file` for channels given as the index bits, plain leaves and rank
+    /// they were stored with, each coarsened to `cutoffs` without building its
+    /// tree; raw channels, given as `None`, are encoded from their roots.
+    fn to_file_coarsened(&self, cutoffs: Cutoff, streams: [Option<StoredStream>; 3]) -> Result<Vec<u8>, DecodeError> {
+        let roots = [&self.lumin_root, &self.c_blu_root, &self.c_red_root];
+        let maps: [&dyn CutoffMap; 3] = [&cutoffs.0, &cutoffs.1, &cutoffs.2];
+        let mut flags = self.flags();
+        let mut channels = vec![];
+        let [luma, c_blu, c_red] = streams;
+        for (channel, stream) in vec![luma, c_blu, c_red].into_iter().enumerate() {
+            let (index, leaf, channel_flags) = match stream {
+                _ if self.grayscale && channel > 0 => (vec![], vec![], 0),
+                Some((index, leaves, rank)) => {
+                    let cutoff = self.min_leaf(maps[channel]);
+                    let (index, leaf_data) = coarsen_leaf_tree(&index, &mut leaves.into_iter(), rank, &cutoff, self.leaf_coding())?;
+                    self.encode_sections(channel, index, leaf_data, rank)
+                },
+                None => self.channel_sections(channel, roots[channel], maps[channel]),
+            };
+            flags |= channel_flags;
+            channels.push((index, leaf));
+        }
+        return Ok(self.assemble(flags, cutoffs, &channels));
+    }
+
+    /// Header, prelude, the three channels' sections and the checksum footer.
+    fn assemble(&self, flags: u32, header_cutoffs: Cutoff, channels: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let (r_index, r_leaf) = &channels[0];
+        let (g_index, g_leaf) = &channels[1];
+        let (b_index, b_leaf) = &channels[2];
+        let index_lens = [r_index.len() as u32, g_index.len() as u32, b_index.len() as u32];
+        let data_lens = [r_leaf.len() as u32, g_leaf.len() as u32, b_leaf.len() as u32];
+        debug!("section sizes index {:?} data {:?}", index_lens, data_lens);
+        let mut bytes = [
+            &self.file_prefix(flags, header_cutoffs, index_lens, data_lens)[..],
+            &r_index[..],
+            &g_index[..],
+            &b_index[..],
+            &r_leaf[..],
+            &g_leaf[..],
+            &b_leaf[..]
+        ].concat();
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        return bytes;
+    }
+
+    fn file_prefix(&self, flags: u32, cutoffs: Cutoff, index_lens: [u32; 3], data_lens: [u32; 3]) -> Vec<u8> {
+        let header = FileHeader {
+            magic: MAGIC,
+            version: VERSION,
+            color_space: self.color_space.id(),
+            flags,
+            rank: self.rank,
+            width: self.width,
+            height: self.height,
+            cutoffs,
+            prediction: self.prediction,
+            index_lens,
+            data_lens,
+        };
+        let palette = self.palette.as_ref().map_or(vec![], |palette| palette_bytes(palette));
+        let exif = self.exif.as_ref().map_or(vec![], |blob| blob_bytes(blob));
+        let icc = self.icc.as_ref().map_or(vec![], |blob| blob_bytes(blob));
+        let dither = self.dither.map_or(vec![], |dither| dither.to_bytes());
+        let codecs = self.channel_codecs.map_or(vec![], |ids| ids.to_vec());
+        return [&header.to_bytes()[..], &palette[..], &exif[..], &icc[..], &dither[..], &codecs[..]].concat();
+    }
+
+    pub fn chunks(&self, cutoffs: Cutoff) -> Chunks<'_> {
+        let cutoffs = self.effective(cutoffs);
+        let roots = [&self.lumin_root, &self.c_blu_root, &self.c_red_root];
+        let maps: [&dyn CutoffMap; 3] = [&cutoffs.0, &cutoffs.1, &cutoffs.2];
+        let mut index_lens = [0u32; 3];
+        let mut data_lens = [0u32; 3];
+        let mut indexes = vec![];
+        let mut data = vec![];
+        let mut flags = self.flags();
+        for channel in 0..3 {
+            let index = if self.grayscale && channel > 0 {
+                data.push(Some(vec![]));
+                vec![]
+            } else if self.residual_vlc || self.packed_leaves || self.single_stream || self.channel_codecs.is_some() || self.bit_packed_values || self.rle_index || self.huffman_leaves || self.sibling_deltas {
+                let (index, leaf, channel_flags) = self.channel_sections(channel, roots[channel], maps[channel]);
+                flags |= channel_flags;
+                data_lens[channel] = leaf.len() as u32;
+                data.push(Some(leaf));
+                index
+            } else {
+                let index = self.leaf_index(roots[channel], maps[channel]);
+                data_lens[channel] = 4 * index.iter().filter(|bit| !**bit).count() as u32;
+                data.push(None);
+                index.into_vec()
+            };
+            index_lens[channel] = index.len() as u32;
+            indexes.push(index);
+        }
+        let prefix = self.file_prefix(flags, cutoffs, index_lens, data_lens);
+        return Chunks { compressor: self, cutoffs, stage: 0, prefix, indexes, data, crc: Crc32::default() };
+    }
+
+    pub fn to_image(&self, cutoffs: Cutoff) -> RgbImage {
+        let cutoffs = self.effective(cutoffs);
+        return self.to_image_mapped([&cutoffs.0, &cutoffs.1, &cutoffs.2]);
+    }
+
+    pub fn to_image_masked(&self, cutoffs: Cutoff, mask: &ImportanceMask) -> RgbImage {
+        let cutoffs = self.effective(cutoffs);
+        return self.to_image_mapped([
+            &mask.with_cutoff(cutoffs.0),
+            &mask.with_cutoff(cutoffs.1),
+            &mask.with_cutoff(cutoffs.2)
+        ]);
+    }
+
+    fn to_image_mapped(&self, cutoffs: [&dyn CutoffMap; 3]) -> RgbImage {
+        let rank = self.rank as usize;
+        let (lumin, c_blu, c_red) = join3(
+            || self.plane(&self.lumin_root, cutoffs[0]),
+            || self.plane(&self.c_blu_root, cutoffs[1]),
+            || self.plane(&self.c_red_root, cutoffs[2])
+        );
+        let img = ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let i = x as usize + y as usize * rank;
+            self.color((x as usize, y as usize), [lumin[i], c_blu[i], c_red[i]])
+        });
+        return img;
+    }
+
+    fn plane(&self, quadtree_root: &Quadtree, cutoff: &dyn CutoffMap) -> Vec<u8> {
+        let rank = self.rank as usize;
+        let size = quadtree_root.size();
+        let scale = match self.scale(quadtree_root) {
+            Some(scale) => scale,
+            None => return vec![quadtree_root.average(); rank * rank],
+        };
+        let mut plane = vec![0u8; size * size];
+        quadtree_root.fill(&self.min_leaf(cutoff), self.clamp_interpolation, self.interpolation, &mut plane);
+        if scale == 1 {
+            return plane;
+        }
+        return (0..rank * rank).map(|i| plane[i % rank / scale + i / rank / scale * size]).collect();
+    }
+
+    /// How many image pixels one pixel of `quadtree_root` covers along each
+    /// axis, or `None` for a constant stand-in tree such as grayscale chroma.
+    fn scale(&self, quadtree_root: &Quadtree) -> Option<usize> {
+        let (rank, size) = (self.rank as usize, quadtree_root.size());
+        if size == rank {
+            return Some(1);
+        }
+        if self.chroma_subsampling == ChromaSubsampling::Half && size * 2 == rank {
+            return Some(2);
+        }
+        return None;
+    }
+
+    pub fn to_image_oriented(&self, cutoffs: Cutoff, orientation: Orientation) -> RgbImage {
+        let cutoffs = self.effective(cutoffs);
+        let dimensions = (self.width, self.height);
+        let (width, height) = if orientation.swaps_axes() { (self.height, self.width) } else { dimensions };
+        return ImageBuffer::from_fn(width, height, |x, y| {
+            let (sx, sy) = orientation.source((x, y), dimensions);
+            return self.pixel((sx as usize, sy as usize), [&cutoffs.0, &cutoffs.1, &cutoffs.2]);
+        });
+    }
+
+    /// Draws one rect per luma leaf region, filled with the region's average
+    /// color and optionally outlined, on a canvas the size of the tree.
+    pub fn to_svg(&self, cutoffs: Cutoff, outline: bool) -> String {
+        let cutoff = self.effective(cutoffs).0;
+        let luma_cutoff = self.min_leaf(&cutoff);
+        let rank = self.rank;
+        let stroke = if outline { r#" stroke="black" stroke-width="0.25""# } else { "" };
+        let mut svg = format!(r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{0}" viewBox="0 0 {0} {0}">"#, rank);
+        svg.push('\n');
+        for leaf in self.lumin_root.leaves(&luma_cutoff) {
+            let average = |root: &Quadtree| -> u8 {
+                return match self.scale(root) {
+                    Some(scale) => root.node_at((leaf.x / scale, leaf.y / scale), (leaf.size / scale).max(2)).average(),
+                    None => root.average(),
+                };
+            };
+            let rgb = self.color((leaf.x, leaf.y), [average(&self.lumin_root), average(&self.c_blu_root), average(&self.c_red_root)]);
+            svg.push_str(&format!(
+                r##"<rect x="{}" y="{}" width="{2}" height="{2}" fill="#{3:02x}{4:02x}{5:02x}"{6}/>"##,
+                leaf.x, leaf.y, leaf.size, rgb[0], rgb[1], rgb[2], stroke
+            ));
+            svg.push('\n');
+        }
+        svg.push_str("</svg>\n");
+        return svg;
+    }
+
+    pub fn to_image_grayscale(&self, cutoff: u8) -> GrayImage {
+        let cutoff = self.effective((cutoff, 0, 0)).0;
+        return ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            Luma([add_prediction(self.lumin_root.get_mapped((x as usize, y as usize), &self.min_leaf(&cutoff)), self.prediction.0)])
+        });
+    }
+
+    /// Whether the first tree alone is the image's luma: true for grayscale
+    /// files and plain YCbCr ones, whose pixels need no palette or dither.
+    fn raw_luma(&self) -> bool {
+        return self.grayscale || (self.color_space == ColorSpace::YCbCr && self.palette.is_none() && self.dither.is_none());
+    }
+
+    fn luma_image(&self) -> GrayImage {
+        return ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            Luma([add_prediction(self.lumin_root.get((x as usize, y as usize)), self.prediction.0)])
+        });
+    }
+
+    pub fn to_image_sharpened(&self, cutoffs: Cutoff, amount: f32) -> RgbImage {
+        let img = self.to_image(cutoffs);
+        let (width, height) = img.dimensions();
+        return ImageBuffer::from_fn(width, height, |x, y| {
+            let mut sum = [0f32; 3];
+            for dy in -1i64..=1 {
+                for dx in -1i64..=1 {
+                    let nx = (x as i64 + dx).max(0).min(width as i64 - 1) as u32;
+                    let ny = (y as i64 + dy).max(0).min(height as i64 - 1) as u32;
+                    for (total, value) in sum.iter_mut().zip(img.get_pixel(nx, ny).0.iter()) {
+                        *total += *value as f32;
+                    }
+                }
+            }
+            let pixel = img.get_pixel(x, y).0;
+            let sharpen = |i: usize| clamp_u8(pixel[i] as f32 + amount * (pixel[i] as f32 - sum[i] / 9f32));
+            return Rgb([sharpen(0), sharpen(1), sharpen(2)]);
+        });
+    }
+
+    pub fn to_image_depth_blurred(&self, cutoffs: Cutoff) -> RgbImage {
+        let img = self.to_image(cutoffs);
+        let luma_cutoff = self.effective(cutoffs).0;
+        let (width, height) = img.dimensions();
+        let (w, h) = (width as usize, height as usize);
+        let mut sums = vec![[0u64; 3]; (w + 1) * (h + 1)];
+        for y in 0..h {
+            for x in 0..w {
+                let pixel = img.get_pixel(x as u32, y as u32).0;
+                for i in 0..3 {
+                    sums[x+1 + (y+1)*(w+1)][i] = pixel[i] as u64 + sums[x + (y+1)*(w+1)][i]
+                        + sums[x+1 + y*(w+1)][i] - sums[x + y*(w+1)][i];
+                }
+            }
+        }
+        return ImageBuffer::from_fn(width, height, |x, y| {
+            let radius = self.lumin_root.describe_pixel((x as usize, y as usize), &luma_cutoff).size / 4;
+            let (x0, y0) = ((x as usize).saturating_sub(radius), (y as usize).saturating_sub(radius));
+            let (x1, y1) = ((x as usize + radius + 1).min(w), (y as usize + radius + 1).min(h));
+            let area = ((x1 - x0) * (y1 - y0)) as u64;
+            let mean = |i: usize| -> u8 {
+                let total = sums[x1 + y1*(w+1)][i] + sums[x0 + y0*(w+1)][i]
+                    - sums[x0 + y1*(w+1)][i] - sums[x1 + y0*(w+1)][i];
+                return (total / area) as u8;
+            };
+            return Rgb([mean(0), mean(1), mean(2)]);
+        });
+    }
+
+    pub fn average_color(&self) -> Rgb<u8> {
+        let rgb = self.color_space.inverse((
+            add_prediction(self.lumin_root.average(), self.prediction.0),
+            add_prediction(self.c_blu_root.average(), self.prediction.1),
+            add_prediction(self.c_red_root.average(), self.prediction.2),
+            0
+        ));
+        return Rgb([rgb.0, rgb.1, rgb.2]);
+    }
+
+    pub fn to_image_with_lut(&self, cutoffs: Cutoff, lut: &Lut3d) -> RgbImage {
+        let cutoffs = self.effective(cutoffs);
+        return ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let rgb = self.pixel((x as usize, y as usize), [&cutoffs.0, &cutoffs.1, &cutoffs.2]);
+            return Rgb(lut.apply(rgb.0));
+        });
+    }
+
+    pub fn to_image_region(&self, cutoffs: Cutoff, (x, y): (u32, u32), (width, height): (u32, u32)) -> RgbImage {
+        let cutoffs = self.effective(cutoffs);
+        let width = width.min(self.width.saturating_sub(x));
+        let height = height.min(self.height.saturating_sub(y));
+        if self.interpolation == Interpolation::Bicubic {
+            return ImageBuffer::from_fn(width, height, |dx, dy| {
+                self.pixel(((x + dx) as usize, (y + dy) as usize), [&cutoffs.0, &cutoffs.1, &cutoffs.2])
+            });
+        }
+        let rect = Rect { x: x as usize, y: y as usize, width: width as usize, height: height as usize };
+        let block = |root: &Quadtree, cutoff: u8| -> (usize, Rect, Vec<u8>) {
+            let scale = match self.scale(root) {
+                Some(scale) => scale,
+                None => return (0, rect, vec![]),
+            };
+            let area = if scale == 2 { rect.halved() } else { rect };
+            let mut out = vec![0; area.width * area.height];
+            root.fill_rect(area, &self.min_leaf(&cutoff), self.clamp_interpolation, self.interpolation, &mut out);
+            return (scale, area, out);
+        };
+        let roots = [&self.lumin_root, &self.c_blu_root, &self.c_red_root];
+        let blocks = [block(roots[0], cutoffs.0), block(roots[1], cutoffs.1), block(roots[2], cutoffs.2)];
+        return ImageBuffer::from_fn(width, height, |dx, dy| {
+            let p = ((x + dx) as usize, (y + dy) as usize);
+            let value = |channel: usize| -> u8 {
+                let (scale, area, out) = &blocks[channel];
+                if *scale == 0 {
+                    return roots[channel].average();
+                }
+                return out[p.0 / scale - area.x + (p.1 / scale - area.y) * area.width];
+            };
+            return self.color(p, [value(0), value(1), value(2)]);
+        });
+    }
+
+    fn pixel(&self, p: Point, cutoffs: [&dyn CutoffMap; 3]) -> Rgb<u8> {
+        let get = |root: &Quadtree, cutoff: &dyn CutoffMap| -> u8 {
+            return match self.scale(root) {
+                Some(scale) => root.sample((p.0 / scale, p.1 / scale), &self.min_leaf(cutoff), self.clamp_interpolation, self.interpolation),
+                None => root.average(),
+            };
+        };
+        return self.color(p, [get(&self.lumin_root, cutoffs[0]), get(&self.c_blu_root, cutoffs[1]), get(&self.c_red_root, cutoffs[2])]);
+    }
+
+    fn color(&self, p: Point, values: [u8; 3]) -> Rgb<u8> {
+        let rgb = self.color_space.inverse((
+            add_prediction(values[0], self.prediction.0),
+            add_prediction(values[1], self.prediction.1),
+            add_prediction(values[2], self.prediction.2),
+            0
+        ));
+        let rgb = match self.dither {
+            Some(dither) => dither.apply([rgb.0, rgb.1, rgb.2], (p.0 as u32, p.1 as u32)),
+            None => [rgb.0, rgb.1, rgb.2],
+        };
+        return match &self.palette {
+            Some(palette) => Rgb(nearest(palette, rgb)),
+            None => Rgb(rgb),
+        };
+    }
+
+    pub fn produces_same_tree(&self, a: Cutoff, b: Cutoff) -> bool {
+        let (a, b) = (self.effective(a), self.effective(b));
+        return self.lumin_root.same_collapse(&a.0, &b.0)
+            && self.c_blu_root.same_collapse(&a.1, &b.1)
+            && self.c_red_root.same_collapse(&a.2, &b.2);
+    }
+
+    pub fn psnr_for(&self, original: &RgbImage, cutoffs: Cutoff) -> f64 {
+        return psnr(original, &self.to_image(cutoffs));
+    }
+
+    pub fn compress_to_quality(&self, original: &RgbImage, target_psnr: f64) -> Cutoff {
+        let (mut low, mut high) = (0u8, 255u8);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if self.psnr_for(original, scaled_cutoffs(mid)) >= target_psnr {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        debug!("quality target {} dB reached at luma cutoff {}", target_psnr, low);
+        return scaled_cutoffs(low);
+    }
+
+    pub fn compress_to_size(&self, max_bytes: usize) -> SizedFile {
+        let encode = |luma: u8| -> Vec<u8> { return entropy::encode(&Deflate, &self.to_file(scaled_cutoffs(luma))); };
+        let smallest = encode(255);
+        if smallest.len() >= max_bytes {
+            warn!("{} bytes is unreachable, the smallest encoding is {} bytes", max_bytes, smallest.len());
+            return SizedFile { cutoffs: scaled_cutoffs(255), bytes: smallest, reached: false };
+        }
+        let (mut low, mut high) = (0u8, 255u8);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if encode(mid).len() < max_bytes {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        return SizedFile { cutoffs: scaled_cutoffs(low), bytes: encode(low), reached: true };
+    }
+
+    pub fn report(&self, cutoffs: Cutoff) -> CompressionReport {
+        let serialized = self.to_file(cutoffs);
+        let raw_bytes = self.width as usize * self.height as usize * 3;
+        let deflated_bytes = Deflate.encode(&serialized).len();
+        return CompressionReport {
+            raw_bytes,
+            serialized_bytes: serialized.len(),
+            deflated_bytes,
+            ratio: raw_bytes as f64 / deflated_bytes as f64,
+        };
+    }
+
+    pub fn ssim_for(&self, original: &RgbImage, cutoffs: Cutoff) -> f64 {
+        return ssim_rgb(original, &self.to_image(cutoffs));
+    }
+
+    pub fn rd_curve(&self, original: &RgbImage, cutoffs_range: RangeInclusive<u8>) -> Vec<(usize, f64)> {
+        let mut curve: Vec<(usize, f64)> = vec![];
+        for cutoff in cutoffs_range {
+            let cutoffs = (cutoff, cutoff, cutoff);
+            let point = match curve.last() {
+                Some(last) if self.produces_same_tree(cutoffs, (cutoff - 1, cutoff - 1, cutoff - 1)) => *last,
+                _ => (self.to_file(cutoffs).len(), self.psnr_for(original, cutoffs)),
+            };
+            curve.push(point);
+        }
+        return curve;
+    }
+
+    pub fn verify_lossless(&self, original: &RgbImage) -> bool {
+        return self.to_image((0, 0, 0)) == *original;
+    }
+
+    pub fn verify_round_trip(&self, cutoffs: Cutoff) -> bool {
+        let cutoffs = self.effective(cutoffs);
+        return match ImgCompressor::from_file(&self.to_file(cutoffs)) {
+            Ok(decoded) => decoded.to_image(cutoffs) == self.to_image(cutoffs),
+            Err(_) => false,
+        }
+    }
+
+    pub fn rows(&self, cutoffs: Cutoff) -> Rows<'_> {
+        return Rows { compressor: self, cutoffs: self.effective(cutoffs), y: 0 };
+    }
+}
+
+pub struct Chunks<'a> {
+    compressor: &'a ImgCompressor,
+    cutoffs: Cutoff,
+    stage: usize,
+    prefix: Vec<u8>,
+    indexes: Vec<Vec<u8>>,
+    data: Vec<Option<Vec<u8>>>,
+    crc: Crc32,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let stage = self.stage;
+        self.stage += 1;
+        let chunk = match stage {
+            0 => Some(std::mem::take(&mut self.prefix)),
+            1..=3 => Some(std::mem::take(&mut self.indexes[stage - 1])),
+            4..=6 => {
+                let compressor = self.compressor;
+                let cutoffs = [self.cutoffs.0, self.cutoffs.1, self.cutoffs.2];
+                let root = [&compressor.lumin_root, &compressor.c_blu_root, &compressor.c_red_root][stage - 4];
+                Some(self.data[stage - 4].take()
+                    .unwrap_or_else(|| compressor.ordered(compressor.leaf_data(root, &cutoffs[stage - 4]))))
+            },
+            7 => return Some(self.crc.finish().to_le_bytes().to_vec()),
+            _ => None,
+        };
+        if let Some(chunk) = &chunk {
+            self.crc.update(chunk);
+        }
+        return chunk;
+    }
+}
+
+pub struct Rows<'a> {
+    compressor: &'a ImgCompressor,
+    cutoffs: Cutoff,
+    y: u32,
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.y >= self.compressor.height { return None; }
+        let (c0, c1, c2) = self.cutoffs;
+        let y = self.y as usize;
+        let row = (0..self.compressor.width as usize)
+            .flat_map(|x| self.compressor.pixel((x, y), [&c0, &c1, &c2]).0.to_vec())
+            .collect();
+        self.y += 1;
+        return Some(row);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ DynamicImage, GenericImageView, Pixel };
+    use crate::entropy::{ Rle, Stored };
+    use crate::testutil::{compressor, sample_image};
+    use super::*;
+
+    thread_local! {
+        static RECORDS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool { return true; }
+        fn log(&self, record: &log::Record) {
+            let line = format!("{} {}", record.level(), record.args());
+            RECORDS.with(|records| records.borrow_mut().push(line));
+        }
+        fn flush(&self) {}
+    }
+
+    fn capture_logs() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CapturingLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        RECORDS.with(|records| records.borrow_mut().clear());
+    }
+
+    #[test]
+    fn encoding_logs_section_sizes() {
+        capture_logs();
+        let compressor = compressor(&sample_image(16));
+        let (header, _) = parse_header(&compressor.to_file((2, 2, 2))).unwrap();
+        let expected = format!("DEBUG section sizes index {:?} data {:?}", header.index_lens, header.data_lens);
+        RECORDS.with(|records| {
+            let records = records.borrow();
+            assert!(records.contains(&expected), "{:?}", records);
+            assert!(records.iter().any(|line| line.starts_with("DEBUG built 16x16 image at rank 16")));
+        });
+    }
+
+    #[test]
+    fn rows_match_to_image() {
+        let compressor = compressor(&sample_image(32));
+        let rows: Vec<Vec<u8>> = compressor.rows((4, 8, 8)).collect();
+        assert_eq!(rows.len(), 32);
+        assert!(rows.iter().all(|row| row.len() == 32 * 3));
+        assert_eq!(rows.concat(), compressor.to_image((4, 8, 8)).into_raw());
+    }
+
+    #[test]
+    fn lossless_luma_round_trips_exactly() {
+        let img = sample_image(32);
+        let lossless = ImgCompressor::new_lossless(DynamicImage::ImageRgb8(img.clone())).unwrap();
+        for (x, y, pixel) in img.enumerate_pixels() {
+            let luma = rgb_to_ycocg_r(pixel.channels4()).0;
+            assert_eq!(lossless.lumin_root.get((x as usize, y as usize)), luma);
+        }
+        let luma_data = lossless.leaf_data(&lossless.lumin_root, &0);
+        assert_eq!(luma_data.len(), 32 * 32);
+        assert_eq!(lossless.to_file((40, 40, 40)), lossless.to_file((0, 0, 0)));
+        assert_eq!(lossless.to_image((40, 40, 40)), img);
+    }
+
+    #[test]
+    fn lossless_round_trip_of_random_pixels_is_identical() {
+        let mut state = 12345u32;
+        let img: RgbImage = ImageBuffer::from_fn(64, 64, |_, _| {
+            let mut next = || {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                return (state >> 16) as u8;
+            };
+            return image::Rgb([next(), next(), next()]);
+        });
+        let lossless = ImgCompressor::new_lossless(DynamicImage::ImageRgb8(img.clone())).unwrap();
+        assert!(lossless.verify_lossless(&img));
+        let decoded = ImgCompressor::from_file(&lossless.to_file((30, 30, 30))).unwrap();
+        assert!(decoded.verify_lossless(&img));
+        assert!(!compressor(&img).verify_lossless(&img));
+    }
+
+    #[test]
+    fn padded_image_keeps_its_dimensions() {
+        let img = sample_image(6);
+        let compressor = compressor(&img);
+        assert_eq!(compressor.rank, 8);
+        assert_eq!((compressor.width(), compressor.height()), (6, 6));
+        assert_eq!(compressor.to_image((0, 0, 0)).dimensions(), (6, 6));
+        let rows: Vec<Vec<u8>> = compressor.rows((0, 0, 0)).collect();
+        assert_eq!(rows.len(), 6);
+        assert!(rows.iter().all(|row| row.len() == 6 * 3));
+        assert_eq!(compressor.lumin_root.get((7, 7)), compressor.lumin_root.get((5, 5)));
+    }
+
+    #[test]
+    fn decoded_file_matches_to_image() {
+        let noise = ImageBuffer::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 97 + y * 31) as u8, (x * y * 13) as u8, (x ^ y) as u8 * 16])
+        });
+        for img in [sample_image(64), sample_image(20), noise].iter() {
+            let compressor = compressor(img);
+            for cutoffs in [(0, 0, 0), (2, 2, 2), (8, 16, 16), (40, 60, 60)].iter() {
+                assert!(compressor.verify_round_trip(*cutoffs));
+            }
+        }
+    }
+
+    #[test]
+    fn dominant_color_prediction_shrinks_mostly_blue_image() {
+        let img = ImageBuffer::from_fn(64, 64, |x, y| {
+            let in_square = (20..36).contains(&x) && (8..24).contains(&y);
+            let in_stripe = (44..48).contains(&y);
+            if in_square { image::Rgb([250, 250, 250]) }
+            else if in_stripe { image::Rgb([220, 30, 30]) }
+            else { image::Rgb([20, 60, 200 + ((x + y) % 3) as u8]) }
+        });
+        let plain = compressor(&img);
+        let predicted = ImgCompressor::new_predicted(DynamicImage::ImageRgb8(img.clone())).unwrap();
+        assert_ne!(predicted.prediction, (128, 128, 128));
+        let deflated = |c: &ImgCompressor| crate::entropy::encode(&crate::entropy::Deflate, &c.to_file((2, 2, 2))).len();
+        assert!(deflated(&predicted) < deflated(&plain));
+        let decoded = ImgCompressor::from_file(&predicted.to_file((0, 0, 0))).unwrap();
+        assert_eq!(decoded.prediction, predicted.prediction);
+        assert_eq!(decoded.to_image((0, 0, 0)), plain.to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn integer_transform_at_cutoff_zero_is_lossless() {
+        let noise = ImageBuffer::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 97 + y * 31) as u8, (x * y * 13) as u8, (x ^ y) as u8 * 16])
+        });
+        let extremes = ImageBuffer::from_fn(8, 8, |x, y| {
+            if (x + y) % 2 == 0 { image::Rgb([255, 0, 255]) } else { image::Rgb([0, 255, 0]) }
+        });
+        for img in [sample_image(32), sample_image(20), noise, extremes].iter() {
+            let dynamic = DynamicImage::ImageRgb8(img.clone());
+            let compressor = ImgCompressor::new_with_color_space(dynamic, ColorSpace::YCoCgR).unwrap();
+            assert_eq!(&compressor.to_image((0, 0, 0)), img);
+            let decoded = ImgCompressor::from_file(&compressor.to_file((0, 0, 0))).unwrap();
+            assert_eq!(&decoded.to_image((0, 0, 0)), img);
+        }
+    }
+
+    #[test]
+    fn rgb_direct_is_exact_where_ycbcr_is_not() {
+        let img: RgbImage = ImageBuffer::from_fn(16, 16, |x, y| image::Rgb([(x * 16) as u8, 255 - (y * 16) as u8, (x * y) as u8]));
+        let dynamic = DynamicImage::ImageRgb8(img.clone());
+        let direct = ImgCompressor::new_with_color_space(dynamic.clone(), ColorSpace::Rgb).unwrap();
+        assert_eq!(direct.to_image((0, 0, 0)), img);
+        let decoded = ImgCompressor::from_file(&direct.to_file((0, 0, 0))).unwrap();
+        assert_eq!(decoded.color_space, ColorSpace::Rgb);
+        assert_eq!(decoded.to_image((0, 0, 0)), img);
+        let transformed = ImgCompressor::new_with_color_space(dynamic, ColorSpace::YCbCr).unwrap();
+        assert_ne!(transformed.to_image((0, 0, 0)), img);
+    }
+
+    #[test]
+    fn ycocg_compresses_smaller_than_bt601_at_equal_quality() {
+        let img = sample_image(64);
+        let build = |color_space: ColorSpace| {
+            return ImgCompressor::new_with_color_space(DynamicImage::ImageRgb8(img.clone()), color_space).unwrap();
+        };
+        let size = |compressor: &ImgCompressor| -> usize {
+            let cutoffs = compressor.compress_to_quality(&img, 32f64);
+            assert!(compressor.psnr_for(&img, cutoffs) >= 32f64);
+            return entropy::encode(&Deflate, &compressor.to_file(cutoffs)).len();
+        };
+        let ycocg = build(ColorSpace::YCoCg);
+        assert!(size(&ycocg) < size(&build(ColorSpace::YCbCr)));
+        let decoded = ImgCompressor::from_file(&ycocg.to_file((0, 0, 0))).unwrap();
+        assert_eq!(decoded.color_space, ColorSpace::YCoCg);
+        for pixel in img.pixels() {
+            let rgb = ycocg_to_rgba(rgb_to_ycocg(pixel.channels4()));
+            let error = |a: u8, b: u8| (a as i32 - b as i32).abs();
+            assert!(error(rgb.0, pixel[0]) <= 1 && error(rgb.1, pixel[1]) <= 1 && error(rgb.2, pixel[2]) <= 1);
+        }
+    }
+
+    #[test]
+    fn planar_leaves_decode_identically() {
+        let mut compressor = compressor(&sample_image(64));
+        let interleaved = compressor.to_file((6, 12, 12));
+        compressor.planar_leaves = true;
+        let planar = compressor.to_file((6, 12, 12));
+        assert_eq!(planar.len(), interleaved.len());
+        assert_ne!(planar, interleaved);
+        let from_planar = ImgCompressor::from_file(&planar).unwrap();
+        let from_interleaved = ImgCompressor::from_file(&interleaved).unwrap();
+        assert!(from_planar.planar_leaves);
+        assert_eq!(from_planar.to_image((0, 0, 0)), from_interleaved.to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn vlc_residuals_decode_identically() {
+        let mut compressor = compressor(&sample_image(64));
+        let fixed = compressor.to_file((6, 12, 12));
+        compressor.residual_vlc = true;
+        let coded = compressor.to_file((6, 12, 12));
+        assert!(coded.len() < fixed.len());
+        let decoded = ImgCompressor::from_file(&coded).unwrap();
+        assert!(decoded.residual_vlc);
+        assert_eq!(decoded.to_image((0, 0, 0)), ImgCompressor::from_file(&fixed).unwrap().to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn sibling_deltas_decode_identically_and_deflate_smaller() {
+        let img = RgbImage::from_fn(128, 128, |x, y| {
+            let (fx, fy) = (x as f32 / 20f32, y as f32 / 27f32);
+            let v = (128f32 + 100f32 * fx.sin() * fy.cos()) as u8;
+            return Rgb([v, v / 2 + 60, 200 - v / 3]);
+        });
+        let mut compressor = compressor(&img);
+        let absolute = compressor.to_file((4, 8, 8));
+        compressor.sibling_deltas = true;
+        let deltas = compressor.to_file((4, 8, 8));
+        assert!(Deflate.encode(&deltas).len() < Deflate.encode(&absolute).len());
+        assert_eq!(compressor.chunks((4, 8, 8)).collect::<Vec<_>>().concat(), deltas);
+        let decoded = ImgCompressor::from_file(&deltas).unwrap();
+        assert!(decoded.sibling_deltas);
+        assert_eq!(decoded.to_image((0, 0, 0)), ImgCompressor::from_file(&absolute).unwrap().to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn report_relates_raw_and_deflated_sizes() {
+        let compressor = compressor(&sample_image(64));
+        let report = compressor.report((4, 8, 8));
+        assert_eq!(report.raw_bytes, 64 * 64 * 3);
+        assert_eq!(report.serialized_bytes, compressor.to_file((4, 8, 8)).len());
+        assert!(report.deflated_bytes <= report.serialized_bytes);
+        assert_eq!(report.ratio, report.raw_bytes as f64 / report.deflated_bytes as f64);
+    }
+
+    #[test]
+    fn config_matches_the_dedicated_constructors() {
+        let img = DynamicImage::ImageRgb8(sample_image(64));
+        let default = ImgCompressor::with_config(img.clone(), &CompressConfig::new()).unwrap();
+        assert_eq!(default.to_file((2, 2, 2)), ImgCompressor::new(img.clone()).unwrap().to_file((2, 2, 2)));
+        let config = CompressConfig::new().chroma(ChromaSubsampling::Half).min_leaf(8).cutoffs((6, 12, 12));
+        let configured = ImgCompressor::with_config(img.clone(), &config).unwrap();
+        let halved = ImgCompressor::new_with_subsampling(img.clone(), ChromaSubsampling::Half).unwrap();
+        let expected = ImgCompressor { min_leaf: 8, ..halved };
+        assert_eq!(configured.to_file((6, 12, 12)), expected.to_file((6, 12, 12)));
+        let file = config.encode(img.clone()).unwrap();
+        assert_eq!(ImgCompressor::from_compressed_file(&file).unwrap().to_image((0, 0, 0)), expected.to_image((6, 12, 12)));
+        let rgb = CompressConfig::new().color_space(ColorSpace::Rgb).backend(EntropyBackend::Deflate, CompressionLevel::Fast);
+        assert_eq!(ImgCompressor::with_config(img.clone(), &rgb).unwrap().color_space, ColorSpace::Rgb);
+        assert!(rgb.encode(img.clone()).unwrap().len() < 64 * 64 * 3);
+        assert_eq!(CompressConfig::new().min_leaf(6).encode(img), Err(CompressError::InvalidMinLeaf(6)));
+    }
+
+    #[test]
+    fn next_compressible_is_smallest_containing_square() {
+        assert_eq!(next_compressible(512, 512), (512, 512));
+        assert_eq!(next_compressible(500, 300), (512, 512));
+        assert_eq!(next_compressible(300, 513), (1024, 1024));
+        assert_eq!(next_compressible(1, 1), (2, 2));
+        assert!(is_power_of_two(64) && !is_power_of_two(48) && !is_power_of_two(0));
+        assert!(is_square(8, 8) && !is_square(8, 4));
+    }
+
+    #[test]
+    fn palette_shrinks_pixel_art_and_reconstructs() {
+        let colors = [[20, 20, 60], [200, 40, 40], [240, 220, 120], [40, 160, 80]];
+        let img = RgbImage::from_fn(64, 64, |x, y| {
+            let [r, g, b] = colors[((x / 8 + y / 16) % 4) as usize];
+            let noise = ((x * 7 + y * 13) % 5) as u8;
+            return Rgb([r + noise, g + noise, b + noise]);
+        });
+        let plain = compressor(&img).to_file((8, 8, 8));
+        let paletted = ImgCompressor::new_paletted(DynamicImage::ImageRgb8(img.clone()), 4).unwrap();
+        let bytes = paletted.to_file((8, 8, 8));
+        assert!(deflate::deflate_bytes(&bytes).len() < deflate::deflate_bytes(&plain).len());
+        let decoded = ImgCompressor::from_file(&bytes).unwrap();
+        assert_eq!(decoded.palette, paletted.palette);
+        let reconstructed = decoded.to_image((0, 0, 0));
+        assert_eq!(reconstructed, paletted.to_image((8, 8, 8)));
+        let palette = decoded.palette.unwrap();
+        assert!(reconstructed.pixels().all(|p| palette.contains(&p.0)));
+        let psnr = crate::metrics::psnr;
+        assert!(psnr(&img, &reconstructed) > psnr(&img, &compressor(&img).to_image((8, 8, 8))));
+    }
+
+    #[test]
+    fn oversized_section_length_is_a_bounded_read_error() {
+        let bytes = compressor(&sample_image(16)).to_file((2, 2, 2));
+        let (mut header, body) = parse_header(&bytes).unwrap();
+        header.data_lens[0] += 5;
+        let tampered = [&header.to_bytes()[..], body].concat();
+        let len = header.data_lens[2] as usize;
+        let offset = header.body_len() - len;
+        assert_eq!(
+            ImgCompressor::from_file(&tampered).map(|_| ()),
+            Err(DecodeError::OutOfBounds { offset, len, end: body.len() })
+        );
+    }
+
+    #[test]
+    fn identity_lut_matches_to_image() {
+        let compressor = compressor(&sample_image(32));
+        assert_eq!(compressor.to_image_with_lut((6, 6, 6), &Lut3d::identity(9)), compressor.to_image((6, 6, 6)));
+    }
+
+    #[test]
+    fn rotate_90_places_pixels_clockwise() {
+        let img = RgbImage::from_fn(4, 4, |x, y| Rgb([(x * 60) as u8, (y * 60) as u8, 0]));
+        let compressor = ImgCompressor::new_lossless(DynamicImage::ImageRgb8(img)).unwrap();
+        let rotated = compressor.to_image_oriented((0, 0, 0), Orientation::Rotate90);
+        for (x, y, pixel) in compressor.to_image((0, 0, 0)).enumerate_pixels() {
+            assert_eq!(rotated.get_pixel(3 - y, x), pixel);
+        }
+        assert_eq!(compressor.to_image_oriented((0, 0, 0), Orientation::Normal), compressor.to_image((0, 0, 0)));
+        assert_eq!(Orientation::from_exif(6), Some(Orientation::Rotate90));
+        assert_eq!(Orientation::from_exif(0), None);
+    }
+
+    #[test]
+    fn icc_blob_round_trips_byte_for_byte() {
+        let mut compressor = compressor(&sample_image(16));
+        let icc: Vec<u8> = (0..600).map(|i| (i * 31 % 256) as u8).collect();
+        compressor.icc = Some(icc.clone());
+        let decoded = ImgCompressor::from_file(&compressor.to_file((2, 2, 2))).unwrap();
+        assert_eq!(decoded.icc, Some(icc));
+        assert_eq!(decoded.exif, None);
+        assert_eq!(decoded.to_image((0, 0, 0)), compressor.to_image((2, 2, 2)));
+    }
+
+    #[test]
+    fn noise_falls_back_to_raw_planes() {
+        let img = RgbImage::from_fn(32, 32, |x, y| {
+            let n = (x * 7919 + y * 104729) ^ (x * y * 31337);
+            return Rgb([(n % 251) as u8, (n / 7 % 253) as u8, (n / 13 % 241) as u8]);
+        });
+        let compressor = compressor(&img);
+        let bytes = compressor.compress_and_verify((0, 0, 0));
+        let (header, _) = parse_header(&bytes).unwrap();
+        assert_eq!(header.flags & FLAG_RAW_CHANNELS[0], FLAG_RAW_CHANNELS[0]);
+        assert_eq!(header.index_lens[0], 0);
+        assert_eq!(header.data_lens[0], 32 * 32);
+        assert!(bytes.len() < compressor.to_file((0, 0, 0)).len());
+        let decoded = ImgCompressor::from_file(&bytes).unwrap();
+        assert_eq!(decoded.to_image((0, 0, 0)), compressor.to_image((0, 0, 0)));
+        let halved = ImgCompressor::new_with_subsampling(DynamicImage::ImageRgb8(img), ChromaSubsampling::Half).unwrap();
+        let bytes = halved.compress_and_verify((0, 0, 0));
+        let (header, _) = parse_header(&bytes).unwrap();
+        assert_eq!(header.flags & FLAG_RAW_CHANNELS[1], FLAG_RAW_CHANNELS[1]);
+        assert_eq!(header.data_lens[1], 16 * 16);
+        assert_eq!(ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0)), halved.to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn streamed_chunks_concatenate_to_file() {
+        let mut compressor = compressor(&sample_image(32));
+        compressor.icc = Some(vec![1, 2, 3]);
+        let chunks: Vec<Vec<u8>> = compressor.chunks((4, 8, 8)).collect();
+        assert_eq!(chunks.len(), 8);
+        assert_eq!(chunks.concat(), compressor.to_file((4, 8, 8)));
+        compressor.residual_vlc = true;
+        assert_eq!(compressor.chunks((4, 8, 8)).collect::<Vec<_>>().concat(), compressor.to_file((4, 8, 8)));
+    }
+
+    #[test]
+    fn packed_leaves_shrink_and_decode_identically() {
+        let mut compressor = compressor(&sample_image(64));
+        let plain = compressor.to_file((3, 3, 3));
+        compressor.packed_leaves = true;
+        let packed = compressor.to_file((3, 3, 3));
+        assert!(packed.len() < plain.len());
+        assert_eq!(compressor.chunks((3, 3, 3)).collect::<Vec<_>>().concat(), packed);
+        let decoded = ImgCompressor::from_file(&packed).unwrap();
+        assert_eq!(decoded.to_image((0, 0, 0)), compressor.to_image((3, 3, 3)));
+    }
+
+    #[test]
+    fn rd_curve_trades_bytes_for_quality() {
+        let original = sample_image(64);
+        let mut curve = compressor(&original).rd_curve(&original, 0..=40);
+        assert_eq!(curve.len(), 41);
+        curve.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for pair in curve.windows(2) {
+            assert!(pair[0].1 <= pair[1].1, "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn decode_as_requested_color_type() {
+        let gray = RgbImage::from_fn(16, 16, |x, y| { let v = (x * 9 + y * 5) as u8; return Rgb([v, v, v]); });
+        let bytes = compressor(&gray).to_file((0, 0, 0));
+        let luma = decode_as(&bytes, ColorType::L8).unwrap().to_luma8();
+        for (x, y, pixel) in luma.enumerate_pixels() {
+            assert!((pixel.0[0] as i16 - gray.get_pixel(x, y).0[0] as i16).abs() <= 1);
+        }
+        let color = sample_image(16);
+        let bytes = compressor(&color).to_file((2, 2, 2));
+        let rgba = decode_as(&bytes, ColorType::Rgba8).unwrap();
+        assert_eq!(rgba.color(), ColorType::Rgba8);
+        assert_eq!(DynamicImage::ImageRgba8(rgba.to_rgba8()).to_rgb8(), ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0)));
+        assert_eq!(decode_as(&bytes, ColorType::L16).map(|_| ()), Err(DecodeError::UnsupportedColorType(ColorType::L16)));
+        for color_space in &[ColorSpace::Rgb, ColorSpace::YCoCgR] {
+            let bytes = ImgCompressor::new_with_color_space(DynamicImage::ImageRgb8(color.clone()), *color_space).unwrap().to_file((0, 0, 0));
+            let expected = DynamicImage::ImageRgb8(ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0))).to_luma8();
+            assert_eq!(decode_as(&bytes, ColorType::L8).unwrap().to_luma8(), expected);
+        }
+        let paletted = ImgCompressor::new_paletted(DynamicImage::ImageRgb8(color.clone()), 4).unwrap().to_file((0, 0, 0));
+        let expected = DynamicImage::ImageRgb8(ImgCompressor::from_file(&paletted).unwrap().to_image((0, 0, 0))).to_luma8();
+        assert_eq!(decode_as(&paletted, ColorType::L8).unwrap().to_luma8(), expected);
+    }
+
+    #[test]
+    fn dead_zone_lengthens_runs_at_small_quality_cost() {
+        let original = sample_image(64);
+        let mut compressor = compressor(&original);
+        let runs = |c: &ImgCompressor| c.leaf_data(&c.lumin_root, &2u8).windows(2).filter(|w| w[0] == w[1]).count();
+        let plain_runs = runs(&compressor);
+        let plain = psnr(&original, &ImgCompressor::from_file(&compressor.to_file((2, 2, 2))).unwrap().to_image((0, 0, 0)));
+        compressor.dead_zone = 4;
+        assert!(runs(&compressor) > plain_runs);
+        let zoned = psnr(&original, &ImgCompressor::from_file(&compressor.to_file((2, 2, 2))).unwrap().to_image((0, 0, 0)));
+        assert!(zoned < plain && zoned > plain - 3f64, "{} {}", plain, zoned);
+    }
+
+    #[test]
+    fn transcode_coarsens_without_the_original() {
+        let bytes = compressor(&sample_image(64)).to_file((2, 4, 4));
+        let transcoded = transcode(&bytes, (10, 12, 12)).unwrap();
+        assert!(transcoded.len() < bytes.len());
+        let decoded = ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0));
+        let reencoded = ImgCompressor::new(DynamicImage::ImageRgb8(decoded.clone())).unwrap().to_file((10, 12, 12));
+        let transcoded_image = ImgCompressor::from_file(&transcoded).unwrap().to_image((0, 0, 0));
+        let reencoded_image = ImgCompressor::from_file(&reencoded).unwrap().to_image((0, 0, 0));
+        assert!(psnr(&reencoded_image, &transcoded_image) > 35.0);
+        assert!(transcoded.len() <= reencoded.len() + reencoded.len() / 20);
+        let variants = [
+            ImgCompressor { single_stream: true, ..compressor(&sample_image(64)) },
+            ImgCompressor { sibling_deltas: true, huffman_leaves: true, rle_index: true, ..compressor(&sample_image(64)) },
+            compressor(&sample_image(64)).subsampled(ChromaSubsampling::Half),
+            ImgCompressor::new_grayscale(DynamicImage::ImageRgb8(sample_image(64))).unwrap(),
+        ];
+        for variant in variants.iter() {
+            let bytes = variant.to_file((2, 4, 4));
+            let decoded = ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0));
+            let transcoded = ImgCompressor::from_file(&transcode(&bytes, (10, 12, 12)).unwrap()).unwrap().to_image((0, 0, 0));
+            assert!(psnr(&decoded, &transcoded) > 30.0);
+        }
+        assert_eq!(parse_header(&transcoded).unwrap().0.cutoffs, (10, 12, 12));
+        assert_eq!(
+            transcode(&bytes, (1, 12, 12)),
+            Err(DecodeError::CutoffBelowStored { stored: (2, 4, 4), requested: (1, 12, 12) })
+        );
+    }
+
+    #[test]
+    fn decoded_luma_channel_matches_full_decode() {
+        let bytes = compressor(&sample_image(32)).to_file((4, 8, 8));
+        let full = ImgCompressor::from_file(&bytes).unwrap();
+        let luma = decode_channel(&bytes, Channel::Luma).unwrap();
+        assert_eq!(luma.dimensions(), (32, 32));
+        for (x, y, pixel) in luma.enumerate_pixels() {
+            let expected = add_prediction(full.lumin_root.get((x as usize, y as usize)), full.prediction.0);
+            assert_eq!(pixel.0[0], expected);
+        }
+        assert_eq!(decode_channel(&bytes, Channel::ChromaRed).unwrap().dimensions(), (32, 32));
+        let half = ImgCompressor::new_with_subsampling(DynamicImage::ImageRgb8(sample_image(32)), ChromaSubsampling::Half).unwrap();
+        let bytes = half.to_file((4, 8, 8));
+        let full = ImgCompressor::from_file(&bytes).unwrap();
+        let c_blu = decode_channel(&bytes, Channel::ChromaBlue).unwrap();
+        assert_eq!(c_blu.dimensions(), (32, 32));
+        for (x, y, pixel) in c_blu.enumerate_pixels() {
+            let expected = add_prediction(full.c_blu_root.get((x as usize / 2, y as usize / 2)), full.prediction.1);
+            assert_eq!(pixel.0[0], expected);
+        }
+    }
+
+    #[test]
+    fn decoded_channel_skips_the_other_channels_sections() {
+        let mut compressor = compressor(&sample_image(32));
+        compressor.channel_codecs = Some([Deflate.id(), Deflate.id(), Deflate.id()]);
+        let bytes = compressor.to_file((4, 8, 8));
+        let (header, _) = parse_header(&bytes).unwrap();
+        let mut corrupt = bytes.clone();
+        let end = corrupt.len() - 4;
+        for byte in &mut corrupt[end - header.data_lens[2] as usize..end] {
+            *byte = 0xFF;
+        }
+        let checksum = crc32(&corrupt[..end]).to_le_bytes();
+        corrupt[end..].copy_from_slice(&checksum);
+        assert!(ImgCompressor::from_file(&corrupt).is_err());
+        assert!(decode_channel(&corrupt, Channel::ChromaRed).is_err());
+        assert_eq!(decode_channel(&corrupt, Channel::Luma).unwrap(), decode_channel(&bytes, Channel::Luma).unwrap());
+    }
+
+    #[test]
+    fn clamped_interpolation_improves_collapsed_regions() {
+        let original = sample_image(64);
+        let mut compressor = compressor(&original);
+        let plain = psnr(&original, &compressor.to_image((30, 30, 30)));
+        compressor.clamp_interpolation = true;
+        assert!(psnr(&original, &compressor.to_image((30, 30, 30))) > plain);
+    }
+
+    #[test]
+    fn sharpening_increases_local_contrast() {
+        let compressor = compressor(&sample_image(64));
+        let contrast = |img: &RgbImage| -> u64 {
+            return img.as_raw().windows(4).map(|w| (w[0] as i64 - w[3] as i64).unsigned_abs()).sum();
+        };
+        let plain = compressor.to_image((12, 12, 12));
+        let sharpened = compressor.to_image_sharpened((12, 12, 12), 0.8);
+        assert_eq!(sharpened.dimensions(), plain.dimensions());
+        assert!(contrast(&sharpened) > contrast(&plain));
+        assert_eq!(compressor.to_image_sharpened((12, 12, 12), 0f32), plain);
+    }
+
+    #[test]
+    fn single_stream_decodes_like_sections() {
+        let mut compressor = compressor(&sample_image(64));
+        let sectioned = compressor.to_file((4, 8, 8));
+        compressor.single_stream = true;
+        compressor.packed_leaves = true;
+        let single = compressor.to_file((4, 8, 8));
+        let (header, _) = parse_header(&single).unwrap();
+        assert_eq!(header.index_lens, [0, 0, 0]);
+        assert_eq!(header.flags & FLAG_SINGLE_STREAM, FLAG_SINGLE_STREAM);
+        assert_eq!(compressor.chunks((4, 8, 8)).collect::<Vec<_>>().concat(), single);
+        let decoded = ImgCompressor::from_file(&single).unwrap();
+        assert_eq!(decoded.to_image((0, 0, 0)), ImgCompressor::from_file(&sectioned).unwrap().to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn single_stream_keeps_half_chroma_ranks() {
+        let mut compressor = ImgCompressor::new_with_subsampling(DynamicImage::ImageRgb8(sample_image(64)), ChromaSubsampling::Half).unwrap();
+        let sectioned = compressor.to_file((4, 8, 8));
+        compressor.single_stream = true;
+        compressor.packed_leaves = true;
+        let single = compressor.to_file((4, 8, 8));
+        assert_eq!(compressor.chunks((4, 8, 8)).collect::<Vec<_>>().concat(), single);
+        let decoded = ImgCompressor::from_file(&single).unwrap();
+        assert_eq!(decoded.c_blu_root.size(), 32);
+        assert_eq!(decoded.to_image((0, 0, 0)), ImgCompressor::from_file(&sectioned).unwrap().to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn truncated_chroma_is_salvaged_and_reported() {
+        let bytes = compressor(&sample_image(64)).to_file((2, 4, 4));
+        let (header, _) = parse_header(&bytes).unwrap();
+        let truncated = &bytes[..bytes.len() - header.data_lens[2] as usize / 2];
+        assert!(ImgCompressor::from_file(truncated).is_err());
+        let full = ImgCompressor::from_file(&bytes).unwrap();
+        let (salvaged, damaged) = ImgCompressor::salvage(truncated).unwrap();
+        assert_eq!(salvaged.lumin_root, full.lumin_root);
+        assert_eq!(salvaged.c_blu_root, full.c_blu_root);
+        assert_ne!(salvaged.c_red_root, full.c_red_root);
+        assert!(!damaged.is_empty());
+        assert!(damaged.iter().all(|region| region.x + region.size <= 64 && region.y + region.size <= 64));
+        let (image, reported) = decode_lenient(truncated).unwrap();
+        assert_eq!(image.dimensions(), (64, 64));
+        assert_eq!(reported, damaged);
+    }
+
+    fn image_300() -> DynamicImage {
+        return DynamicImage::ImageRgb8(sample_image(300));
+    }
+
+    #[test]
+    #[should_panic(expected = "image is 300x300")]
+    fn panic_policy_names_the_dimensions() {
+        let _ = ImgCompressor::new_with_policy(image_300(), Policy::Panic);
+    }
+
+    #[test]
+    fn error_policy_returns_dimensions() {
+        assert_eq!(
+            ImgCompressor::new_with_policy(image_300(), Policy::Error).err(),
+            Some(CompressError::NotSquare { width: 300, height: 300 })
+        );
+        assert!(ImgCompressor::new_with_policy(DynamicImage::ImageRgb8(sample_image(32)), Policy::Error).is_ok());
+    }
+
+    #[test]
+    fn pad_to_fit_policy_pads() {
+        let compressor = ImgCompressor::new_with_policy(image_300(), Policy::PadToFit).unwrap();
+        assert_eq!(compressor.rank, 512);
+        assert_eq!(compressor.to_image((2, 2, 2)).dimensions(), (300, 300));
+    }
+
+    #[test]
+    fn stored_dither_seed_reproduces_output() {
+        let mut compressor = compressor(&sample_image(32));
+        let plain = compressor.to_image((8, 8, 8));
+        compressor.dither = Some(Dither { mode: crate::dither::DitherMode::Noise, seed: 1234 });
+        let bytes = compressor.to_file((8, 8, 8));
+        let first = ImgCompressor::from_file(&bytes).unwrap();
+        let second = ImgCompressor::from_file(&bytes).unwrap();
+        assert_eq!(first.dither, compressor.dither);
+        assert_eq!(first.to_image((0, 0, 0)), second.to_image((0, 0, 0)));
+        assert_eq!(first.to_image((0, 0, 0)), compressor.to_image((8, 8, 8)));
+        assert_ne!(first.to_image((0, 0, 0)), plain);
+    }
+
+    #[test]
+    fn average_color_of_solid_image() {
+        let solid = RgbImage::from_pixel(32, 32, Rgb([30, 140, 220]));
+        let compressor = compressor(&solid);
+        assert_eq!(compressor.average_color(), *compressor.to_image((0, 0, 0)).get_pixel(5, 5));
+        let lossless = ImgCompressor::new_with_color_space(DynamicImage::ImageRgb8(solid), ColorSpace::YCoCgR).unwrap();
+        assert_eq!(lossless.average_color(), Rgb([30, 140, 220]));
+        let halves = RgbImage::from_fn(16, 16, |x, _| if x < 8 { Rgb([0, 0, 0]) } else { Rgb([200, 200, 200]) });
+        let halves = ImgCompressor::new_with_color_space(DynamicImage::ImageRgb8(halves), ColorSpace::YCoCgR).unwrap();
+        assert_eq!(halves.average_color(), Rgb([100, 100, 100]));
+    }
+
+    #[test]
+    fn bounded_file_keeps_every_channel_under_the_threshold() {
+        let compressor = compressor(&sample_image(32));
+        let bytes = compressor.to_file_bounded(4f64);
+        let decoded = ImgCompressor::from_file(&bytes).unwrap();
+        let pairs = [
+            (&compressor.lumin_root, &decoded.lumin_root),
+            (&compressor.c_blu_root, &decoded.c_blu_root),
+            (&compressor.c_red_root, &decoded.c_red_root),
+        ];
+        for (original, restored) in pairs.iter() {
+            let squared: f64 = (0..32 * 32).map(|i| {
+                let diff = original.get((i % 32, i / 32)) as f64 - restored.get((i % 32, i / 32)) as f64;
+                return diff * diff;
+            }).sum();
+            assert!(squared / (32 * 32) as f64 <= 4f64);
+        }
+        assert_ne!(decoded.to_image((0, 0, 0)), compressor.to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn channels_decode_through_their_own_codecs() {
+        let mut compressor = compressor(&sample_image(32));
+        let plain = compressor.to_file((4, 4, 4));
+        compressor.channel_codecs = Some([Deflate.id(), Rle.id(), Rle.id()]);
+        let bytes = compressor.to_file((4, 4, 4));
+        assert_ne!(bytes, plain);
+        let decoded = ImgCompressor::from_file(&bytes).unwrap();
+        assert_eq!(decoded.channel_codecs, Some([0, 2, 2]));
+        assert_eq!(decoded.to_image((0, 0, 0)), compressor.to_image((4, 4, 4)));
+        assert_eq!(compressor.chunks((4, 4, 4)).collect::<Vec<_>>().concat(), bytes);
+        compressor.channel_codecs = Some([0, 9, 2]);
+        let unknown = compressor.to_file((4, 4, 4));
+        assert_eq!(ImgCompressor::from_file(&unknown).map(|_| ()), Err(DecodeError::Entropy(EntropyError::UnknownCodec(9))));
+    }
+
+    #[test]
+    fn write_to_streams_the_to_file_bytes() {
+        let mut compressor = compressor(&sample_image(24));
+        let mut written = vec![];
+        compressor.write_to(&mut written, (3, 6, 6)).unwrap();
+        assert_eq!(written, compressor.to_file((3, 6, 6)));
+        compressor.residual_vlc = true;
+        let mut written = vec![];
+        compressor.write_to(&mut written, (3, 6, 6)).unwrap();
+        assert_eq!(written, compressor.to_file((3, 6, 6)));
+    }
+
+    #[test]
+    fn read_from_decodes_a_stream_and_rejects_a_short_one() {
+        let mut compressor = compressor(&sample_image(24));
+        compressor.exif = Some(vec![1, 2, 3]);
+        compressor.channel_codecs = Some([Deflate.id(), Rle.id(), Stored.id()]);
+        let bytes = compressor.to_file((3, 6, 6));
+        let expected = ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0));
+        assert_eq!(ImgCompressor::read_from(&mut &bytes[..]).unwrap(), expected);
+        for len in [10, HEADER_LEN + 2, bytes.len() - 1].iter() {
+            assert_eq!(ImgCompressor::read_from(&mut &bytes[..*len]), Err(DecodeError::UnexpectedEof));
+        }
+    }
+
+    #[test]
+    fn a_flipped_byte_fails_the_checksum() {
+        let bytes = compressor(&sample_image(16)).to_file((2, 4, 4));
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_LEN + 3] ^= 0x10;
+        let stored = u32::from_le_bytes([bytes[bytes.len() - 4], bytes[bytes.len() - 3], bytes[bytes.len() - 2], bytes[bytes.len() - 1]]);
+        let mismatch = Err(DecodeError::ChecksumMismatch { stored, computed: crc32(&corrupt[..corrupt.len() - 4]) });
+        assert_eq!(ImgCompressor::from_file(&corrupt).map(|_| ()), mismatch);
+        assert_eq!(ImgCompressor::read_from(&mut &corrupt[..]).map(|_| ()), mismatch);
+        assert!(ImgCompressor::from_file(&bytes).is_ok());
+    }
+
+    #[test]
+    fn cutoffs_bracketing_no_contrast_produce_the_same_tree() {
+        let bitmap: Vec<u8> = (0..16 * 16).map(|i| if (i % 16 + i / 16) % 4 == 0 { 100 } else { 110 }).collect();
+        let image = RgbImage::from_fn(16, 16, |x, y| {
+            let v = bitmap[(x + y * 16) as usize];
+            return Rgb([v, v, v]);
+        });
+        let compressor = compressor(&image);
+        assert!(compressor.produces_same_tree((1, 1, 1), (2, 2, 2)));
+        assert!(!compressor.produces_same_tree((1, 1, 1), (200, 200, 200)));
+        assert_eq!(compressor.to_file((1, 1, 1)).len(), compressor.to_file((2, 2, 2)).len());
+    }
+
+    #[test]
+    fn depth_blur_softens_large_regions_only() {
+        let image = RgbImage::from_fn(32, 32, |x, y| {
+            let v = if x < 16 { (x * 2 + y) as u8 } else { ((x * 37 + y * 91) % 256) as u8 };
+            return Rgb([v, v, v]);
+        });
+        let compressor = compressor(&image);
+        let sharp = compressor.to_image((30, 30, 30));
+        let blurred = compressor.to_image_depth_blurred((30, 30, 30));
+        assert_eq!(blurred.dimensions(), sharp.dimensions());
+        let change = |x0: u32, x1: u32| -> u32 {
+            return (x0..x1).flat_map(|x| (0..32).map(move |y| (x, y)))
+                .map(|(x, y)| (sharp.get_pixel(x, y)[0] as i32 - blurred.get_pixel(x, y)[0] as i32).unsigned_abs())
+                .sum();
+        };
+        let detail = compressor.lumin_root.describe_pixel((24, 8), &30u8);
+        assert_eq!(detail.size, 2);
+        assert!(compressor.lumin_root.describe_pixel((4, 4), &30u8).size >= 8);
+        assert!(change(0, 16) > 0);
+        assert_eq!(change(16, 32), 0);
+    }
+
+    #[test]
+    fn strict_mode_rejects_lossy_settings() {
+        let image = sample_image(32);
+        let mut exact = ImgCompressor::new_with_color_space(DynamicImage::ImageRgb8(image.clone()), ColorSpace::YCoCgR).unwrap();
+        exact.strict = true;
+        assert_eq!(exact.try_to_file((2, 0, 0)), Err(CompressError::Lossy(LossySetting::Cutoff((2, 0, 0)))));
+        let bytes = exact.try_to_file((0, 0, 0)).unwrap();
+        assert_eq!(ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0)), image);
+        exact.min_leaf = 8;
+        assert_eq!(exact.try_to_file((0, 0, 0)), Err(CompressError::Lossy(LossySetting::MinLeaf(8))));
+        exact.min_leaf = 2;
+        let config = CompressConfig::new().color_space(ColorSpace::YCoCgR).chroma(ChromaSubsampling::Half);
+        let mut halved = ImgCompressor::with_config(DynamicImage::ImageRgb8(image.clone()), &config).unwrap();
+        halved.strict = true;
+        assert_eq!(halved.try_to_file((0, 0, 0)), Err(CompressError::Lossy(LossySetting::ChromaSubsampling)));
+        let mut rounded = ImgCompressor::new_with_color_space(DynamicImage::ImageRgb8(image.clone()), ColorSpace::YCoCg).unwrap();
+        rounded.strict = true;
+        assert_eq!(rounded.try_to_file((0, 0, 0)), Err(CompressError::Lossy(LossySetting::ColorTransform(ColorSpace::YCoCg))));
+        let mut lossy = compressor(&image);
+        lossy.strict = true;
+        assert_eq!(lossy.try_to_file((0, 0, 0)), Err(CompressError::Lossy(LossySetting::ColorTransform(ColorSpace::YCbCr))));
+        lossy.strict = false;
+        assert_eq!(lossy.try_to_file((4, 4, 4)), Ok(lossy.to_file((4, 4, 4))));
+    }
+
+    #[test]
+    fn new_rejects_empty_images() {
+        let empty = DynamicImage::ImageRgb8(RgbImage::new(0, 0));
+        assert_eq!(ImgCompressor::new(empty.clone()).err(), Some(CompressError::EmptyImage));
+        assert_eq!(ImgCompressor::new_with_policy(empty.clone(), Policy::PadToFit).err(), Some(CompressError::EmptyImage));
+        assert_eq!(ImgCompressor::new_with_color_space(empty.clone(), ColorSpace::Rgb).err(), Some(CompressError::EmptyImage));
+        assert_eq!(ImgCompressor::new_predicted(empty.clone()).err(), Some(CompressError::EmptyImage));
+        assert_eq!(ImgCompressor::new_paletted(empty.clone(), 4).err(), Some(CompressError::EmptyImage));
+        assert_eq!(ImgCompressor::new_lossless(empty.clone()).err(), Some(CompressError::EmptyImage));
+        assert_eq!(ImgCompressor::new_grayscale(empty).err(), Some(CompressError::EmptyImage));
+        let wide = DynamicImage::ImageRgb8(RgbImage::new(8, 4));
+        assert_eq!(ImgCompressor::new(wide).map(|c| (c.rank, c.width, c.height)).ok(), Some((8, 8, 4)));
+        assert_eq!(ImgCompressor::new(DynamicImage::ImageRgb8(sample_image(8))).map(|c| c.rank).ok(), Some(8));
+    }
+
+    #[test]
+    fn builds_from_raw_sample_buffers() {
+        let img = sample_image(12);
+        let compressor = ImgCompressor::from_rgb(img.as_raw(), 12, 12).unwrap();
+        assert_eq!(compressor.to_image((2, 4, 4)), ImgCompressor::new(DynamicImage::ImageRgb8(img.clone())).unwrap().to_image((2, 4, 4)));
+        assert_eq!(ImgCompressor::from_rgb(&img.as_raw()[1..], 12, 12).err(), Some(CompressError::BufferSize { expected: 432, actual: 431 }));
+        let lumin = compressor.raw_plane(&compressor.lumin_root);
+        let (c_blu, c_red) = (compressor.raw_plane(&compressor.c_blu_root), compressor.raw_plane(&compressor.c_red_root));
+        let planes = ImgCompressor::from_channels(lumin.clone(), c_blu.clone(), c_red.clone(), 16).unwrap();
+        assert_eq!(planes.to_file((0, 0, 0)), ImgCompressor { width: 16, height: 16, ..compressor }.to_file((0, 0, 0)));
+        assert_eq!(ImgCompressor::from_channels(lumin, c_blu, vec![0; 10], 16).err(), Some(CompressError::BufferSize { expected: 256, actual: 10 }));
+    }
+
+    #[test]
+    fn auto_cutoffs_follow_channel_detail() {
+        let noisy: Vec<u8> = (0..32 * 32).map(|i| ((i * 7919) % 251) as u8).collect();
+        let gentle: Vec<u8> = (0..32 * 32).map(|i| 120 + (i % 32) as u8 / 4).collect();
+        let compressor = ImgCompressor::from_channels(noisy, vec![128; 32 * 32], gentle, 32).unwrap();
+        let (luma, flat, gradient) = compressor.auto_cutoffs(1f32);
+        assert_eq!(flat, 255);
+        assert!(luma < 5 && luma < gradient && gradient < flat);
+        assert!(compressor.auto_cutoffs(0.5).0 <= luma);
+    }
+
+    #[test]
+    fn region_of_interest_decodes_sharper_inside() {
+        let img = sample_image(32);
+        let compressor = compressor(&img);
+        let rect = Rect { x: 0, y: 0, width: 16, height: 16 };
+        let plain = ImgCompressor::from_file(&compressor.to_file((40, 40, 40))).unwrap().to_image((0, 0, 0));
+        let roi = ImgCompressor::from_file(&compressor.to_file_roi((40, 40, 40), rect, (0, 0, 0))).unwrap().to_image((0, 0, 0));
+        let crop = |image: &RgbImage| image.view(0, 0, 16, 16).to_image();
+        assert!(psnr(&crop(&img), &crop(&roi)) > psnr(&crop(&img), &crop(&plain)));
+        assert_eq!(roi.view(16, 16, 16, 16).to_image(), plain.view(16, 16, 16, 16).to_image());
+    }
+
+    #[test]
+    fn region_decode_matches_the_cropped_full_decode() {
+        let mut compressor = compressor(&sample_image(40));
+        let rect = Rect { x: 20, y: 0, width: 20, height: 20 };
+        let bytes = compressor.to_file((4, 8, 8));
+        let full = ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0));
+        assert_eq!(decode_region(&bytes, rect).unwrap(), full.view(20, 0, 20, 20).to_image());
+        let clipped = decode_region(&bytes, Rect { x: 30, y: 35, width: 64, height: 64 }).unwrap();
+        assert_eq!(clipped, full.view(30, 35, 10, 5).to_image());
+        compressor.single_stream = true;
+        let bytes = compressor.to_file((4, 8, 8));
+        assert_eq!(decode_region(&bytes, rect).unwrap(), full.view(20, 0, 20, 20).to_image());
+        let half = ImgCompressor::new_with_subsampling(DynamicImage::ImageRgb8(sample_image(40)), ChromaSubsampling::Half).unwrap();
+        let bytes = half.to_file((4, 8, 8));
+        let full = ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0));
+        assert_eq!(decode_region(&bytes, Rect { x: 3, y: 7, width: 13, height: 9 }).unwrap(), full.view(3, 7, 13, 9).to_image());
+    }
+
+    fn box_filtered(image: &RgbImage, pitch: u32) -> RgbImage {
+        return ImageBuffer::from_fn(image.width() / pitch, image.height() / pitch, |x, y| {
+            let mut sum = [0u32; 3];
+            for p in image.view(x * pitch, y * pitch, pitch, pitch).pixels() {
+                for (total, value) in sum.iter_mut().zip((p.2).0.iter()) {
+                    *total += *value as u32;
+                }
+            }
+            return Rgb(sum.map(|s| (s / (pitch * pitch)) as u8));
+        });
+    }
+
+    #[test]
+    fn thumbnail_stops_at_the_preview_pitch() {
+        let bytes = compressor(&sample_image(512)).to_file((4, 8, 8));
+        let thumbnail = decode_thumbnail(&bytes, 64).unwrap();
+        assert_eq!(thumbnail.dimensions(), (64, 64));
+        let full = ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0));
+        assert!(psnr(&box_filtered(&full, 8), &thumbnail) > 30f64);
+        let half = ImgCompressor::new_with_subsampling(DynamicImage::ImageRgb8(sample_image(40)), ChromaSubsampling::Half).unwrap();
+        let bytes = half.to_file((0, 0, 0));
+        assert_eq!(decode_thumbnail(&bytes, 40).unwrap(), ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0)));
+        assert_eq!(decode_thumbnail(&bytes, 25).unwrap().dimensions(), (20, 20));
+    }
+
+    #[test]
+    #[ignore]
+    fn thumbnail_outpaces_full_decode_and_resize() {
+        let bytes = compressor(&sample_image(512)).to_file((4, 8, 8));
+        let start = std::time::Instant::now();
+        let thumbnail = decode_thumbnail(&bytes, 64).unwrap();
+        let thumbnail_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let full = ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0));
+        let resized = image::imageops::thumbnail(&full, 64, 64);
+        let full_time = start.elapsed();
+        println!("thumbnail {:?}, full decode and resize {:?}", thumbnail_time, full_time);
+        assert_eq!(thumbnail.dimensions(), resized.dimensions());
+        assert!(thumbnail_time * 4 < full_time);
+    }
+
+    #[test]
+    fn padding_never_reaches_the_visible_image() {
+        let img = RgbImage::from_fn(100, 100, |x, y| Rgb([(x * 2) as u8, (y * 2) as u8, 90]));
+        let padded = compressor(&img);
+        assert_eq!(padded.rank, 128);
+        let decoded = ImgCompressor::from_file(&padded.to_file((0, 0, 0))).unwrap();
+        assert_eq!(decoded.to_image((0, 0, 0)).dimensions(), (100, 100));
+        assert_eq!(decoded.to_image((0, 0, 0)), padded.to_image((0, 0, 0)));
+        let small = ImgCompressor::from_file(&compressor(&sample_image(6)).to_file((0, 0, 0))).unwrap();
+        assert_eq!(small.to_image((0, 0, 0)).dimensions(), (6, 6));
+    }
+
+    #[test]
+    fn rectangular_image_keeps_its_aspect_ratio() {
+        let img = RgbImage::from_fn(640, 480, |x, y| Rgb([(x / 3) as u8, (y / 2) as u8, ((x + y) / 5) as u8]));
+        let wide = compressor(&img);
+        assert_eq!(wide.rank, 1024);
+        let decoded = ImgCompressor::from_file(&wide.to_file((4, 4, 4))).unwrap();
+        assert_eq!((decoded.width, decoded.height), (640, 480));
+        let path = std::env::temp_dir().join(format!("quad-compress-wide-{}.png", std::process::id()));
+        decoded.to_image((0, 0, 0)).save(&path).unwrap();
+        assert_eq!(image::image_dimensions(&path).unwrap(), (640, 480));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(decoded.to_image((0, 0, 0)), wide.to_image((4, 4, 4)));
+    }
+
+    #[test]
+    fn grayscale_mode_stores_only_luma() {
+        let gray = GrayImage::from_fn(32, 32, |x, y| Luma([(x * 7 + y * 3) as u8]));
+        let rgb = DynamicImage::ImageLuma8(gray.clone()).to_rgb8();
+        let grayscale = ImgCompressor::new_grayscale(DynamicImage::ImageLuma8(gray.clone())).unwrap();
+        assert_eq!(grayscale.to_image_grayscale(0), gray);
+        let bytes = grayscale.to_file((0, 0, 0));
+        assert!(bytes.len() * 2 < compressor(&rgb).to_file((0, 0, 0)).len());
+        let decoded = ImgCompressor::from_file(&bytes).unwrap();
+        assert!(decoded.grayscale);
+        assert_eq!(decoded.to_image_grayscale(0), gray);
+        assert_eq!(decoded.to_image((0, 0, 0)), grayscale.to_image((0, 0, 0)));
+        assert_eq!(grayscale.chunks((0, 0, 0)).collect::<Vec<_>>().concat(), bytes);
+    }
+
+    #[test]
+    fn larger_min_leaf_stores_fewer_nodes() {
+        let img = sample_image(64);
+        let dynamic = || DynamicImage::ImageRgb8(img.clone());
+        assert_eq!(ImgCompressor::new_with_min_leaf(dynamic(), 6).err(), Some(CompressError::InvalidMinLeaf(6)));
+        let plain = ImgCompressor::new_with_min_leaf(dynamic(), 2).unwrap();
+        let coarse = ImgCompressor::new_with_min_leaf(dynamic(), 8).unwrap();
+        let nodes = |c: &ImgCompressor| c.leaf_index(&c.lumin_root, &4u8).len();
+        assert!(nodes(&coarse) < nodes(&plain));
+        assert!(coarse.to_file((4, 4, 4)).len() < plain.to_file((4, 4, 4)).len());
+        let decoded = ImgCompressor::from_file(&coarse.to_file((4, 4, 4))).unwrap();
+        assert_eq!(decoded.to_image((0, 0, 0)), coarse.to_image((4, 4, 4)));
+        let checkers = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, y| if (x + y) % 2 == 0 { Rgb([0; 3]) } else { Rgb([255; 3]) }));
+        let plain = ImgCompressor::new_with_min_leaf(checkers.clone(), 2).unwrap();
+        let coarse = ImgCompressor::new_with_min_leaf(checkers, 8).unwrap();
+        assert!(coarse.to_file((0, 0, 0)).len() < plain.to_file((0, 0, 0)).len());
+        assert_eq!(nodes(&coarse), 1);
+    }
+
+    #[test]
+    #[ignore]
+    fn region_fill_outpaces_per_pixel_descent() {
+        let compressor = compressor(&sample_image(2048));
+        let cutoffs = (8u8, 8u8, 8u8);
+        let start = std::time::Instant::now();
+        let filled = compressor.to_image(cutoffs);
+        let fill_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let descended: RgbImage = ImageBuffer::from_fn(2048, 2048, |x, y| {
+            compressor.pixel((x as usize, y as usize), [&cutoffs.0, &cutoffs.1, &cutoffs.2])
+        });
+        let descent_time = start.elapsed();
+        println!("fill {:?}, per-pixel descent {:?}", fill_time, descent_time);
+        assert_eq!(filled, descended);
+        assert!(fill_time < descent_time);
+    }
+
+    #[test]
+    #[ignore]
+    fn channel_trees_build_faster_with_rayon() {
+        let planes: Vec<Vec<u8>> = (0..3).map(|c| (0..1024 * 1024).map(|i| ((i * (c + 3)) % 251) as u8).collect()).collect();
+        let start = std::time::Instant::now();
+        let serial = [Quadtree::new(&planes[0]), Quadtree::new(&planes[1]), Quadtree::new(&planes[2])];
+        let serial_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let (a, b, c) = join3(|| Quadtree::new(&planes[0]), || Quadtree::new(&planes[1]), || Quadtree::new(&planes[2]));
+        let joined_time = start.elapsed();
+        println!("serial {:?}, joined {:?}", serial_time, joined_time);
+        assert_eq!(serial, [a, b, c]);
+    }
+
+    #[test]
+    fn psnr_for_is_infinite_only_when_exact() {
+        let img = sample_image(32);
+        let exact = ImgCompressor::new_with_color_space(DynamicImage::ImageRgb8(img.clone()), ColorSpace::YCoCgR).unwrap();
+        assert_eq!(exact.psnr_for(&img, (0, 0, 0)), f64::INFINITY);
+        assert!(exact.psnr_for(&img, (30, 30, 30)) < exact.psnr_for(&img, (4, 4, 4)));
+        assert!((exact.ssim_for(&img, (0, 0, 0)) - 1f64).abs() < 1e-9);
+        assert!(exact.ssim_for(&img, (60, 60, 60)) < 1f64);
+    }
+
+    #[test]
+    fn quality_search_lands_just_above_the_target() {
+        let img = sample_image(32);
+        let compressor = compressor(&img);
+        assert_eq!(compressor.compress_to_quality(&img, 200f64), (0, 0, 0));
+        let cutoffs = compressor.compress_to_quality(&img, 30f64);
+        assert!(cutoffs.0 > 0);
+        assert_eq!(cutoffs.1, cutoffs.0.saturating_mul(2));
+        assert!(compressor.psnr_for(&img, cutoffs) >= 30f64);
+    }
+
+    #[test]
+    fn size_search_meets_reachable_targets() {
+        let compressor = compressor(&sample_image(64));
+        let lossless = entropy::encode(&Deflate, &compressor.to_file((0, 0, 0))).len();
+        let sized = compressor.compress_to_size(lossless / 2);
+        assert!(sized.reached);
+        assert!(sized.bytes.len() < lossless / 2);
+        assert!(sized.cutoffs.0 > 0);
+        assert_eq!(ImgCompressor::from_file(&entropy::decode(&sized.bytes).unwrap()).unwrap().width, 64);
+        let unreachable = compressor.compress_to_size(10);
+        assert!(!unreachable.reached);
+        assert_eq!(unreachable.cutoffs, (255, 255, 255));
+    }
+
+    #[test]
+    fn nearest_interpolation_renders_flat_blocks() {
+        let mut compressor = compressor(&sample_image(32));
+        let bilinear = compressor.to_image((30, 30, 30));
+        compressor.interpolation = Interpolation::Nearest;
+        let nearest = compressor.to_image((30, 30, 30));
+        assert_ne!(nearest, bilinear);
+        let region = compressor.to_image_region((30, 30, 30), (0, 0), (32, 32));
+        assert_eq!(region, nearest);
+    }
+
+    #[test]
+    fn bicubic_smooths_gradients_better_than_bilinear() {
+        let img = RgbImage::from_fn(64, 64, |x, y| {
+            let (fx, fy) = (x as f32 / 10f32, y as f32 / 13f32);
+            let v = (128f32 + 100f32 * fx.sin() * fy.cos()) as u8;
+            return Rgb([v, v, v]);
+        });
+        let mut compressor = compressor(&img);
+        let bilinear = compressor.ssim_for(&img, (40, 40, 40));
+        compressor.interpolation = Interpolation::Bicubic;
+        let bicubic = compressor.ssim_for(&img, (40, 40, 40));
+        assert!(bicubic > bilinear, "bicubic {} vs bilinear {}", bicubic, bilinear);
+        let region = compressor.to_image_region((40, 40, 40), (0, 0), (64, 64));
+        assert_eq!(region, compressor.to_image((40, 40, 40)));
+    }
+
+    #[test]
+    fn compressed_files_round_trip_through_each_backend() {
+        let compressor = compressor(&sample_image(32));
+        let backends = [
+            EntropyBackend::Deflate,
+            #[cfg(feature = "zstd")]
+            EntropyBackend::Zstd,
+        ];
+        for backend in backends.iter().copied() {
+            let file = compressor.to_compressed_file((2, 2, 2), backend);
+            let decoded = ImgCompressor::from_compressed_file(&file).unwrap();
+            assert_eq!(decoded.to_image((0, 0, 0)), compressor.to_image((2, 2, 2)));
+        }
+        assert!(ImgCompressor::from_compressed_file(&[]).is_err());
+    }
+
+    #[test]
+    fn bit_packed_values_decode_to_the_same_trees() {
+        let img = RgbImage::from_fn(32, 32, |x, y| {
+            let noise = ((x * 7 + y * 13) % 5) as u8;
+            return Rgb([(x * 8) as u8 + noise, (x * 8) as u8, (x * 8) as u8 + 4 - noise]);
+        });
+        let mut compressor = compressor(&img);
+        let plain = compressor.to_file((2, 2, 2));
+        compressor.bit_packed_values = true;
+        let packed = compressor.to_file((2, 2, 2));
+        let (plain_header, _) = parse_header(&plain).unwrap();
+        let (packed_header, _) = parse_header(&packed).unwrap();
+        assert!(packed_header.data_lens[1] * 2 <= plain_header.data_lens[1]);
+        assert_eq!(compressor.chunks((2, 2, 2)).collect::<Vec<_>>().concat(), packed);
+        let (plain, packed) = (ImgCompressor::from_file(&plain).unwrap(), ImgCompressor::from_file(&packed).unwrap());
+        assert!(packed.bit_packed_values);
+        assert_eq!(packed.lumin_root, plain.lumin_root);
+        assert_eq!(packed.c_blu_root, plain.c_blu_root);
+        assert_eq!(packed.c_red_root, plain.c_red_root);
+    }
+
+    #[test]
+    fn huffman_leaves_are_kept_only_when_they_win() {
+        let noisy = RgbImage::from_fn(64, 64, |x, y| {
+            let v = ((x * 7919 + y * 104729) % 4) as u8 * 60;
+            return Rgb([v, 100 + v / 4, 90]);
+        });
+        for (img, wins) in [(sample_image(64), false), (noisy, true)].iter() {
+            let mut compressor = compressor(img);
+            let plain = compressor.to_file((0, 0, 0));
+            compressor.huffman_leaves = true;
+            let coded = compressor.to_file((0, 0, 0));
+            let (header, _) = parse_header(&coded).unwrap();
+            assert_eq!(header.flags & FLAG_HUFFMAN[0] != 0, *wins);
+            assert!(Deflate.encode(&coded).len() <= Deflate.encode(&plain).len());
+            assert_eq!(compressor.chunks((0, 0, 0)).collect::<Vec<_>>().concat(), coded);
+            let decoded = ImgCompressor::from_file(&coded).unwrap();
+            assert_eq!(decoded.lumin_root, ImgCompressor::from_file(&plain).unwrap().lumin_root);
+        }
+    }
+
+    #[test]
+    fn corrupt_huffman_sections_fail_without_panicking() {
+        let noisy = RgbImage::from_fn(64, 64, |x, y| {
+            let v = ((x * 7919 + y * 104729) % 4) as u8 * 60;
+            return Rgb([v, 100 + v / 4, 90]);
+        });
+        let mut compressor = compressor(&noisy);
+        compressor.huffman_leaves = true;
+        let bytes = compressor.to_file((0, 0, 0));
+        let (header, _) = parse_header(&bytes).unwrap();
+        assert_ne!(header.flags & FLAG_HUFFMAN[0], 0);
+        let start = HEADER_LEN + header.index_lens.iter().sum::<u32>() as usize;
+        for i in start..start + header.data_lens[0] as usize {
+            for flip in [1u8, 0x10, 0xFF].iter() {
+                let mut corrupt = bytes.clone();
+                corrupt[i] ^= flip;
+                let _ = decode_lenient(&corrupt);
+            }
+        }
+    }
+
+    #[test]
+    fn run_length_index_is_kept_only_when_it_wins() {
+        let img = RgbImage::from_fn(64, 64, |x, y| {
+            let v = if x < 48 { ((x * 7 + y * 13) % 5) as u8 * 40 } else { 100 };
+            return Rgb([v, v, v]);
+        });
+        let mut compressor = compressor(&img);
+        let plain = compressor.to_file((2, 2, 2));
+        compressor.rle_index = true;
+        let runs = compressor.to_file((2, 2, 2));
+        let (header, _) = parse_header(&runs).unwrap();
+        assert_ne!(header.flags & FLAG_RLE_INDEX[0], 0);
+        assert_eq!(header.flags & FLAG_RLE_INDEX[1], 0);
+        assert!(entropy::encode(&Deflate, &runs).len() < entropy::encode(&Deflate, &plain).len());
+        assert_eq!(compressor.chunks((2, 2, 2)).collect::<Vec<_>>().concat(), runs);
+        let decoded = ImgCompressor::from_file(&runs).unwrap();
+        assert_eq!(decoded.to_image((0, 0, 0)), compressor.to_image((2, 2, 2)));
+    }
+
+    #[test]
+    fn half_chroma_shrinks_chroma_with_little_loss() {
+        let img = RgbImage::from_fn(64, 64, |x, y| {
+            let wave = (x as f32 / 9f32).sin() * (y as f32 / 7f32).cos();
+            return Rgb([(x * 4) as u8, (128f32 + 60f32 * wave) as u8, (y * 4) as u8]);
+        });
+        let full = compressor(&img);
+        let half = ImgCompressor::new_with_subsampling(DynamicImage::ImageRgb8(img.clone()), ChromaSubsampling::Half).unwrap();
+        assert_eq!((half.lumin_root.size(), half.c_blu_root.size()), (64, 32));
+        let (full_header, _) = parse_header(&full.to_file((0, 0, 0))).unwrap();
+        let file = half.to_file((0, 0, 0));
+        let (half_header, _) = parse_header(&file).unwrap();
+        assert_eq!(half_header.data_lens[0], full_header.data_lens[0]);
+        assert!(half_header.data_lens[1] * 3 < full_header.data_lens[1]);
+        assert!(half.psnr_for(&img, (0, 0, 0)) > 35f64);
+        let decoded = ImgCompressor::from_file(&file).unwrap();
+        assert_eq!(decoded.chroma_subsampling, ChromaSubsampling::Half);
+        assert_eq!(decoded.to_image((0, 0, 0)), half.to_image((0, 0, 0)));
+        assert_eq!(half.to_image_region((0, 0, 0), (0, 0), (64, 64)), half.to_image((0, 0, 0)));
+    }
+
+    #[test]
+    fn stats_match_the_serialized_index() {
+        let compressor = compressor(&sample_image(32));
+        let stats = compressor.stats((4, 40, 40));
+        let (header, _) = parse_header(&compressor.to_file((4, 40, 40))).unwrap();
+        for (channel, stats) in stats.iter().enumerate() {
+            assert_eq!(stats.total_nodes.div_ceil(8), header.index_lens[channel] as usize);
+            assert_eq!(4 * (stats.collapsed_leaves + stats.real_leaves), header.data_lens[channel] as usize);
+            assert_eq!(stats.depth_histogram.iter().sum::<usize>(), stats.collapsed_leaves + stats.real_leaves);
+        }
+        assert!(stats[0].total_nodes > stats[1].total_nodes);
+        assert_eq!(TreeStats::combined(&stats).total_nodes, stats.iter().map(|s| s.total_nodes).sum::<usize>());
+    }
+
+    #[test]
+    fn svg_draws_one_rect_per_luma_leaf() {
+        let solid = compressor(&RgbImage::from_pixel(8, 8, Rgb([30, 140, 220])));
+        let svg = solid.to_svg((2, 2, 2), false);
+        assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="8" height="8""#));
+        assert_eq!(svg.matches("<rect ").count(), 1);
+        assert!(svg.contains(r##"<rect x="0" y="0" width="8" height="8" fill="#"##));
+        let compressor = compressor(&sample_image(32));
+        let svg = compressor.to_svg((20, 40, 40), true);
+        assert_eq!(svg.matches("<rect ").count(), compressor.lumin_root.leaves(&20u8).count());
+        assert_eq!(svg.matches(r#"stroke="black""#).count(), svg.matches("<rect ").count());
     }
 }
@@ -0,0 +1,74 @@
+use crate::serialize::{BoundedReader, DecodeError};
+
+const BAYER: [[i16; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DitherMode {
+    Noise,
+    Ordered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dither {
+    pub mode: DitherMode,
+    pub seed: u32,
+}
+
+fn hash(seed: u32, x: u32, y: u32, channel: u32) -> u32 {
+    let mut h = seed ^ x.wrapping_mul(0x9E37_79B1) ^ y.wrapping_mul(0x85EB_CA77) ^ channel.wrapping_mul(0xC2B2_AE3D);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2C1B_3C6D);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297A_2D39);
+    return h ^ (h >> 15);
+}
+
+impl Dither {
+    pub fn offset(&self, (x, y): (u32, u32), channel: u32) -> i16 {
+        return match self.mode {
+            DitherMode::Noise => (hash(self.seed, x, y, channel) % 3) as i16 - 1,
+            DitherMode::Ordered => {
+                let shift = self.seed.wrapping_add(channel);
+                let cell = BAYER[((y + shift) % 4) as usize][((x + (shift >> 2)) % 4) as usize];
+                (cell - 8).signum()
+            },
+        };
+    }
+
+    pub fn apply(&self, rgb: [u8; 3], p: (u32, u32)) -> [u8; 3] {
+        let dither = |channel: usize| (rgb[channel] as i16 + self.offset(p, channel as u32)).clamp(0, 255) as u8;
+        return [dither(0), dither(1), dither(2)];
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mode = match self.mode { DitherMode::Noise => 0u8, DitherMode::Ordered => 1u8 };
+        return [&[mode][..], &self.seed.to_le_bytes()[..]].concat();
+    }
+
+    pub fn read(reader: &mut BoundedReader) -> Result<Dither, DecodeError> {
+        let bytes = reader.section(5)?;
+        let mode = match bytes[0] {
+            0 => DitherMode::Noise,
+            1 => DitherMode::Ordered,
+            other => return Err(DecodeError::UnknownDitherMode(other)),
+        };
+        return Ok(Dither { mode, seed: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_are_small_and_seeded() {
+        let a = Dither { mode: DitherMode::Noise, seed: 1 };
+        let b = Dither { mode: DitherMode::Noise, seed: 2 };
+        let offsets = |d: &Dither| -> Vec<i16> { return (0..64).map(|i| d.offset((i % 8, i / 8), 0)).collect(); };
+        assert!(offsets(&a).iter().all(|o| (-1..=1).contains(o)));
+        assert_eq!(offsets(&a), offsets(&a));
+        assert_ne!(offsets(&a), offsets(&b));
+        let bytes = Dither { mode: DitherMode::Ordered, seed: 77 }.to_bytes();
+        assert_eq!(Dither::read(&mut BoundedReader::new(&bytes)), Ok(Dither { mode: DitherMode::Ordered, seed: 77 }));
+    }
+}
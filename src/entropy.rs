@@ -0,0 +1,231 @@
+use deflate::{deflate_bytes, deflate_bytes_conf, Compression};
+use miniz_oxide::inflate::decompress_to_vec;
+
+#[derive(Debug, PartialEq)]
+pub enum EntropyError {
+    Empty,
+    UnknownCodec(u8),
+    Corrupt,
+}
+
+pub trait EntropyCodec {
+    fn id(&self) -> u8;
+    fn name(&self) -> &'static str;
+    fn encode(&self, data: &[u8]) -> Vec<u8>;
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, EntropyError>;
+}
+
+pub struct Deflate;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionLevel { Fast, Default, Best }
+
+/// Deflate at a chosen level; decodes exactly like `Deflate` and shares its id.
+pub struct DeflateAt(pub CompressionLevel);
+
+pub struct Stored;
+
+pub struct Rle;
+
+impl EntropyCodec for Deflate {
+    fn id(&self) -> u8 {
+        return 0;
+    }
+    fn name(&self) -> &'static str {
+        return "deflate";
+    }
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        return deflate_bytes(data);
+    }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, EntropyError> {
+        return decompress_to_vec(data).map_err(|_| EntropyError::Corrupt);
+    }
+}
+
+impl EntropyCodec for DeflateAt {
+    fn id(&self) -> u8 {
+        return Deflate.id();
+    }
+    fn name(&self) -> &'static str {
+        return Deflate.name();
+    }
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let level = match self.0 {
+            CompressionLevel::Fast => Compression::Fast,
+            CompressionLevel::Default => Compression::Default,
+            CompressionLevel::Best => Compression::Best,
+        };
+        return deflate_bytes_conf(data, level);
+    }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, EntropyError> {
+        return Deflate.decode(data);
+    }
+}
+
+/// Zstandard at a chosen level, available with the `zstd` feature.
+#[cfg(feature = "zstd")]
+pub struct Zstd(pub CompressionLevel);
+
+#[cfg(feature = "zstd")]
+impl EntropyCodec for Zstd {
+    fn id(&self) -> u8 {
+        return 3;
+    }
+    fn name(&self) -> &'static str {
+        return "zstd";
+    }
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let level = match self.0 {
+            CompressionLevel::Fast => 1,
+            CompressionLevel::Default => zstd::DEFAULT_COMPRESSION_LEVEL,
+            CompressionLevel::Best => 19,
+        };
+        return zstd::encode_all(data, level).expect("in-memory zstd encoding");
+    }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, EntropyError> {
+        return zstd::decode_all(data).map_err(|_| EntropyError::Corrupt);
+    }
+}
+
+/// The final compression step of a `.ski` file. The codec id written by
+/// `encode` records the choice, so `decode` needs no hint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntropyBackend {
+    Deflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl EntropyBackend {
+    pub fn codec(&self, level: CompressionLevel) -> Box<dyn EntropyCodec> {
+        return match self {
+            EntropyBackend::Deflate => Box::new(DeflateAt(level)),
+            #[cfg(feature = "zstd")]
+            EntropyBackend::Zstd => Box::new(Zstd(level)),
+        };
+    }
+}
+
+impl EntropyCodec for Stored {
+    fn id(&self) -> u8 {
+        return 1;
+    }
+    fn name(&self) -> &'static str {
+        return "stored";
+    }
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        return data.to_vec();
+    }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, EntropyError> {
+        return Ok(data.to_vec());
+    }
+}
+
+impl EntropyCodec for Rle {
+    fn id(&self) -> u8 {
+        return 2;
+    }
+    fn name(&self) -> &'static str {
+        return "rle";
+    }
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut runs = vec![];
+        for byte in data.iter() {
+            match runs.len() {
+                n if n >= 2 && runs[n - 1] == *byte && runs[n - 2] < 255 => runs[n - 2] += 1,
+                _ => runs.extend_from_slice(&[1, *byte]),
+            }
+        }
+        return runs;
+    }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, EntropyError> {
+        if !data.len().is_multiple_of(2) {
+            return Err(EntropyError::Corrupt);
+        }
+        return Ok(data.chunks(2).flat_map(|run| std::iter::repeat_n(run[1], run[0] as usize)).collect());
+    }
+}
+
+pub fn codec_for_id(id: u8) -> Option<&'static dyn EntropyCodec> {
+    return match id {
+        0 => Some(&Deflate),
+        1 => Some(&Stored),
+        2 => Some(&Rle),
+        #[cfg(feature = "zstd")]
+        3 => Some(&Zstd(CompressionLevel::Default)),
+        _ => None,
+    }
+}
+
+pub fn encode(codec: &dyn EntropyCodec, payload: &[u8]) -> Vec<u8> {
+    return [&[codec.id()][..], &codec.encode(payload)[..]].concat();
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<u8>, EntropyError> {
+    let (id, body) = bytes.split_first().ok_or(EntropyError::Empty)?;
+    let codec = codec_for_id(*id).ok_or(EntropyError::UnknownCodec(*id))?;
+    return codec.decode(body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Vec<u8> {
+        return (0..4096u32).map(|i| ((i / 7) % 13) as u8).collect();
+    }
+
+    #[test]
+    fn round_trip_through_each_codec() {
+        let codecs: [&dyn EntropyCodec; 3] = [&Deflate, &Stored, &Rle];
+        for codec in codecs.iter() {
+            let encoded = codec.encode(&payload());
+            assert_eq!(codec.decode(&encoded), Ok(payload()));
+            assert_eq!(decode(&encode(*codec, &payload())), Ok(payload()));
+        }
+    }
+
+    #[test]
+    fn codec_id_selects_decoder() {
+        let deflated = encode(&Deflate, &payload());
+        let stored = encode(&Stored, &payload());
+        assert_eq!(deflated[0], 0);
+        assert_eq!(stored[0], 1);
+        assert!(deflated.len() < stored.len());
+        assert_eq!(decode(&deflated), decode(&stored));
+    }
+
+    #[test]
+    fn best_level_is_no_larger_than_fast() {
+        let index: Vec<u8> = (0..8192u32).map(|i| if i % 97 < 90 { 0 } else { (i % 251) as u8 }).collect();
+        let fast = encode(&DeflateAt(CompressionLevel::Fast), &index);
+        let best = encode(&DeflateAt(CompressionLevel::Best), &index);
+        assert!(best.len() <= fast.len());
+        assert_eq!(decode(&fast), Ok(index.clone()));
+        assert_eq!(decode(&best), Ok(index));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_backend_is_picked_from_the_codec_id() {
+        let zstd = encode(&*EntropyBackend::Zstd.codec(CompressionLevel::Default), &payload());
+        assert_eq!(zstd[0], 3);
+        assert_eq!(decode(&zstd), Ok(payload()));
+        assert_eq!(codec_for_id(3).map(|codec| codec.name()), Some("zstd"));
+    }
+
+    #[test]
+    fn rle_collapses_long_runs() {
+        let runs = [vec![7u8; 600], vec![1, 2, 2]].concat();
+        let encoded = Rle.encode(&runs);
+        assert_eq!(encoded, vec![255, 7, 255, 7, 90, 7, 1, 1, 2, 2]);
+        assert_eq!(Rle.decode(&encoded), Ok(runs));
+        assert_eq!(Rle.decode(&[3]), Err(EntropyError::Corrupt));
+    }
+
+    #[test]
+    fn unknown_codec_is_rejected() {
+        assert_eq!(decode(&[]), Err(EntropyError::Empty));
+        assert_eq!(decode(&[9, 1, 2]), Err(EntropyError::UnknownCodec(9)));
+    }
+}
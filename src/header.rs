@@ -0,0 +1,274 @@
+use crate::compressor::ColorSpace;
+use crate::entropy;
+use crate::serialize::DecodeError;
+
+pub const MAGIC: [u8; 4] = *b"SKIQ";
+pub const VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 52;
+
+pub const FLAG_PLANAR_LEAVES: u32 = 1;
+pub const FLAG_VLC_RESIDUALS: u32 = 2;
+pub const FLAG_PALETTE: u32 = 4;
+pub const FLAG_EXIF: u32 = 8;
+pub const FLAG_ICC: u32 = 16;
+pub const FLAG_RAW_CHANNELS: [u32; 3] = [32, 64, 128];
+pub const FLAG_SINGLE_STREAM: u32 = 256;
+pub const FLAG_DITHER: u32 = 512;
+pub const FLAG_CHANNEL_CODECS: u32 = 1024;
+pub const FLAG_GRAYSCALE: u32 = 2048;
+pub const FLAG_BIT_PACKED: u32 = 4096;
+pub const FLAG_RLE_INDEX: [u32; 3] = [8192, 16384, 32768];
+pub const FLAG_HALF_CHROMA: u32 = 65536;
+pub const FLAG_WIDE_SAMPLES: u32 = 131072;
+pub const FLAG_CHECKSUM: u32 = 262144;
+pub const FLAG_HUFFMAN: [u32; 3] = [524288, 1048576, 2097152];
+pub const FLAG_SIBLING_DELTAS: u32 = 4194304;
+
+/// Fixed-size little-endian header at the front of every `.ski` payload:
+///
+/// | bytes | field                                   |
+/// |-------|-----------------------------------------|
+/// | 4     | magic `SKIQ`                            |
+/// | 1     | version                                 |
+/// | 1     | color space id                          |
+/// | 4     | flags (`FLAG_*` bits)                   |
+/// | 4     | rank (side of the square tree)          |
+/// | 4 + 4 | width, height of the visible image      |
+/// | 3     | luma, cb, cr cutoffs                    |
+/// | 3     | luma, cb, cr prediction (128 = none)    |
+/// | 3 * 4 | byte length of each channel's index     |
+/// | 3 * 4 | byte length of each channel's leaf data |
+///
+/// With `FLAG_PALETTE` set, a palette (count - 1, then RGB triples) comes
+/// next, then the EXIF and ICC blobs (u32 length, then bytes) when their
+/// flags are set, then the dither mode and u32 seed with `FLAG_DITHER`, then
+/// one entropy codec id per channel with `FLAG_CHANNEL_CODECS`. The six
+/// sections follow in the same order as the lengths, each coded with its
+/// channel's codec when one is recorded. `FLAG_GRAYSCALE` leaves both chroma
+/// channels' sections empty. With `FLAG_BIT_PACKED` each data section starts
+/// with its lowest value, a bit width and a u32 value count, and holds every
+/// value as an offset of that width. `FLAG_RLE_INDEX` marks a channel whose
+/// index holds varint run lengths, and `FLAG_HALF_CHROMA` one whose chroma
+/// trees have half the rank. With `FLAG_SINGLE_STREAM` the index
+/// lengths are zero and each data section holds that channel's index bits and
+/// leaf values interleaved. `FLAG_WIDE_SAMPLES` records a bit depth of 16:
+/// every leaf value takes two little-endian bytes, and such files are read by
+/// `WideCompressor` rather than `ImgCompressor`. With `FLAG_CHECKSUM` the
+/// file ends in a u32 CRC-32 of every byte before it. `FLAG_HUFFMAN` marks a
+/// channel whose data section holds a canonical Huffman table (u16 count,
+/// then symbol and code length pairs), a u32 value count and the code bits,
+/// which decode to the section as it would otherwise be stored. With
+/// `FLAG_SIBLING_DELTAS` leaf values are zig-zagged deltas from their parent's
+/// average, and every split branch adds a byte for its own average.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileHeader {
+    pub magic: [u8; 4],
+    pub version: u8,
+    pub color_space: u8,
+    pub flags: u32,
+    pub rank: u32,
+    pub width: u32,
+    pub height: u32,
+    pub cutoffs: (u8, u8, u8),
+    pub prediction: (u8, u8, u8),
+    pub index_lens: [u32; 3],
+    pub data_lens: [u32; 3],
+}
+
+impl FileHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(&self.magic);
+        bytes.push(self.version);
+        bytes.push(self.color_space);
+        bytes.extend_from_slice(&self.flags.to_le_bytes());
+        bytes.extend_from_slice(&self.rank.to_le_bytes());
+        bytes.extend_from_slice(&self.width.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(&[self.cutoffs.0, self.cutoffs.1, self.cutoffs.2]);
+        bytes.extend_from_slice(&[self.prediction.0, self.prediction.1, self.prediction.2]);
+        for len in self.index_lens.iter().chain(self.data_lens.iter()) {
+            bytes.extend_from_slice(&len.to_le_bytes());
+        }
+        return bytes;
+    }
+
+    pub fn body_len(&self) -> usize {
+        return self.index_lens.iter().chain(self.data_lens.iter()).map(|len| *len as usize).sum();
+    }
+}
+
+pub fn blob_bytes(blob: &[u8]) -> Vec<u8> {
+    return [&(blob.len() as u32).to_le_bytes()[..], blob].concat();
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        self.at += n;
+        return &self.bytes[self.at-n..self.at];
+    }
+    fn u8(&mut self) -> u8 {
+        return self.take(1)[0];
+    }
+    fn u8x3(&mut self) -> (u8, u8, u8) {
+        let b = self.take(3);
+        return (b[0], b[1], b[2]);
+    }
+    fn u32(&mut self) -> u32 {
+        let b = self.take(4);
+        return u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    }
+}
+
+pub fn parse_header(bytes: &[u8]) -> Result<(FileHeader, &[u8]), DecodeError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let (head, body) = bytes.split_at(HEADER_LEN);
+    let mut cursor = Cursor { bytes: head, at: 0 };
+    let mut magic = [0u8; 4];
+    magic.copy_from_slice(cursor.take(4));
+    if magic != MAGIC {
+        return Err(DecodeError::BadMagic(magic));
+    }
+    let version = cursor.u8();
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let header = FileHeader {
+        magic,
+        version,
+        color_space: cursor.u8(),
+        flags: cursor.u32(),
+        rank: cursor.u32(),
+        width: cursor.u32(),
+        height: cursor.u32(),
+        cutoffs: cursor.u8x3(),
+        prediction: cursor.u8x3(),
+        index_lens: [cursor.u32(), cursor.u32(), cursor.u32()],
+        data_lens: [cursor.u32(), cursor.u32(), cursor.u32()],
+    };
+    let min_rank = if header.flags & FLAG_HALF_CHROMA != 0 { 4 } else { 2 };
+    let rank = header.width.max(header.height).checked_next_power_of_two().map(|rank| rank.max(min_rank));
+    if rank != Some(header.rank) {
+        return Err(DecodeError::InvalidDimensions { rank: header.rank, width: header.width, height: header.height });
+    }
+    return Ok((header, body));
+}
+
+pub fn inspect(file: &[u8]) -> Result<String, DecodeError> {
+    let codec = file.first().and_then(|id| entropy::codec_for_id(*id));
+    let payload = entropy::decode(file)?;
+    let (header, body) = parse_header(&payload)?;
+    let color_space = ColorSpace::from_id(header.color_space)
+        .map_or(format!("unknown ({})", header.color_space), |space| format!("{:?}", space));
+    let mut lines = vec![
+        format!("codec        {}", codec.map_or("unknown", |codec| codec.name())),
+        format!("dimensions   {} x {}", header.width, header.height),
+        format!("rank         {}", header.rank),
+        format!("color space  {}", color_space),
+        format!("version      {}", header.version),
+        format!("flags        {:#034b}", header.flags),
+        format!("cutoffs      {} {} {}", header.cutoffs.0, header.cutoffs.1, header.cutoffs.2),
+        format!("prediction   {} {} {}", header.prediction.0, header.prediction.1, header.prediction.2),
+        format!("payload      {} bytes ({} header + {} body)", payload.len(), HEADER_LEN, body.len()),
+        "channel      index    data".to_string(),
+    ];
+    for (i, name) in ["luma", "cb", "cr"].iter().enumerate() {
+        lines.push(format!("{:<12} {:<8} {}", name, header.index_lens[i], header.data_lens[i]));
+    }
+    return Ok(lines.join("\n"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> FileHeader {
+        return FileHeader {
+            magic: MAGIC,
+            version: VERSION,
+            color_space: 1,
+            flags: FLAG_PLANAR_LEAVES,
+            rank: 512,
+            width: 500,
+            height: 500,
+            cutoffs: (2, 4, 8),
+            prediction: (128, 100, 140),
+            index_lens: [10, 20, 30],
+            data_lens: [1000, 2000, 70000],
+        };
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let bytes = [&header().to_bytes()[..], &[7, 7]].concat();
+        assert_eq!(bytes.len(), HEADER_LEN + 2);
+        let (parsed, body) = parse_header(&bytes).unwrap();
+        assert_eq!(parsed, header());
+        assert_eq!(body, &[7, 7]);
+    }
+
+    #[test]
+    fn rejects_foreign_magic() {
+        let mut bytes = header().to_bytes();
+        bytes[0] = b'P';
+        assert_eq!(parse_header(&bytes), Err(DecodeError::BadMagic(*b"PKIQ")));
+        assert_eq!(parse_header(&bytes[..10]), Err(DecodeError::UnexpectedEof));
+    }
+
+    #[test]
+    fn rejects_ranks_that_cannot_hold_the_image() {
+        let bytes = crate::testutil::compressor(&crate::testutil::sample_image(32)).to_file((4, 4, 4));
+        let patched = |offset: usize, value: u32| -> Vec<u8> {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            return bytes;
+        };
+        let invalid = |rank: u32, width: u32, height: u32| Err(DecodeError::InvalidDimensions { rank, width, height });
+        let cases = [
+            (patched(10, 24), invalid(24, 32, 32)),
+            (patched(14, 200), invalid(32, 200, 32)),
+            (patched(18, 33), invalid(32, 32, 33)),
+            (patched(10, 1024), invalid(1024, 32, 32)),
+            (patched(10, 1 << 31), invalid(1 << 31, 32, 32)),
+        ];
+        for (bytes, expected) in cases.iter() {
+            assert_eq!(parse_header(bytes).map(|_| ()), *expected);
+            assert_eq!(crate::compressor::decode_lenient(bytes).map(|_| ()), *expected);
+            assert_eq!(crate::compressor::ImgCompressor::from_file(bytes).map(|_| ()), *expected);
+        }
+        let mut half = header();
+        half.flags = FLAG_HALF_CHROMA;
+        (half.rank, half.width, half.height) = (2, 2, 2);
+        assert_eq!(parse_header(&half.to_bytes()).map(|_| ()), invalid(2, 2, 2));
+    }
+
+    #[test]
+    fn to_file_writes_a_self_describing_header() {
+        let compressor = crate::testutil::compressor(&crate::testutil::sample_image(16));
+        let bytes = compressor.to_file((1, 2, 3));
+        let (parsed, body) = parse_header(&bytes).unwrap();
+        assert_eq!((parsed.magic, parsed.version, parsed.rank), (MAGIC, VERSION, 16));
+        assert_eq!(parsed.cutoffs, (1, 2, 3));
+        assert_eq!(parsed.body_len() + 4, body.len());
+    }
+
+    #[test]
+    fn inspect_reports_dimensions_and_cutoffs() {
+        let compressor = crate::testutil::compressor(&crate::testutil::sample_image(20));
+        let file = entropy::encode(&entropy::Deflate, &compressor.to_file((3, 5, 7)));
+        let report = inspect(&file).unwrap();
+        assert!(report.contains("codec        deflate"));
+        assert!(report.contains("dimensions   20 x 20"));
+        assert!(report.contains("rank         32"));
+        assert!(report.contains("cutoffs      3 5 7"));
+        assert!(report.contains("color space  YCbCr"));
+        assert_eq!(inspect(&[0, 1, 2]).map(|_| ()), Err(DecodeError::Entropy(entropy::EntropyError::Corrupt)));
+    }
+}
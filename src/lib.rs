@@ -0,0 +1,25 @@
+#![allow(clippy::needless_return)]
+
+pub mod anim;
+pub mod checksum;
+pub mod compressor;
+pub mod dither;
+pub mod entropy;
+pub mod header;
+pub mod lut;
+pub mod mask;
+pub mod metrics;
+pub mod palette;
+pub mod quadtree;
+pub mod serialize;
+pub mod tiles;
+pub mod wide;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(test)]
+mod testutil;
+
+pub use compressor::{ CompressConfig, Cutoff, ImgCompressor };
+pub use entropy::CompressionLevel;
+pub use quadtree::Quadtree;
+pub use serialize::{ build_leaf_data, build_leaf_index, read_leaf_tree, DecodeError };
@@ -0,0 +1,63 @@
+pub struct Lut3d {
+    size: usize,
+    table: Vec<[f32; 3]>,
+}
+
+impl Lut3d {
+    pub fn from_fn<F: Fn([f32; 3]) -> [f32; 3]>(size: usize, f: F) -> Lut3d {
+        assert!(size >= 2);
+        let step = 255f32 / (size - 1) as f32;
+        let table = (0..size * size * size).map(|i| {
+            let (r, g, b) = (i % size, i / size % size, i / (size * size));
+            return f([r as f32 * step, g as f32 * step, b as f32 * step]);
+        }).collect();
+        return Lut3d { size, table };
+    }
+
+    pub fn identity(size: usize) -> Lut3d {
+        return Lut3d::from_fn(size, |rgb| rgb);
+    }
+
+    fn at(&self, r: usize, g: usize, b: usize) -> [f32; 3] {
+        return self.table[r + g * self.size + b * self.size * self.size];
+    }
+
+    pub fn apply(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let scale = (self.size - 1) as f32 / 255f32;
+        let mut base = [0usize; 3];
+        let mut frac = [0f32; 3];
+        for i in 0..3 {
+            let position = rgb[i] as f32 * scale;
+            base[i] = (position.floor() as usize).min(self.size - 2);
+            frac[i] = position - base[i] as f32;
+        }
+        let mut output = [0f32; 3];
+        for corner in 0..8 {
+            let (dr, dg, db) = (corner & 1, corner >> 1 & 1, corner >> 2 & 1);
+            let weight = [dr, dg, db].iter().enumerate()
+                .map(|(i, d)| if *d == 1 { frac[i] } else { 1f32 - frac[i] })
+                .product::<f32>();
+            let value = self.at(base[0] + dr, base[1] + dg, base[2] + db);
+            for i in 0..3 {
+                output[i] += weight * value[i];
+            }
+        }
+        return [
+            output[0].round().clamp(0f32, 255f32) as u8,
+            output[1].round().clamp(0f32, 255f32) as u8,
+            output[2].round().clamp(0f32, 255f32) as u8,
+        ];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trilinear_lookup_between_grid_points() {
+        let invert = Lut3d::from_fn(2, |[r, g, b]| [255f32 - r, 255f32 - g, 255f32 - b]);
+        assert_eq!(invert.apply([0, 100, 255]), [255, 155, 0]);
+        assert_eq!(Lut3d::identity(17).apply([3, 128, 250]), [3, 128, 250]);
+    }
+}
@@ -1,38 +1,196 @@
-mod compressor;
-mod quadtree;
-mod serialize;
-use std::{ env, fs, os::unix::fs::MetadataExt };
-use deflate::deflate_bytes;
+#![allow(clippy::needless_return)]
 
-use crate::compressor::ImgCompressor;
+#[cfg(test)]
+mod testutil;
+#[cfg(test)]
+use quad_compress::compressor;
+use std::{ env, fs, path::Path, process };
+
+use image::{ DynamicImage, RgbImage };
+use quad_compress::{ entropy, header, CompressionLevel, Cutoff, ImgCompressor };
+use quad_compress::entropy::EntropyBackend;
 
 fn main() {
-    let compression = parse_arguments(env::args().collect());
-    println!(" raw     png     quad    deflat  (kB)");
-    println!("------- ------- ------- -------");
-    test_case(compression, "lena");
-    test_case(compression, "lichtenstein");
-    test_case(compression, "mandelbrot");
-}
-
-fn test_case(compression: (u8, u8, u8), name: &str) {
-    let input_path = format!("./samples/{}.png", name);
-    let compressor = ImgCompressor::new(image::open(&input_path));
-    let outfile = format!("./output/{}.png", name);
+    let args: Vec<String> = env::args().collect();
+    let level = match parse_level(&args) {
+        Ok(level) => level,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        },
+    };
+    let backend = match parse_backend(&args) {
+        Ok(backend) => backend,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        },
+    };
+    let args: Vec<String> = args.into_iter()
+        .filter(|arg| !arg.starts_with("--level=") && !arg.starts_with("--backend="))
+        .collect();
+    if args.len() == 3 && args[1] == "inspect" {
+        return inspect(&args[2]);
+    }
+    if args.len() == 4 && args[1] == "decompress" {
+        match decompress_file(&args[2], &args[3]) {
+            Ok(summary) => println!("{}", summary),
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(1);
+            },
+        }
+        return;
+    }
+    if (args.len() == 3 || args.len() == 6) && args[1].parse::<u8>().is_err() && !args[1].starts_with("--") {
+        let result = parse_cutoffs(&args[3..]).and_then(|cutoffs| compress_file(&args[1], &args[2], cutoffs, backend, level));
+        if let Err(error) = result {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+        return;
+    }
+    let verify = args.iter().any(|arg| arg == "--verify");
+    let compression = parse_arguments(args.into_iter().filter(|arg| arg != "--verify").collect());
+    println!(" raw     png     quad    ski     zstd    (kB)  psnr (dB)");
+    println!("------- ------- ------- ------- ------- ---------");
+    for (name, input_path) in sample_entries(env::var("QUAD_SAMPLES").ok()) {
+        test_case(compression, backend, level, &name, &input_path, "./output", verify);
+    }
+}
+
+fn sample_entries(config: Option<String>) -> Vec<(String, String)> {
+    let config = match config {
+        Some(config) if !config.trim().is_empty() => config,
+        _ => return ["lena", "lichtenstein", "mandelbrot"].iter()
+            .map(|name| (name.to_string(), format!("./samples/{}.png", name)))
+            .collect(),
+    };
+    return config.split(',').map(str::trim).filter(|entry| !entry.is_empty()).map(|entry| {
+        return match entry.split_once('=') {
+            Some((name, path)) => (name.to_string(), path.to_string()),
+            None => {
+                let stem = Path::new(entry).file_stem().map_or(entry.into(), |stem| stem.to_string_lossy());
+                (stem.to_string(), entry.to_string())
+            },
+        }
+    }).collect();
+}
+
+fn psnr_column(original: &RgbImage, compressor: &ImgCompressor, compression: Cutoff) -> String {
+    let psnr = compressor.psnr_for(original, compression);
+    return if psnr.is_finite() { format!("{:>6.2}", psnr) } else { format!("{:>6}", "inf") };
+}
+
+fn test_case(compression: (u8, u8, u8), backend: EntropyBackend, level: CompressionLevel, name: &str, input_path: &str, output_dir: &str, verify: bool) -> bool {
+    let original = match image::open(input_path) {
+        Ok(img) => img.to_rgb8(),
+        Err(_) => return false,
+    };
+    let compressor = match ImgCompressor::new(DynamicImage::ImageRgb8(original.clone())) {
+        Ok(compressor) => compressor,
+        Err(error) => {
+            println!("cannot compress {}: {:?}", name, error);
+            return false;
+        },
+    };
+    let outfile = format!("{}/{}.png", output_dir, name);
     let png_result = compressor.to_image(compression).save(outfile);
-    if !png_result.is_ok() { return; }
+    if png_result.is_err() { return false; }
     let serialized_bytes = compressor.to_file(compression);
-    let file_bytes = deflate_bytes(&serialized_bytes);
-    let size_input = fs::metadata(&input_path).unwrap().size() / 1024;
-    let size_raw = 512*512*3/1024;
+    let file_bytes = entropy::encode(&*backend.codec(level), &serialized_bytes);
+    let size_input = match fs::metadata(input_path) {
+        Ok(metadata) => (metadata.len() / 1024).to_string(),
+        Err(error) => {
+            eprintln!("cannot read size of {}: {}", input_path, error);
+            "-".to_string()
+        },
+    };
+    let size_raw = compressor.width * compressor.height * 3 / 1024;
     let size_a = serialized_bytes.len() / 1024;
     let size_b = file_bytes.len() / 1024;
-    let ski_result = fs::write(format!("./output/{}.ski", name), file_bytes);
+    let size_zstd = zstd_size(&serialized_bytes, level).map_or("-".to_string(), |size| (size / 1024).to_string());
+    let ski_result = fs::write(format!("{}/{}.ski", output_dir, name), file_bytes);
     if ski_result.is_ok() {
-        println!("{:>4}    {:>4}    {:>4}    {:>4}    {}", size_raw, size_input, size_a, size_b, name);
+        let psnr = psnr_column(&original, &compressor, compression);
+        println!("{:>4}    {:>4}    {:>4}    {:>4}    {:>4}    {}    {}", size_raw, size_input, size_a, size_b, size_zstd, psnr, name);
     } else {
         println!("failed {}", name);
     };
+    if verify && !compressor.verify_round_trip(compression) {
+        println!("round trip mismatch {}", name);
+        return false;
+    }
+    return ski_result.is_ok();
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_size(serialized_bytes: &[u8], level: CompressionLevel) -> Option<usize> {
+    return Some(entropy::encode(&*EntropyBackend::Zstd.codec(level), serialized_bytes).len());
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_size(_serialized_bytes: &[u8], _level: CompressionLevel) -> Option<usize> {
+    return None;
+}
+
+fn parse_cutoffs(args: &[String]) -> Result<Cutoff, String> {
+    if args.is_empty() {
+        return Ok((2, 2, 2));
+    }
+    let parse = |arg: &String| arg.parse::<u8>().map_err(|_| format!("cutoff {} is not a number from 0 to 255", arg));
+    return Ok((parse(&args[0])?, parse(&args[1])?, parse(&args[2])?));
+}
+
+fn parse_level(args: &[String]) -> Result<CompressionLevel, String> {
+    return match args.iter().find_map(|arg| arg.strip_prefix("--level=")) {
+        None | Some("default") => Ok(CompressionLevel::Default),
+        Some("fast") => Ok(CompressionLevel::Fast),
+        Some("best") => Ok(CompressionLevel::Best),
+        Some(level) => Err(format!("level {} is not one of fast, default, best", level)),
+    };
+}
+
+fn parse_backend(args: &[String]) -> Result<EntropyBackend, String> {
+    return match args.iter().find_map(|arg| arg.strip_prefix("--backend=")) {
+        None | Some("deflate") => Ok(EntropyBackend::Deflate),
+        #[cfg(feature = "zstd")]
+        Some("zstd") => Ok(EntropyBackend::Zstd),
+        Some(backend) => Err(format!("backend {} is not available", backend)),
+    };
+}
+
+fn compress_file(input_path: &str, output_path: &str, cutoffs: Cutoff, backend: EntropyBackend, level: CompressionLevel) -> Result<(), String> {
+    if !Path::new(input_path).is_file() {
+        return Err(format!("input {} does not exist", input_path));
+    }
+    let img = image::open(input_path).map_err(|e| format!("cannot read {}: {}", input_path, e))?;
+    let compressor = ImgCompressor::new(img).map_err(|e| format!("cannot compress {}: {:?}", input_path, e))?;
+    let file_bytes = entropy::encode(&*backend.codec(level), &compressor.to_file(cutoffs));
+    fs::write(output_path, &file_bytes).map_err(|e| format!("cannot write {}: {}", output_path, e))?;
+    println!("{} -> {} ({} bytes)", input_path, output_path, file_bytes.len());
+    return Ok(());
+}
+
+fn decompress_file(input_path: &str, output_path: &str) -> Result<String, String> {
+    let file_bytes = fs::read(input_path).map_err(|e| format!("cannot read {}: {}", input_path, e))?;
+    let decoded = entropy::decode(&file_bytes).map_err(|e| format!("cannot decode {}: {:?}", input_path, e))
+        .and_then(|payload| ImgCompressor::from_file(&payload).map_err(|e| format!("cannot decode {}: {:?}", input_path, e)))?;
+    decoded.to_image((0, 0, 0)).save(output_path).map_err(|e| format!("cannot write {}: {}", output_path, e))?;
+    let raw = decoded.width as usize * decoded.height as usize * 3;
+    return Ok(format!("{} x {}, ratio {:.2}:1", decoded.width, decoded.height, raw as f64 / file_bytes.len() as f64));
+}
+
+fn inspect(path: &str) {
+    let report = fs::read(path).map_err(|e| e.to_string())
+        .and_then(|bytes| header::inspect(&bytes).map_err(|e| format!("{:?}", e)));
+    match report {
+        Ok(report) => println!("{}", report),
+        Err(error) => {
+            eprintln!("cannot inspect {}: {}", path, error);
+            process::exit(1);
+        },
+    }
 }
 
 fn parse_arguments(args: Vec<String>) -> (u8, u8, u8) {
@@ -45,3 +203,72 @@ fn parse_arguments(args: Vec<String>) -> (u8, u8, u8) {
     }
     return (2, 2, 2);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_samples_are_the_bundled_three() {
+        let names: Vec<String> = sample_entries(None).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["lena", "lichtenstein", "mandelbrot"]);
+        assert_eq!(sample_entries(Some(" ".to_string())).len(), 3);
+    }
+
+    #[test]
+    fn override_list_is_processed() {
+        let dir = env::temp_dir().join(format!("quad-compress-samples-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.png");
+        let second = dir.join("second.png");
+        testutil::sample_image(16).save(&first).unwrap();
+        testutil::sample_image(32).save(&second).unwrap();
+        let config = format!("one={}, {}", first.display(), second.display());
+        let entries = sample_entries(Some(config));
+        assert_eq!(entries[0].0, "one");
+        assert_eq!(entries[1].0, "second");
+        for (name, path) in entries.iter() {
+            assert!(test_case((2, 2, 2), EntropyBackend::Deflate, CompressionLevel::Default, name, path, dir.to_str().unwrap(), true));
+            assert!(dir.join(format!("{}.ski", name)).exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn psnr_drops_with_aggressive_cutoff() {
+        let original = testutil::sample_image(64);
+        let compressor = testutil::compressor(&original);
+        let near_lossless: f64 = psnr_column(&original, &compressor, (1, 1, 1)).trim().parse().unwrap();
+        let aggressive: f64 = psnr_column(&original, &compressor, (60, 60, 60)).trim().parse().unwrap();
+        assert!(near_lossless > 30f64);
+        assert!(aggressive < near_lossless);
+    }
+
+    #[test]
+    fn compresses_and_decompresses_given_paths() {
+        let dir = env::temp_dir().join(format!("quad-compress-cli-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.png");
+        let output = dir.join("output.ski");
+        testutil::sample_image(16).save(&input).unwrap();
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+        assert_eq!(compress_file(input, output, (2, 2, 2), EntropyBackend::Deflate, CompressionLevel::Best), Ok(()));
+        let decoded = ImgCompressor::from_file(&entropy::decode(&fs::read(output).unwrap()).unwrap()).unwrap();
+        assert_eq!((decoded.width, decoded.height), (16, 16));
+        assert!(compress_file("missing.png", output, (2, 2, 2), EntropyBackend::Deflate, CompressionLevel::Default).is_err());
+        let unwritable = dir.join("missing").join("output.ski");
+        assert!(compress_file(input, unwritable.to_str().unwrap(), (2, 2, 2), EntropyBackend::Deflate, CompressionLevel::Default).is_err());
+        let png = dir.join("decoded.png");
+        let summary = decompress_file(output, png.to_str().unwrap()).unwrap();
+        assert!(summary.starts_with("16 x 16, ratio "));
+        assert_eq!(image::image_dimensions(&png).unwrap(), (16, 16));
+        assert!(decompress_file(input, png.to_str().unwrap()).is_err());
+        assert_eq!(parse_cutoffs(&["4".to_string(), "8".to_string(), "8".to_string()]), Ok((4, 8, 8)));
+        assert!(parse_cutoffs(&["4".to_string(), "x".to_string(), "8".to_string()]).is_err());
+        assert_eq!(parse_level(&["prog".to_string(), "--level=fast".to_string()]), Ok(CompressionLevel::Fast));
+        assert!(parse_level(&["--level=max".to_string()]).is_err());
+        assert_eq!(parse_backend(&["--backend=deflate".to_string()]), Ok(EntropyBackend::Deflate));
+        assert!(parse_backend(&["--backend=lzma".to_string()]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use crate::quadtree::{BitmapData, CutoffMap, Point, Quadtree, Rect};
+
+pub fn sobel(plane: &[u8], rank: usize) -> Vec<u8> {
+    let max = rank as isize - 1;
+    let at = |x: isize, y: isize| -> i32 {
+        let cx = x.max(0).min(max) as usize;
+        let cy = y.max(0).min(max) as usize;
+        return plane[cx + cy*rank] as i32;
+    };
+    return (0..rank*rank).map(|i| {
+        let x = (i % rank) as isize;
+        let y = (i / rank) as isize;
+        let gx = at(x+1, y-1) + 2*at(x+1, y) + at(x+1, y+1)
+            - at(x-1, y-1) - 2*at(x-1, y) - at(x-1, y+1);
+        let gy = at(x-1, y+1) + 2*at(x, y+1) + at(x+1, y+1)
+            - at(x-1, y-1) - 2*at(x, y-1) - at(x+1, y-1);
+        return ((gx*gx + gy*gy) as f32).sqrt().min(255f32) as u8;
+    }).collect();
+}
+
+pub struct ImportanceMask {
+    importance: Box<Quadtree>,
+}
+
+impl ImportanceMask {
+    pub fn new(importance: BitmapData) -> ImportanceMask {
+        return ImportanceMask { importance: Quadtree::new(importance) };
+    }
+
+    pub fn from_edges(plane: BitmapData) -> ImportanceMask {
+        let rank = (plane.len() as f32).sqrt() as usize;
+        return ImportanceMask::new(&sobel(plane, rank));
+    }
+
+    pub fn peak(&self, offset: Point, size: usize) -> u8 {
+        return self.importance.node_at(offset, size).high();
+    }
+
+    pub fn with_cutoff(&self, base: u8) -> MaskedCutoff<'_> {
+        return MaskedCutoff { mask: self, base };
+    }
+}
+
+pub struct MaskedCutoff<'a> {
+    mask: &'a ImportanceMask,
+    base: u8,
+}
+
+impl<'a> CutoffMap for MaskedCutoff<'a> {
+    fn cutoff_at(&self, offset: Point, size: usize) -> u8 {
+        let peak = self.mask.peak(offset, size) as u32;
+        return (self.base as u32 * (255 - peak) / 255) as u8;
+    }
+}
+
+/// Collapses a region only when its reconstruction stays within `max_mse` of
+/// the original plane; every other region is subdivided down to its pixels.
+pub struct MseBound {
+    collapsed: HashSet<(Point, usize)>,
+}
+
+impl MseBound {
+    pub fn new(tree: &Quadtree, plane: &[u8], max_mse: f64) -> MseBound {
+        let mut bound = MseBound { collapsed: HashSet::new() };
+        bound.visit(tree, plane, tree.size(), (0, 0), max_mse);
+        return bound;
+    }
+
+    fn visit(&mut self, node: &Quadtree, plane: &[u8], rank: usize, (x, y): Point, max_mse: f64) {
+        let size = node.size();
+        if node.high() - node.low() < 255 && node.collapse_error(plane, rank, (x, y)) <= max_mse {
+            self.collapsed.insert(((x, y), size));
+        } else if let Quadtree::Branch(a, b, c, d, _, _) = node {
+            let s = size / 2;
+            self.visit(a, plane, rank, (x, y), max_mse);
+            self.visit(b, plane, rank, (x+s, y), max_mse);
+            self.visit(c, plane, rank, (x, y+s), max_mse);
+            self.visit(d, plane, rank, (x+s, y+s), max_mse);
+        }
+    }
+}
+
+impl CutoffMap for MseBound {
+    fn cutoff_at(&self, offset: Point, size: usize) -> u8 {
+        return if self.collapsed.contains(&(offset, size)) { 255 } else { 0 };
+    }
+}
+
+/// Uses `inside` for every node that overlaps `rect` and `outside` for the
+/// rest, keeping detail in a region of interest.
+pub struct RegionOfInterest {
+    pub rect: Rect,
+    pub inside: u8,
+    pub outside: u8,
+}
+
+impl CutoffMap for RegionOfInterest {
+    fn cutoff_at(&self, offset: Point, size: usize) -> u8 {
+        return if self.rect.overlaps(offset, size) { self.inside } else { self.outside };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::Local;
+    use bitvec::slice::BitSlice;
+    use bitvec::vec::BitVec;
+    use crate::serialize::{build_leaf_index, LeafCoding};
+    use super::*;
+
+    fn subtree_end(bits: &BitSlice<Local, u8>, pos: usize) -> usize {
+        let mut end = pos + 1;
+        if bits[pos] {
+            for _ in 0..4 {
+                end = subtree_end(bits, end);
+            }
+        }
+        return end;
+    }
+
+    fn branches_in_top_quadrants(plane: &Vec<u8>, cutoff: &dyn CutoffMap) -> (usize, usize) {
+        let mut index: BitVec<Local, u8> = BitVec::new();
+        build_leaf_index(&Quadtree::new(plane), &mut index, cutoff, LeafCoding::default());
+        let a_end = subtree_end(&index, 1);
+        let b_end = subtree_end(&index, a_end);
+        let a = index[1..a_end].iter().filter(|bit| **bit).count();
+        let b = index[a_end..b_end].iter().filter(|bit| **bit).count();
+        return (a, b);
+    }
+
+    fn textured_plane_with_band() -> Vec<u8> {
+        return (0..32*32).map(|i| {
+            let (x, y) = (i % 32, i / 32);
+            let texture = ((x * 7 + y * 13) % 5) as u8;
+            let band = if y < 16 && (8..12).contains(&x) { 120 } else { 0 };
+            return texture + band;
+        }).collect();
+    }
+
+    #[test]
+    fn sobel_is_zero_on_flat_plane() {
+        assert!(sobel(&[42u8; 64], 8).iter().all(|m| *m == 0));
+    }
+
+    #[test]
+    fn edges_subdivide_deeper_than_flat_regions() {
+        let plane = textured_plane_with_band();
+        let mask = ImportanceMask::from_edges(&plane);
+        let (edge_masked, flat_masked) = branches_in_top_quadrants(&plane, &mask.with_cutoff(10));
+        let (edge_uniform, _) = branches_in_top_quadrants(&plane, &10u8);
+        assert!(edge_masked > flat_masked);
+        assert!(edge_masked > edge_uniform);
+        assert_eq!(flat_masked, 0);
+    }
+
+    #[test]
+    fn region_of_interest_keeps_more_leaves() {
+        let plane = textured_plane_with_band();
+        let tree = Quadtree::new(&plane);
+        let rect = Rect { x: 16, y: 16, width: 16, height: 16 };
+        let roi = RegionOfInterest { rect, inside: 0, outside: 40 };
+        let count = |area: Rect| tree.leaves(&roi).filter(|leaf| area.contains((leaf.x, leaf.y))).count();
+        assert!(count(rect) > 4 * count(Rect { x: 0, y: 16, width: 16, height: 16 }));
+        assert!(rect.overlaps((8, 8), 16) && !rect.overlaps((0, 0), 16) && rect.contains((31, 31)));
+    }
+
+    #[test]
+    fn bounded_regions_stay_under_the_threshold() {
+        let plane: Vec<u8> = (0..32*32).map(|i| {
+            let (x, y) = (i % 32, i / 32);
+            return if x < 16 { ((x + y) % 2) as u8 } else { 100 + ((x * 7 + y * 13) % 5) as u8 * 10 };
+        }).collect();
+        let tree = Quadtree::new(&plane);
+        let errors = tree.region_errors(&plane, &MseBound::new(&tree, &plane, 2f64));
+        assert!(errors.iter().any(|(region, _)| region.size > 2));
+        assert!(errors.iter().all(|(_, mse)| *mse <= 2f64));
+        assert!(tree.region_errors(&plane, &50u8).iter().any(|(_, mse)| *mse > 2f64));
+    }
+}
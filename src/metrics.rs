@@ -0,0 +1,79 @@
+use image::{ DynamicImage, GrayImage, RgbImage };
+
+pub fn mse(original: &RgbImage, reconstructed: &RgbImage) -> f64 {
+    assert!(original.dimensions() == reconstructed.dimensions());
+    let squared: f64 = original.as_raw().iter().zip(reconstructed.as_raw().iter())
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum();
+    return squared / original.as_raw().len().max(1) as f64;
+}
+
+pub fn psnr(original: &RgbImage, reconstructed: &RgbImage) -> f64 {
+    let mse = mse(original, reconstructed);
+    if mse == 0f64 {
+        return f64::INFINITY;
+    }
+    return 10f64 * (255f64 * 255f64 / mse).log10();
+}
+
+const SSIM_WINDOW: u32 = 8;
+
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    assert!(a.dimensions() == b.dimensions());
+    let (width, height) = a.dimensions();
+    let c1 = (0.01f64 * 255f64).powi(2);
+    let c2 = (0.03f64 * 255f64).powi(2);
+    let mut total = 0f64;
+    let mut windows = 0;
+    for y0 in (0..height).step_by(SSIM_WINDOW as usize) {
+        for x0 in (0..width).step_by(SSIM_WINDOW as usize) {
+            let points: Vec<(f64, f64)> = (y0..(y0 + SSIM_WINDOW).min(height))
+                .flat_map(|y| (x0..(x0 + SSIM_WINDOW).min(width)).map(move |x| (x, y)))
+                .map(|(x, y)| (a.get_pixel(x, y)[0] as f64, b.get_pixel(x, y)[0] as f64))
+                .collect();
+            let n = points.len() as f64;
+            let mean_a = points.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_b = points.iter().map(|p| p.1).sum::<f64>() / n;
+            let var_a = points.iter().map(|p| (p.0 - mean_a).powi(2)).sum::<f64>() / n;
+            let var_b = points.iter().map(|p| (p.1 - mean_b).powi(2)).sum::<f64>() / n;
+            let covariance = points.iter().map(|p| (p.0 - mean_a) * (p.1 - mean_b)).sum::<f64>() / n;
+            total += (2f64 * mean_a * mean_b + c1) * (2f64 * covariance + c2)
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+            windows += 1;
+        }
+    }
+    return total / windows.max(1) as f64;
+}
+
+pub fn ssim_rgb(original: &RgbImage, reconstructed: &RgbImage) -> f64 {
+    let luma = |img: &RgbImage| DynamicImage::ImageRgb8(img.clone()).to_luma8();
+    return ssim(&luma(original), &luma(reconstructed));
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+    use super::*;
+
+    #[test]
+    fn psnr_of_known_error() {
+        let a = RgbImage::from_pixel(4, 4, Rgb([100, 100, 100]));
+        let b = RgbImage::from_pixel(4, 4, Rgb([110, 90, 100]));
+        assert_eq!(psnr(&a, &a), f64::INFINITY);
+        assert!((mse(&a, &b) - 200f64 / 3f64).abs() < 1e-9);
+        assert!((psnr(&a, &b) - 29.89).abs() < 0.01);
+    }
+
+    #[test]
+    fn ssim_drops_for_a_blurred_copy() {
+        let sharp = GrayImage::from_fn(30, 30, |x, y| image::Luma([if (x / 3 + y / 3) % 2 == 0 { 220 } else { 30 }]));
+        let blurred = GrayImage::from_fn(30, 30, |x, y| {
+            let sum: u32 = (0..3).map(|d| sharp.get_pixel((x + d).min(29), y)[0] as u32).sum();
+            return image::Luma([(sum / 3) as u8]);
+        });
+        assert!((ssim(&sharp, &sharp) - 1f64).abs() < 1e-9);
+        assert!(ssim(&sharp, &blurred) < 0.9);
+        let rgb = RgbImage::from_fn(30, 30, |x, y| Rgb([sharp.get_pixel(x, y)[0], 40, 90]));
+        assert!((ssim_rgb(&rgb, &rgb) - 1f64).abs() < 1e-9);
+    }
+}
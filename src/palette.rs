@@ -0,0 +1,88 @@
+use image::{ Rgb, RgbImage };
+use crate::serialize::DecodeError;
+
+pub type Color = [u8; 3];
+
+fn widest_channel(colors: &[Color]) -> (usize, u8) {
+    return (0..3).map(|channel| {
+        let low = colors.iter().map(|c| c[channel]).min().unwrap_or(0);
+        let high = colors.iter().map(|c| c[channel]).max().unwrap_or(0);
+        return (channel, high - low);
+    }).max_by_key(|(_, spread)| *spread).unwrap();
+}
+
+fn mean(colors: &[Color]) -> Color {
+    let mut sum = [0usize; 3];
+    for color in colors {
+        for channel in 0..3 {
+            sum[channel] += color[channel] as usize;
+        }
+    }
+    let n = colors.len().max(1);
+    return [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8];
+}
+
+pub fn median_cut(img: &RgbImage, colors: usize) -> Vec<Color> {
+    assert!((1..=256).contains(&colors));
+    let mut boxes: Vec<Vec<Color>> = vec![img.pixels().map(|p| p.0).collect()];
+    while boxes.len() < colors {
+        let (i, (channel, spread)) = boxes.iter()
+            .map(|colors| widest_channel(colors))
+            .enumerate()
+            .max_by_key(|(_, (_, spread))| *spread)
+            .unwrap();
+        if spread == 0 {
+            break;
+        }
+        let mut split = boxes.swap_remove(i);
+        split.sort_unstable_by_key(|c| c[channel]);
+        let upper = split.split_off(split.len() / 2);
+        boxes.push(split);
+        boxes.push(upper);
+    }
+    return boxes.iter().map(|colors| mean(colors)).collect();
+}
+
+pub fn nearest(palette: &[Color], rgb: Color) -> Color {
+    let distance = |c: &Color| -> i32 {
+        return (0..3).map(|i| (c[i] as i32 - rgb[i] as i32).pow(2)).sum();
+    };
+    return *palette.iter().min_by_key(|c| distance(c)).unwrap_or(&rgb);
+}
+
+pub fn quantize(img: &RgbImage, palette: &[Color]) -> RgbImage {
+    return RgbImage::from_fn(img.width(), img.height(), |x, y| Rgb(nearest(palette, img.get_pixel(x, y).0)));
+}
+
+pub fn palette_bytes(palette: &[Color]) -> Vec<u8> {
+    let mut bytes = vec![(palette.len() - 1) as u8];
+    bytes.extend(palette.iter().flat_map(|c| c.iter().cloned()));
+    return bytes;
+}
+
+pub fn read_palette(bytes: &[u8]) -> Result<(Vec<Color>, &[u8]), DecodeError> {
+    let count = *bytes.first().ok_or(DecodeError::UnexpectedEof)? as usize + 1;
+    if bytes.len() < 1 + count * 3 {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let (head, rest) = bytes.split_at(1 + count * 3);
+    let palette = head[1..].chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+    return Ok((palette, rest));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_cut_finds_distinct_colors() {
+        let img = RgbImage::from_fn(8, 8, |x, _| if x < 4 { Rgb([10, 200, 30]) } else { Rgb([250, 0, 90]) });
+        let mut palette = median_cut(&img, 4);
+        palette.sort();
+        assert_eq!(palette, vec![[10, 200, 30], [250, 0, 90]]);
+        let bytes = [&palette_bytes(&palette)[..], &[9]].concat();
+        let (read, rest) = read_palette(&bytes).unwrap();
+        assert_eq!(read, palette);
+        assert_eq!(rest, &[9]);
+    }
+}
@@ -10,9 +10,32 @@ pub fn lerp(a: u8, b: u8, factor: f32) -> u8 {
     return ((a as f32) * (1f32 - factor) + (b as f32) * (factor)) as u8;
 }
 
+pub fn interpolate((a, b, c, d): Quad, size: usize, (dx, dy): Point) -> u8 {
+    let span = (size - 1).max(1) as f32;
+    let x_coord = (dx as f32 / span).clamp(0f32, 1f32);
+    let y_coord = (dy as f32 / span).clamp(0f32, 1f32);
+    return lerp(lerp(a, b, x_coord), lerp(c, d, x_coord), y_coord);
+}
+
+/// Extends a `width` x `height` plane to the next power-of-two square by
+/// replicating its last column and row, returning the plane and its side.
+pub fn pad_to_pow2<T: Copy>(pixels: &[T], width: usize, height: usize) -> (Vec<T>, usize) {
+    let rank = width.max(height).next_power_of_two().max(2);
+    let padded = (0..rank * rank).map(|i| {
+        let x = (i % rank).min(width - 1);
+        let y = (i / rank).min(height - 1);
+        return pixels[x + y * width];
+    }).collect();
+    return (padded, rank);
+}
+
 pub type Quad = (u8, u8, u8, u8);
-pub struct QuadMeta { pub low: u8, pub average: u8, pub high: u8, pub size: usize  }
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QuadMeta { pub low: u8, pub area_average: u8, pub high: u8, pub size: u32, pub sum: u64 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Quadtree {
     Leaf(u8, u8, u8, u8),
     Branch(Box<Quadtree>, Box<Quadtree>, Box<Quadtree>, Box<Quadtree>, Quad, QuadMeta),
@@ -20,10 +43,153 @@ pub enum Quadtree {
 
 pub type Point = (usize, usize);
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Interpolation { Nearest, Bilinear, Bicubic }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DetailOrientation { Horizontal, Vertical, Diagonal }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Region { pub x: usize, pub y: usize, pub size: usize }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rect { pub x: usize, pub y: usize, pub width: usize, pub height: usize }
+
+impl Rect {
+    pub fn overlaps(&self, (x, y): Point, size: usize) -> bool {
+        return x < self.x + self.width && self.x < x + size && y < self.y + self.height && self.y < y + size;
+    }
+
+    pub fn contains(&self, (x, y): Point) -> bool {
+        return self.overlaps((x, y), 1);
+    }
+
+    /// The same area on a half-rank plane, rounded outwards.
+    pub fn halved(&self) -> Rect {
+        let (x, y) = (self.x / 2, self.y / 2);
+        return Rect { x, y, width: (self.x + self.width).div_ceil(2) - x, height: (self.y + self.height).div_ceil(2) - y };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LeafInfo { pub region: Region, pub size: usize, pub depth: usize, pub value: u8, pub collapsed: bool }
+
+/// What a terminal node stores: a collapsed 2x2 leaf's average, a kept
+/// leaf's four samples, or the corner quad of a collapsed branch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LeafValue { Average(u8), Pixels(Quad), Corners(Quad) }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LeafRegion { pub x: usize, pub y: usize, pub size: usize, pub value: LeafValue }
+
+/// Walks the terminal nodes in the order `build_leaf_data` writes them.
+pub struct Leaves<'a> {
+    stack: Vec<(&'a Quadtree, Point)>,
+    cutoff: &'a dyn CutoffMap,
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = LeafRegion;
+
+    fn next(&mut self) -> Option<LeafRegion> {
+        while let Some((node, (x, y))) = self.stack.pop() {
+            let size = node.size();
+            let collapsed = self.cutoff.collapses((x, y), size, node.high() - node.low());
+            let value = match node {
+                Quadtree::Leaf(..) if collapsed => LeafValue::Average(node.average()),
+                Quadtree::Leaf(a, b, c, d) => LeafValue::Pixels((*a, *b, *c, *d)),
+                Quadtree::Branch(_, _, _, _, quad, _) if collapsed => LeafValue::Corners(*quad),
+                Quadtree::Branch(a, b, c, d, _, _) => {
+                    let s = size / 2;
+                    self.stack.extend_from_slice(&[(&**d, (x+s, y+s)), (&**c, (x, y+s)), (&**b, (x+s, y)), (&**a, (x, y))]);
+                    continue;
+                },
+            };
+            return Some(LeafRegion { x, y, size, value });
+        }
+        return None;
+    }
+}
+
+/// Shape of the tree a cutoff would serialize: every visited node, the
+/// terminals that collapse to one value or quad, the 2x2 leaves that keep
+/// all four samples, and how many terminals sit at each depth.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TreeStats {
+    pub total_nodes: usize,
+    pub collapsed_leaves: usize,
+    pub real_leaves: usize,
+    pub max_depth: usize,
+    pub depth_histogram: Vec<usize>,
+}
+
+impl TreeStats {
+    pub fn combined(stats: &[TreeStats]) -> TreeStats {
+        let mut total = TreeStats::default();
+        for channel in stats.iter() {
+            total.total_nodes += channel.total_nodes;
+            total.collapsed_leaves += channel.collapsed_leaves;
+            total.real_leaves += channel.real_leaves;
+            total.max_depth = total.max_depth.max(channel.max_depth);
+            if total.depth_histogram.len() < channel.depth_histogram.len() {
+                total.depth_histogram.resize(channel.depth_histogram.len(), 0);
+            }
+            for (sum, count) in total.depth_histogram.iter_mut().zip(channel.depth_histogram.iter()) {
+                *sum += count;
+            }
+        }
+        return total;
+    }
+}
+
+pub trait CutoffMap: Sync {
+    fn cutoff_at(&self, offset: Point, size: usize) -> u8;
+    /// Whether a node of `size` at `offset` is kept as one terminal whatever
+    /// its contrast.
+    fn terminal_at(&self, _offset: Point, _size: usize) -> bool {
+        return false;
+    }
+    /// Whether a node whose values span `contrast` is stored collapsed.
+    fn collapses(&self, offset: Point, size: usize, contrast: u8) -> bool {
+        return self.terminal_at(offset, size) || contrast < self.cutoff_at(offset, size);
+    }
+}
+
+impl CutoffMap for u8 {
+    fn cutoff_at(&self, _offset: Point, _size: usize) -> u8 {
+        return *self;
+    }
+}
+
+/// Collapses every branch no wider than `min_leaf`, so regions of that size
+/// are reconstructed from their four corners; 2x2 leaves still follow `base`.
+pub struct MinLeaf<'a> {
+    pub base: &'a dyn CutoffMap,
+    pub min_leaf: usize,
+}
+
+impl<'a> CutoffMap for MinLeaf<'a> {
+    fn cutoff_at(&self, offset: Point, size: usize) -> u8 {
+        return self.base.cutoff_at(offset, size);
+    }
+
+    fn terminal_at(&self, offset: Point, size: usize) -> bool {
+        return (size > 2 && size <= self.min_leaf) || self.base.terminal_at(offset, size);
+    }
+}
+
 impl Quadtree {
     pub fn new(pixels: BitmapData) -> Box<Quadtree> {
         let rank = (pixels.len() as f32).sqrt() as usize;
-        assert!(pixels.len() as usize == rank * rank);
+        assert!(pixels.len() == rank * rank);
         return Quadtree::build(pixels, rank, (0,0), rank);
     }
     fn build(pixels: BitmapData, rank: usize, (x, y): Point, size: usize) -> Box<Quadtree> {
@@ -36,28 +202,88 @@ impl Quadtree {
             ))
         }
         let s = size / 2;
-        let a = Quadtree::build(pixels, rank, (x, y), s);
-        let b = Quadtree::build(pixels, rank, (x+s, y), s);
-        let c = Quadtree::build(pixels, rank, (x, y+s), s);
-        let d = Quadtree::build(pixels, rank, (x+s, y+s), s);
-        let quad = (
-            pixels[x + y*rank],
-            pixels[x+size-1 + y*rank],
-            pixels[x + (y+size-1)*rank],
-            pixels[x+size-1 + (y+size-1)*rank]
+        return Quadtree::join(
+            Quadtree::build(pixels, rank, (x, y), s),
+            Quadtree::build(pixels, rank, (x+s, y), s),
+            Quadtree::build(pixels, rank, (x, y+s), s),
+            Quadtree::build(pixels, rank, (x+s, y+s), s)
         );
+    }
+    pub fn join(a: Box<Quadtree>, b: Box<Quadtree>, c: Box<Quadtree>, d: Box<Quadtree>) -> Box<Quadtree> {
+        let s = a.size();
         let low = min(min(a.low(), b.low()), min(c.low(), d.low()));
         let high = max(max(a.high(), b.high()), max(c.high(), d.high()));
-        let aver = average(quad.0, quad.1, quad.2, quad.3);
-        let meta = QuadMeta{ low, high, average: aver, size };
+        let sum = a.sum() + b.sum() + c.sum() + d.sum();
+        let size = (s * 2) as u32;
+        debug_assert!(size.is_power_of_two());
+        let corners = (a.get((0, 0)), b.get((s-1, 0)), c.get((0, s-1)), d.get((s-1, s-1)));
+        let (quad, meta) = branch_summary(corners, low, high, sum, size);
         return Box::new(Quadtree::Branch(a, b, c, d, quad, meta));
     }
-    #[allow(dead_code)]
+    pub fn from_fn(size: usize, f: impl Fn(Point) -> u8) -> Box<Quadtree> {
+        let pixels = (0..size*size).map(|i| f((i % size, i / size))).collect();
+        return Quadtree::new(&pixels);
+    }
     pub fn get(&self, p: Point) -> u8 {
-        return self.get_deep(p, 0, (0, 0));
+        return self.get_deep(p, &0, (0, 0), false, Interpolation::Bilinear);
     }
     pub fn get_approx(&self, p: Point, cutoff: u8) -> u8 {
-        return self.get_deep(p, cutoff, (0, 0));
+        return self.get_deep(p, &cutoff, (0, 0), false, Interpolation::Bilinear);
+    }
+    pub fn get_mapped(&self, p: Point, cutoff: &dyn CutoffMap) -> u8 {
+        return self.get_deep(p, cutoff, (0, 0), false, Interpolation::Bilinear);
+    }
+    pub fn node_at(&self, p: Point, size: usize) -> &Quadtree {
+        return self.descend(p, size, (0, 0));
+    }
+    fn descend(&self, p: Point, size: usize, (xo, yo): Point) -> &Quadtree {
+        match self {
+            Quadtree::Branch(a, b, c, d, _, meta) if meta.size as usize > size => {
+                let s = meta.size as usize / 2;
+                let left = (p.0 - xo) < s;
+                let top = (p.1 - yo) < s;
+                return match (left, top) {
+                    (true, true) => a.descend(p, size, (xo, yo)),
+                    (false, true) => b.descend(p, size, (xo+s, yo)),
+                    (true, false) => c.descend(p, size, (xo, yo+s)),
+                    (false, false) => d.descend(p, size, (xo+s, yo+s)),
+                }
+            },
+            _ => self,
+        }
+    }
+    pub fn size(&self) -> usize {
+        return match self {
+            Quadtree::Leaf(..) => 2,
+            Quadtree::Branch(_, _, _, _, _, meta) => meta.size as usize,
+        }
+    }
+    pub fn detail_orientation(&self) -> DetailOrientation {
+        let (gx, gy) = self.gradients();
+        if gy > 2 * gx {
+            return DetailOrientation::Horizontal;
+        }
+        if gx > 2 * gy {
+            return DetailOrientation::Vertical;
+        }
+        return DetailOrientation::Diagonal;
+    }
+    fn gradients(&self) -> (u64, u64) {
+        return match self {
+            Quadtree::Leaf(a, b, c, d) => {
+                let (a, b, c, d) = (*a as i64, *b as i64, *c as i64, *d as i64);
+                (((b - a) + (d - c)).unsigned_abs(), ((c - a) + (d - b)).unsigned_abs())
+            },
+            Quadtree::Branch(a, b, c, d, _, _) => [a, b, c, d].iter()
+                .map(|child| child.gradients())
+                .fold((0, 0), |(x, y), (dx, dy)| (x + dx, y + dy)),
+        };
+    }
+    pub fn node_count(&self) -> usize {
+        return match self {
+            Quadtree::Leaf(..) => 1,
+            Quadtree::Branch(a, b, c, d, _, _) => 1 + a.node_count() + b.node_count() + c.node_count() + d.node_count(),
+        };
     }
     pub fn low(&self) -> u8 {
         return match self {
@@ -74,16 +300,228 @@ impl Quadtree {
     pub fn average(&self) -> u8 {
         return match self {
             Quadtree::Leaf(a, b, c, d) =>  average(*a, *b, *c, *d),
-            Quadtree::Branch(_, _, _, _, _, meta) => meta.average,
+            Quadtree::Branch(_, _, _, _, _, meta) => meta.area_average,
+        }
+    }
+    pub fn sum(&self) -> u64 {
+        return match self {
+            Quadtree::Leaf(a, b, c, d) => *a as u64 + *b as u64 + *c as u64 + *d as u64,
+            Quadtree::Branch(_, _, _, _, _, meta) => meta.sum,
+        }
+    }
+    pub fn region_errors(&self, original_plane: &[u8], cutoff: &dyn CutoffMap) -> Vec<(Region, f64)> {
+        let mut errors = vec![];
+        let rank = self.size();
+        self.collect_errors(original_plane, rank, cutoff, (0, 0), &mut errors);
+        return errors;
+    }
+    fn collect_errors(&self, plane: &[u8], rank: usize, cutoff: &dyn CutoffMap, (x, y): Point, errors: &mut Vec<(Region, f64)>) {
+        let size = self.size();
+        let contrast = self.high() - self.low();
+        if cutoff.collapses((x, y), size, contrast) {
+            errors.push((Region { x, y, size }, self.collapse_error(plane, rank, (x, y))));
+        } else if let Quadtree::Branch(a, b, c, d, _, _) = self {
+            let s = size / 2;
+            a.collect_errors(plane, rank, cutoff, (x, y), errors);
+            b.collect_errors(plane, rank, cutoff, (x+s, y), errors);
+            c.collect_errors(plane, rank, cutoff, (x, y+s), errors);
+            d.collect_errors(plane, rank, cutoff, (x+s, y+s), errors);
+        }
+    }
+    pub fn collapse_error(&self, plane: &[u8], rank: usize, (x, y): Point) -> f64 {
+        let size = self.size();
+        let mut squared = 0f64;
+        for dy in 0..size {
+            for dx in 0..size {
+                let value = match self {
+                    Quadtree::Leaf(a, b, c, d) => average(*a, *b, *c, *d),
+                    Quadtree::Branch(_, _, _, _, quad, _) => interpolate(*quad, size, (dx, dy)),
+                };
+                let diff = value as f64 - plane[x + dx + (y + dy) * rank] as f64;
+                squared += diff * diff;
+            }
+        }
+        return squared / (size * size) as f64;
+    }
+    pub fn describe_pixel(&self, p: Point, cutoff: &dyn CutoffMap) -> LeafInfo {
+        let (mut node, mut offset, mut depth) = (self, (0, 0), 0);
+        loop {
+            let size = node.size();
+            let collapsed = cutoff.collapses(offset, size, node.high() - node.low());
+            match node {
+                Quadtree::Branch(a, b, c, d, _, _) if !collapsed => {
+                    let s = size / 2;
+                    let (left, top) = (p.0 - offset.0 < s, p.1 - offset.1 < s);
+                    node = match (left, top) {
+                        (true, true) => a,
+                        (false, true) => b,
+                        (true, false) => c,
+                        (false, false) => d,
+                    };
+                    offset = (offset.0 + if left { 0 } else { s }, offset.1 + if top { 0 } else { s });
+                    depth += 1;
+                },
+                _ => {
+                    let region = Region { x: offset.0, y: offset.1, size };
+                    let value = node.get_deep(p, cutoff, offset, false, Interpolation::Bilinear);
+                    return LeafInfo { region, size, depth, value, collapsed };
+                },
+            }
+        }
+    }
+    pub fn leaves<'a>(&'a self, cutoff: &'a dyn CutoffMap) -> Leaves<'a> {
+        return Leaves { stack: vec![(self, (0, 0))], cutoff };
+    }
+    pub fn stats(&self, cutoff: &dyn CutoffMap) -> TreeStats {
+        let mut stats = TreeStats::default();
+        let mut stack = vec![(self, (0, 0), 0)];
+        while let Some((node, (x, y), depth)) = stack.pop() {
+            stats.total_nodes += 1;
+            let size = node.size();
+            let collapsed = cutoff.collapses((x, y), size, node.high() - node.low());
+            match node {
+                Quadtree::Branch(a, b, c, d, _, _) if !collapsed => {
+                    let s = size / 2;
+                    stack.extend_from_slice(&[(&**a, (x, y), depth+1), (&**b, (x+s, y), depth+1), (&**c, (x, y+s), depth+1), (&**d, (x+s, y+s), depth+1)]);
+                    continue;
+                },
+                Quadtree::Leaf(..) if !collapsed => stats.real_leaves += 1,
+                _ => stats.collapsed_leaves += 1,
+            }
+            stats.max_depth = stats.max_depth.max(depth);
+            if stats.depth_histogram.len() <= depth {
+                stats.depth_histogram.resize(depth + 1, 0);
+            }
+            stats.depth_histogram[depth] += 1;
+        }
+        return stats;
+    }
+    pub fn same_collapse(&self, a: &dyn CutoffMap, b: &dyn CutoffMap) -> bool {
+        return self.same_collapse_at(a, b, (0, 0));
+    }
+    fn same_collapse_at(&self, a: &dyn CutoffMap, b: &dyn CutoffMap, (x, y): Point) -> bool {
+        let size = self.size();
+        let contrast = self.high() - self.low();
+        let collapsed = a.collapses((x, y), size, contrast);
+        if collapsed != b.collapses((x, y), size, contrast) {
+            return false;
+        }
+        return match self {
+            Quadtree::Branch(qa, qb, qc, qd, _, _) if !collapsed => {
+                let s = size / 2;
+                qa.same_collapse_at(a, b, (x, y)) && qb.same_collapse_at(a, b, (x+s, y))
+                    && qc.same_collapse_at(a, b, (x, y+s)) && qd.same_collapse_at(a, b, (x+s, y+s))
+            },
+            _ => true,
+        };
+    }
+    pub fn fill(&self, cutoff: &dyn CutoffMap, clamp: bool, interpolation: Interpolation, plane: &mut [u8]) {
+        let rank = self.size();
+        let mut stack = vec![(self, (0, 0))];
+        let mut smoothed = vec![];
+        while let Some((node, (x, y))) = stack.pop() {
+            let size = node.size();
+            let collapsed = cutoff.collapses((x, y), size, node.high() - node.low());
+            match node {
+                Quadtree::Leaf(a, b, c, d) => {
+                    let values = if collapsed { [average(*a, *b, *c, *d); 4] } else { [*a, *b, *c, *d] };
+                    plane[x + y*rank] = values[0];
+                    plane[x+1 + y*rank] = values[1];
+                    plane[x + (y+1)*rank] = values[2];
+                    plane[x+1 + (y+1)*rank] = values[3];
+                },
+                Quadtree::Branch(_, _, _, _, quad, meta) if collapsed => {
+                    for dy in 0..size {
+                        for dx in 0..size {
+                            let value = match interpolation {
+                                Interpolation::Nearest => meta.area_average,
+                                _ => interpolate(*quad, size, (dx, dy)),
+                            };
+                            plane[x+dx + (y+dy)*rank] = if clamp { value.max(meta.low).min(meta.high) } else { value };
+                        }
+                    }
+                    if interpolation == Interpolation::Bicubic {
+                        let bounds = if clamp { (meta.low, meta.high) } else { (0, 255) };
+                        smoothed.push((Region { x, y, size }, bounds));
+                    }
+                },
+                Quadtree::Branch(a, b, c, d, _, _) => {
+                    let s = size / 2;
+                    stack.extend_from_slice(&[(&**a, (x, y)), (&**b, (x+s, y)), (&**c, (x, y+s)), (&**d, (x+s, y+s))]);
+                },
+            }
+        }
+        if smoothed.is_empty() {
+            return;
+        }
+        let bilinear = plane.to_vec();
+        let lattice = |(x, y): Point| bilinear[x + y*rank];
+        for (region, bounds) in smoothed {
+            for dy in 0..region.size {
+                for dx in 0..region.size {
+                    if let Some(value) = bicubic(rank, region, (dx, dy), bounds, &lattice) {
+                        plane[region.x+dx + (region.y+dy)*rank] = value;
+                    }
+                }
+            }
+        }
+    }
+    /// Fills `out`, a `rect.width` x `rect.height` buffer, with the pixels of
+    /// `rect` as `get_approx` would return them, walking the tree only once.
+    pub fn get_rect(&self, rect: Rect, cutoff: u8, out: &mut [u8]) {
+        self.fill_rect(rect, &cutoff, false, Interpolation::Bilinear, out);
+    }
+    /// `get_rect` with a cutoff map and the options of `get_deep`; bicubic
+    /// renders as bilinear here, as it does there.
+    pub fn fill_rect(&self, rect: Rect, cutoff: &dyn CutoffMap, clamp: bool, interpolation: Interpolation, out: &mut [u8]) {
+        let mut stack = vec![(self, (0, 0))];
+        while let Some((node, (x, y))) = stack.pop() {
+            let size = node.size();
+            if !rect.overlaps((x, y), size) {
+                continue;
+            }
+            match node {
+                Quadtree::Branch(a, b, c, d, _, meta) if !cutoff.collapses((x, y), size, meta.high - meta.low) => {
+                    let s = size / 2;
+                    stack.extend_from_slice(&[(&**a, (x, y)), (&**b, (x+s, y)), (&**c, (x, y+s)), (&**d, (x+s, y+s))]);
+                },
+                _ => {
+                    for py in y.max(rect.y)..(y + size).min(rect.y + rect.height) {
+                        for px in x.max(rect.x)..(x + size).min(rect.x + rect.width) {
+                            out[px - rect.x + (py - rect.y) * rect.width] = node.get_deep((px, py), cutoff, (x, y), clamp, interpolation);
+                        }
+                    }
+                },
+            }
         }
     }
-    pub fn get_deep(&self, p: Point, cutoff: u8, self_offset: Point) -> u8 {
+    /// Reconstructs a single pixel like `fill` does. Bicubic needs the corners
+    /// of the neighbouring blocks, so it is only available from the root;
+    /// `get_deep` renders it as bilinear.
+    pub fn sample(&self, p: Point, cutoff: &dyn CutoffMap, clamp: bool, interpolation: Interpolation) -> u8 {
+        let bilinear = |q: Point| {
+            let interpolation = if interpolation == Interpolation::Bicubic { Interpolation::Bilinear } else { interpolation };
+            return self.get_deep(q, cutoff, (0, 0), clamp, interpolation);
+        };
+        if interpolation != Interpolation::Bicubic {
+            return bilinear(p);
+        }
+        let leaf = self.describe_pixel(p, cutoff);
+        if !leaf.collapsed || leaf.size == 2 {
+            return leaf.value;
+        }
+        let node = self.node_at(p, leaf.size);
+        let bounds = if clamp { (node.low(), node.high()) } else { (0, 255) };
+        let offset = (p.0 - leaf.region.x, p.1 - leaf.region.y);
+        return bicubic(self.size(), leaf.region, offset, bounds, &bilinear).unwrap_or_else(|| bilinear(p));
+    }
+    pub fn get_deep(&self, p: Point, cutoff: &dyn CutoffMap, self_offset: Point, clamp: bool, interpolation: Interpolation) -> u8 {
         let (x, y) = p;
         let (xo, yo) = self_offset;
         match self {
             Quadtree::Leaf(a, b, c, d) => {
                 let contrast = range(a, b, c, d);
-                if contrast < cutoff {
+                if cutoff.collapses(self_offset, 2, contrast) {
                     return average(*a, *b, *c, *d);
                 }
                 match (x == xo, y == yo) {
@@ -93,34 +531,346 @@ impl Quadtree {
                     (false, false) => *d,
                 }
             },
-            Quadtree::Branch(a, b, c, d, (a_val, b_val, c_val, d_val), meta) => {
-                let QuadMeta { size, low, high, .. } = meta;
+            Quadtree::Branch(a, b, c, d, quad, meta) => {
+                let QuadMeta { low, high, .. } = meta;
+                let size = meta.size as usize;
                 let contrast = high - low;
-                if contrast < cutoff {
-                    let x_coord = (x-xo) as f32 / (*size as f32);
-                    let y_coord = (y-yo) as f32 / (*size as f32);
-                    let output = lerp(
-                        lerp(*a_val, *b_val, x_coord),
-                        lerp(*c_val, *d_val, x_coord),
-                        y_coord
-                    );
-                    return if x == xo || y == yo { output/2 } else { output }
+                if cutoff.collapses(self_offset, size, contrast) {
+                    if interpolation == Interpolation::Nearest {
+                        return meta.area_average;
+                    }
+                    let value = interpolate(*quad, size, (x-xo, y-yo));
+                    return if clamp { value.max(*low).min(*high) } else { value };
                 }
                 let s = size / 2;
                 let left = (x - xo) < s;
                 let top = (y - yo) < s;
                 return match (left, top) {
-                    (true, true) => a.get_deep(p, cutoff, (xo, yo)),
-                    (false, true) => b.get_deep(p, cutoff, (xo+s, yo)),
-                    (true, false) => c.get_deep(p, cutoff, (xo, yo+s)),
-                    (false, false) => d.get_deep(p, cutoff, (xo+s, yo+s)),
+                    (true, true) => a.get_deep(p, cutoff, (xo, yo), clamp, interpolation),
+                    (false, true) => b.get_deep(p, cutoff, (xo+s, yo), clamp, interpolation),
+                    (true, false) => c.get_deep(p, cutoff, (xo, yo+s), clamp, interpolation),
+                    (false, false) => d.get_deep(p, cutoff, (xo+s, yo+s), clamp, interpolation),
+                }
+            },
+        }
+    }
+}
+
+fn catmull_rom([p0, p1, p2, p3]: [f32; 4], t: f32) -> f32 {
+    return 0.5 * (2f32 * p1 + (p2 - p0) * t
+        + (2f32 * p0 - 5f32 * p1 + 4f32 * p2 - p3) * t * t
+        + (3f32 * (p1 - p2) + p3 - p0) * t * t * t);
+}
+
+/// Catmull-Rom surface through the corners of `region` and of the blocks
+/// around it, read from `lattice`; `None` when a neighbour lies outside the
+/// `rank` x `rank` plane.
+fn bicubic(rank: usize, region: Region, (dx, dy): Point, (low, high): (u8, u8), lattice: &dyn Fn(Point) -> u8) -> Option<u8> {
+    let Region { x, y, size } = region;
+    if x < size || y < size || x + 2*size > rank || y + 2*size > rank {
+        return None;
+    }
+    let xs = [x - size, x, x + size - 1, x + 2*size - 1];
+    let ys = [y - size, y, y + size - 1, y + 2*size - 1];
+    let span = (size - 1) as f32;
+    let row = |y: usize| catmull_rom([0, 1, 2, 3].map(|i| lattice((xs[i], y)) as f32), dx as f32 / span);
+    let value = catmull_rom(ys.map(row), dy as f32 / span);
+    return Some(value.round().clamp(low as f32, high as f32) as u8);
+}
+
+/// The corner quad and mean `join` stores for a branch whose pixels span
+/// `low..=high`, add up to `sum` and have `corners` at its four corners.
+pub fn branch_summary(corners: Quad, low: u8, high: u8, sum: u64, size: u32) -> (Quad, QuadMeta) {
+    let area_average = (sum / (size as u64 * size as u64)) as u8;
+    return (shift_to_mean(corners, area_average), QuadMeta{ low, high, area_average, size, sum });
+}
+
+/// Offsets the corner samples of a block so the bilinear surface they span
+/// has the block's true mean, keeping the gradient between them.
+fn shift_to_mean((a, b, c, d): Quad, mean: u8) -> Quad {
+    let delta = mean as i16 - (a as i16 + b as i16 + c as i16 + d as i16) / 4;
+    let shift = |v: u8| (v as i16 + delta).clamp(0, 255) as u8;
+    return (shift(a), shift(b), shift(c), shift(d));
+}
+
+pub type WideQuad = (u16, u16, u16, u16);
+
+#[derive(Debug, PartialEq)]
+pub struct WideMeta { pub low: u16, pub high: u16, pub size: u32, pub sum: u64 }
+
+/// 16-bit counterpart of `Quadtree` for high-bit-depth planes. Cutoffs stay
+/// in 8-bit units and are widened with `wide_cutoff`.
+#[derive(Debug, PartialEq)]
+pub enum WideTree {
+    Leaf(u16, u16, u16, u16),
+    Branch(Box<WideTree>, Box<WideTree>, Box<WideTree>, Box<WideTree>, WideQuad, WideMeta),
+}
+
+pub fn wide_cutoff(cutoff: u8) -> u16 {
+    return cutoff as u16 * 257;
+}
+
+pub fn range_wide(a: u16, b: u16, c: u16, d: u16) -> u16 {
+    return max(max(a, b), max(c, d)) - min(min(a, b), min(c, d));
+}
+
+pub fn lerp_wide(a: u16, b: u16, factor: f32) -> u16 {
+    return ((a as f32) * (1f32 - factor) + (b as f32) * (factor)) as u16;
+}
+
+pub fn interpolate_wide((a, b, c, d): WideQuad, size: usize, (dx, dy): Point) -> u16 {
+    let span = (size - 1).max(1) as f32;
+    let x_coord = (dx as f32 / span).clamp(0f32, 1f32);
+    let y_coord = (dy as f32 / span).clamp(0f32, 1f32);
+    return lerp_wide(lerp_wide(a, b, x_coord), lerp_wide(c, d, x_coord), y_coord);
+}
+
+pub fn average_wide(a: u16, b: u16, c: u16, d: u16) -> u16 {
+    return ((a as u32 + b as u32 + c as u32 + d as u32) / 4) as u16;
+}
+
+impl WideTree {
+    pub fn new(pixels: &[u16]) -> Box<WideTree> {
+        let rank = (pixels.len() as f32).sqrt() as usize;
+        assert!(pixels.len() == rank * rank);
+        return WideTree::build(pixels, rank, (0, 0), rank);
+    }
+    fn build(pixels: &[u16], rank: usize, (x, y): Point, size: usize) -> Box<WideTree> {
+        if size == 2 {
+            return Box::new(WideTree::Leaf(
+                pixels[x + y*rank],
+                pixels[x+1 + y*rank],
+                pixels[x + (y+1)*rank],
+                pixels[x+1 + (y+1)*rank]
+            ))
+        }
+        let s = size / 2;
+        return WideTree::join(
+            WideTree::build(pixels, rank, (x, y), s),
+            WideTree::build(pixels, rank, (x+s, y), s),
+            WideTree::build(pixels, rank, (x, y+s), s),
+            WideTree::build(pixels, rank, (x+s, y+s), s)
+        );
+    }
+    pub fn join(a: Box<WideTree>, b: Box<WideTree>, c: Box<WideTree>, d: Box<WideTree>) -> Box<WideTree> {
+        let s = a.size();
+        let low = min(min(a.low(), b.low()), min(c.low(), d.low()));
+        let high = max(max(a.high(), b.high()), max(c.high(), d.high()));
+        let sum = a.sum() + b.sum() + c.sum() + d.sum();
+        let size = (s * 2) as u32;
+        let mean = (sum / (size as u64 * size as u64)) as u16;
+        let (ca, cb, cc, cd) = (a.get((0, 0)), b.get((s-1, 0)), c.get((0, s-1)), d.get((s-1, s-1)));
+        let delta = mean as i32 - (ca as i32 + cb as i32 + cc as i32 + cd as i32) / 4;
+        let shift = |v: u16| (v as i32 + delta).clamp(0, u16::MAX as i32) as u16;
+        let quad = (shift(ca), shift(cb), shift(cc), shift(cd));
+        return Box::new(WideTree::Branch(a, b, c, d, quad, WideMeta { low, high, size, sum }));
+    }
+    pub fn from_fn(size: usize, f: impl Fn(Point) -> u16) -> Box<WideTree> {
+        let pixels: Vec<u16> = (0..size*size).map(|i| f((i % size, i / size))).collect();
+        return WideTree::new(&pixels);
+    }
+    pub fn size(&self) -> usize {
+        return match self {
+            WideTree::Leaf(..) => 2,
+            WideTree::Branch(_, _, _, _, _, meta) => meta.size as usize,
+        }
+    }
+    pub fn low(&self) -> u16 {
+        return match self {
+            WideTree::Leaf(a, b, c, d) => min(min(*a, *b), min(*c, *d)),
+            WideTree::Branch(_, _, _, _, _, meta) => meta.low,
+        }
+    }
+    pub fn high(&self) -> u16 {
+        return match self {
+            WideTree::Leaf(a, b, c, d) => max(max(*a, *b), max(*c, *d)),
+            WideTree::Branch(_, _, _, _, _, meta) => meta.high,
+        }
+    }
+    pub fn sum(&self) -> u64 {
+        return match self {
+            WideTree::Leaf(a, b, c, d) => *a as u64 + *b as u64 + *c as u64 + *d as u64,
+            WideTree::Branch(_, _, _, _, _, meta) => meta.sum,
+        }
+    }
+    pub fn get(&self, p: Point) -> u16 {
+        return self.get_deep(p, &0, (0, 0));
+    }
+    pub fn get_mapped(&self, p: Point, cutoff: &dyn CutoffMap) -> u16 {
+        return self.get_deep(p, cutoff, (0, 0));
+    }
+    fn get_deep(&self, (x, y): Point, cutoff: &dyn CutoffMap, (xo, yo): Point) -> u16 {
+        let size = self.size();
+        let collapsed = self.high() - self.low() < wide_cutoff(cutoff.cutoff_at((xo, yo), size));
+        return match self {
+            WideTree::Leaf(a, b, c, d) if collapsed => average_wide(*a, *b, *c, *d),
+            WideTree::Leaf(a, b, c, d) => match (x == xo, y == yo) {
+                (true, true) => *a,
+                (false, true) => *b,
+                (true, false) => *c,
+                (false, false) => *d,
+            },
+            WideTree::Branch(_, _, _, _, quad, _) if collapsed => interpolate_wide(*quad, size, (x-xo, y-yo)),
+            WideTree::Branch(a, b, c, d, _, _) => {
+                let s = size / 2;
+                match ((x - xo) < s, (y - yo) < s) {
+                    (true, true) => a.get_deep((x, y), cutoff, (xo, yo)),
+                    (false, true) => b.get_deep((x, y), cutoff, (xo+s, yo)),
+                    (true, false) => c.get_deep((x, y), cutoff, (xo, yo+s)),
+                    (false, false) => d.get_deep((x, y), cutoff, (xo+s, yo+s)),
                 }
             },
+        };
+    }
+}
+
+/// A node of `FlatQuadtree`; a branch's four children sit next to each other
+/// in the arena, starting at `children`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlatNode {
+    Leaf(u8, u8, u8, u8),
+    Branch { children: u32, quad: Quad, low: u8, high: u8, sum: u64 },
+}
+
+/// Arena-backed counterpart of `Quadtree`: every node lives in one `Vec`,
+/// leaves first in Z-order, then each level of branches, the root last.
+#[derive(Debug, PartialEq)]
+pub struct FlatQuadtree {
+    nodes: Vec<FlatNode>,
+    rank: usize,
+}
+
+/// The block at Z-order position `k`, as column and row.
+fn morton(k: usize) -> Point {
+    let (mut x, mut y, mut bit) = (0, 0, 0);
+    while k >> (2 * bit) != 0 {
+        x |= (k >> (2 * bit) & 1) << bit;
+        y |= (k >> (2 * bit + 1) & 1) << bit;
+        bit += 1;
+    }
+    return (x, y);
+}
+
+impl FlatNode {
+    fn low(&self) -> u8 {
+        return match self {
+            FlatNode::Leaf(a, b, c, d) => min(min(*a, *b), min(*c, *d)),
+            FlatNode::Branch { low, .. } => *low,
+        };
+    }
+    fn high(&self) -> u8 {
+        return match self {
+            FlatNode::Leaf(a, b, c, d) => max(max(*a, *b), max(*c, *d)),
+            FlatNode::Branch { high, .. } => *high,
+        };
+    }
+    fn sum(&self) -> u64 {
+        return match self {
+            FlatNode::Leaf(a, b, c, d) => *a as u64 + *b as u64 + *c as u64 + *d as u64,
+            FlatNode::Branch { sum, .. } => *sum,
+        };
+    }
+}
+
+impl FlatQuadtree {
+    pub fn new(pixels: &[u8]) -> FlatQuadtree {
+        let rank = (pixels.len() as f32).sqrt() as usize;
+        assert!(pixels.len() == rank * rank && rank >= 2);
+        let pixel = |x: usize, y: usize| pixels[x + y * rank];
+        let mut len = rank * rank / 4;
+        let mut nodes = Vec::with_capacity(len * 4 / 3 + 1);
+        for k in 0..len {
+            let (x, y) = morton(k);
+            let (x, y) = (2 * x, 2 * y);
+            nodes.push(FlatNode::Leaf(pixel(x, y), pixel(x+1, y), pixel(x, y+1), pixel(x+1, y+1)));
+        }
+        let (mut start, mut size) = (0, 4);
+        while len > 1 {
+            for k in 0..len / 4 {
+                let children = start + 4 * k;
+                let group = &nodes[children..children + 4];
+                let low = group.iter().map(FlatNode::low).min().unwrap();
+                let high = group.iter().map(FlatNode::high).max().unwrap();
+                let sum = group.iter().map(FlatNode::sum).sum();
+                let (x, y) = morton(k);
+                let (x, y) = (x * size, y * size);
+                let corners = (pixel(x, y), pixel(x+size-1, y), pixel(x, y+size-1), pixel(x+size-1, y+size-1));
+                let quad = shift_to_mean(corners, (sum / (size * size) as u64) as u8);
+                nodes.push(FlatNode::Branch { children: children as u32, quad, low, high, sum });
+            }
+            start += len;
+            len /= 4;
+            size *= 2;
+        }
+        return FlatQuadtree { nodes, rank };
+    }
+    pub fn size(&self) -> usize {
+        return self.rank;
+    }
+    pub fn node_count(&self) -> usize {
+        return self.nodes.len();
+    }
+    pub fn get_approx(&self, p: Point, cutoff: u8) -> u8 {
+        return self.get_from(self.nodes.len() - 1, (0, 0), self.rank, p, cutoff);
+    }
+    fn get_from(&self, mut node: usize, (mut xo, mut yo): Point, mut size: usize, (x, y): Point, cutoff: u8) -> u8 {
+        loop {
+            match self.nodes[node] {
+                FlatNode::Leaf(a, b, c, d) => {
+                    if range(&a, &b, &c, &d) < cutoff {
+                        return average(a, b, c, d);
+                    }
+                    return match (x == xo, y == yo) {
+                        (true, true) => a,
+                        (false, true) => b,
+                        (true, false) => c,
+                        (false, false) => d,
+                    };
+                },
+                FlatNode::Branch { children, quad, low, high, .. } => {
+                    if high - low < cutoff {
+                        return interpolate(quad, size, (x - xo, y - yo));
+                    }
+                    size /= 2;
+                    let (right, bottom) = ((x - xo >= size) as usize, (y - yo >= size) as usize);
+                    node = children as usize + right + 2 * bottom;
+                    xo += right * size;
+                    yo += bottom * size;
+                },
+            }
+        }
+    }
+    /// Same output as `Quadtree::get_rect`.
+    pub fn get_rect(&self, rect: Rect, cutoff: u8, out: &mut [u8]) {
+        let mut stack = vec![(self.nodes.len() - 1, (0, 0), self.rank)];
+        while let Some((node, (x, y), size)) = stack.pop() {
+            if !rect.overlaps((x, y), size) {
+                continue;
+            }
+            match self.nodes[node] {
+                FlatNode::Branch { children, low, high, .. } if high - low >= cutoff => {
+                    let (children, s) = (children as usize, size / 2);
+                    stack.extend_from_slice(&[
+                        (children, (x, y), s),
+                        (children + 1, (x+s, y), s),
+                        (children + 2, (x, y+s), s),
+                        (children + 3, (x+s, y+s), s),
+                    ]);
+                },
+                _ => {
+                    for py in y.max(rect.y)..(y + size).min(rect.y + rect.height) {
+                        for px in x.max(rect.x)..(x + size).min(rect.x + rect.width) {
+                            out[px - rect.x + (py - rect.y) * rect.width] = self.get_from(node, (x, y), size, (px, py), cutoff);
+                        }
+                    }
+                },
+            }
         }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module, clippy::needless_borrow, clippy::assertions_on_constants, clippy::boxed_local)]
 mod tests {
     use super::*;
 
@@ -209,6 +959,330 @@ mod tests {
             Quadtree::Branch(..) => assert!(false),
         }
     }
+
+    #[test]
+    fn every_node_size_is_a_power_of_two() {
+        let bitmap = (0..64 * 64).map(|i| (i * 7 % 251) as u8).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        let mut sizes = vec![];
+        collect_sizes(&quadtree, &mut sizes);
+        assert_eq!(sizes.len(), 1 + 4 + 16 + 64 + 256 + 1024);
+        assert!(sizes.iter().all(|size| size.is_power_of_two()));
+        assert_eq!(sizes[0], 64);
+    }
+
+    fn collect_sizes(node: &Quadtree, sizes: &mut Vec<u32>) {
+        sizes.push(node.size() as u32);
+        if let Quadtree::Branch(a, b, c, d, _, meta) = node {
+            assert_eq!(meta.size as usize, node.size());
+            for child in [a, b, c, d].iter() {
+                collect_sizes(child, sizes);
+            }
+        }
+    }
+
+    #[test]
+    fn padding_replicates_the_edges() {
+        let (padded, rank) = pad_to_pow2(&[1, 2, 3, 4, 5, 6], 3, 2);
+        assert_eq!(rank, 4);
+        assert_eq!(padded, vec![
+            1, 2, 3, 3,
+            4, 5, 6, 6,
+            4, 5, 6, 6,
+            4, 5, 6, 6
+        ]);
+        assert_eq!(pad_to_pow2(&[9], 1, 1), (vec![9; 4], 2));
+    }
+
+    #[test]
+    fn region_errors_of_collapsed_leaf() {
+        let bitmap = vec![
+            10u8, 12u8, 200u8, 0u8,
+            10u8, 12u8, 0u8, 200u8,
+            50u8, 50u8, 90u8, 90u8,
+            50u8, 50u8, 90u8, 90u8
+        ];
+        let quadtree = Quadtree::new(&bitmap);
+        let errors = quadtree.region_errors(&bitmap, &5u8);
+        assert_eq!(errors, vec![
+            (Region { x: 0, y: 0, size: 2 }, 1f64),
+            (Region { x: 0, y: 2, size: 2 }, 0f64),
+            (Region { x: 2, y: 2, size: 2 }, 0f64),
+        ]);
+    }
+
+    #[test]
+    fn region_errors_sum_to_reconstruction_error() {
+        let bitmap: Vec<u8> = (0..32 * 32).map(|i| ((i % 32) * 3 + (i / 32) * 2) as u8).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        let total: f64 = quadtree.region_errors(&bitmap, &20u8).iter()
+            .map(|(region, mse)| mse * (region.size * region.size) as f64)
+            .sum();
+        let expected: f64 = (0..32 * 32).map(|i| {
+            let diff = quadtree.get_approx((i % 32, i / 32), 20) as f64 - bitmap[i] as f64;
+            return diff * diff;
+        }).sum();
+        assert!(total > 0f64);
+        assert!((total - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn collapsed_block_edges_keep_their_corner_values() {
+        let uniform = Quadtree::new(&vec![120u8; 64]);
+        assert_eq!(uniform.get_approx((0, 0), 1), 120);
+        assert_eq!(uniform.get_approx((3, 0), 1), 120);
+        assert_eq!(uniform.get_approx((0, 7), 1), 120);
+        let ramp = Quadtree::from_fn(8, |(x, _)| (x * 10) as u8);
+        assert_eq!(ramp.get_approx((0, 0), 255), 0);
+        assert_eq!(ramp.get_approx((7, 0), 255), 70);
+        assert_eq!(ramp.get_approx((7, 7), 255), 70);
+    }
+
+    #[test]
+    fn clamped_interpolation_stays_within_region_range() {
+        let bitmap: Vec<u8> = (0..16 * 16).map(|i| if i % 16 < 12 { 100 + (i % 16 + i / 16) as u8 / 4 } else { 250 }).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        let mut overshoots = 0;
+        for i in 0..16 * 16 {
+            let p = (i % 16, i / 16);
+            let node = quadtree.node_at(p, quadtree.describe_pixel(p, &10u8).size);
+            let (low, high) = (node.low(), node.high());
+            let unclamped = quadtree.sample(p, &10u8, false, Interpolation::Bicubic);
+            if unclamped < low || unclamped > high {
+                overshoots += 1;
+            }
+            let clamped = quadtree.sample(p, &10u8, true, Interpolation::Bicubic);
+            assert!(low <= clamped && clamped <= high);
+        }
+        assert!(overshoots > 0);
+    }
+
+    #[test]
+    fn horizontal_stripes_report_horizontal_detail() {
+        let stripes: Vec<u8> = (0..32 * 32).map(|i| if (i / 32) % 3 == 0 { 200 } else { 40 }).collect();
+        assert_eq!(Quadtree::new(&stripes).detail_orientation(), DetailOrientation::Horizontal);
+        let columns: Vec<u8> = (0..32 * 32).map(|i| if (i % 32) % 3 == 0 { 200 } else { 40 }).collect();
+        assert_eq!(Quadtree::new(&columns).detail_orientation(), DetailOrientation::Vertical);
+        let checks: Vec<u8> = (0..32 * 32).map(|i| if (i % 32 + i / 32) % 2 == 0 { 200 } else { 40 }).collect();
+        assert_eq!(Quadtree::new(&checks).detail_orientation(), DetailOrientation::Diagonal);
+    }
+
+    #[test]
+    fn describe_pixel_reports_the_covering_leaf() {
+        let bitmap = vec![
+            1u8, 3u8, 255u8, 255u8,
+            1u8, 3u8, 255u8, 255u8,
+            5u8, 0u8, 4u8, 4u8,
+            0u8, 0u8, 4u8, 4u8
+        ];
+        let quadtree = Quadtree::new(&bitmap);
+        assert_eq!(quadtree.describe_pixel((1, 0), &3u8), LeafInfo {
+            region: Region { x: 0, y: 0, size: 2 }, size: 2, depth: 1, value: 2, collapsed: true,
+        });
+        assert_eq!(quadtree.describe_pixel((0, 2), &3u8), LeafInfo {
+            region: Region { x: 0, y: 2, size: 2 }, size: 2, depth: 1, value: 5, collapsed: false,
+        });
+    }
+
+    #[test]
+    fn min_leaf_collapses_small_regions_only() {
+        let min_leaf = MinLeaf { base: &10u8, min_leaf: 8 };
+        assert!(!min_leaf.collapses((0, 0), 2, 10));
+        assert!(min_leaf.collapses((0, 0), 4, 255));
+        assert!(min_leaf.collapses((8, 0), 8, 255));
+        assert!(!min_leaf.collapses((0, 0), 16, 10));
+        assert!(min_leaf.collapses((0, 0), 16, 9));
+        let checkers: Vec<u8> = (0..8 * 8).map(|i| if (i % 8 + i / 8) % 2 == 0 { 0 } else { 255 }).collect();
+        let stats = Quadtree::new(&checkers).stats(&min_leaf);
+        assert_eq!((stats.collapsed_leaves, stats.real_leaves), (1, 0));
+    }
+
+    #[test]
+    fn fill_matches_per_pixel_reconstruction() {
+        let bitmap: Vec<u8> = (0..32 * 32).map(|i| ((i % 32) * 5 + (i / 32) * (i % 7)) as u8).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        for cutoff in [0u8, 8, 40, 255].iter() {
+            let mut plane = vec![0u8; 32 * 32];
+            quadtree.fill(cutoff, false, Interpolation::Bilinear, &mut plane);
+            for (i, value) in plane.iter().enumerate() {
+                assert_eq!(*value, quadtree.get_approx((i % 32, i / 32), *cutoff));
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_fills_collapsed_blocks_with_their_average() {
+        let bitmap = vec![
+            1u8, 3u8, 255u8, 255u8,
+            1u8, 3u8, 255u8, 255u8,
+            5u8, 0u8, 4u8, 4u8,
+            0u8, 0u8, 4u8, 4u8
+        ];
+        let quadtree = Quadtree::new(&bitmap);
+        let render = |interpolation: Interpolation| -> Vec<u8> {
+            let mut plane = vec![0u8; 16];
+            quadtree.fill(&6u8, false, interpolation, &mut plane);
+            return plane;
+        };
+        let sample = |interpolation: Interpolation| -> Vec<u8> {
+            return (0..16).map(|i| quadtree.get_deep((i % 4, i / 4), &6u8, (0, 0), false, interpolation)).collect();
+        };
+        assert_eq!(sample(Interpolation::Nearest), vec![
+            2, 2, 255, 255,
+            2, 2, 255, 255,
+            1, 1, 4, 4,
+            1, 1, 4, 4
+        ]);
+        assert_eq!(sample(Interpolation::Bilinear), (0..16).map(|i| quadtree.get_approx((i % 4, i / 4), 6)).collect::<Vec<u8>>());
+        assert_eq!(render(Interpolation::Nearest), sample(Interpolation::Nearest));
+        assert_eq!(render(Interpolation::Bilinear), sample(Interpolation::Bilinear));
+        let faint: Vec<u8> = bitmap.iter().map(|v| *v.min(&9u8)).collect();
+        let faint = Quadtree::new(&faint);
+        let block = |interpolation: Interpolation| -> Vec<u8> {
+            return (0..16).map(|i| faint.get_deep((i % 4, i / 4), &10u8, (0, 0), false, interpolation)).collect();
+        };
+        assert_eq!(block(Interpolation::Nearest), vec![faint.average(); 16]);
+        assert_ne!(block(Interpolation::Bilinear), block(Interpolation::Nearest));
+    }
+
+    #[test]
+    fn collapsed_blocks_keep_their_true_mean() {
+        let bitmap: Vec<u8> = (0..16 * 16).map(|i| {
+            let (x, y) = (i % 16, i / 16);
+            return if (4..12).contains(&x) && (4..12).contains(&y) { 90 } else { 10 + (x + y) as u8 };
+        }).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        let mean = bitmap.iter().map(|v| *v as u64).sum::<u64>() / 256;
+        assert_eq!(quadtree.average() as u64, mean);
+        let corners = (bitmap[0], bitmap[15], bitmap[240], bitmap[255]);
+        let corner_error = (0..256).map(|i| {
+            let diff = interpolate(corners, 16, (i % 16, i / 16)) as f64 - bitmap[i] as f64;
+            return diff * diff;
+        }).sum::<f64>() / 256f64;
+        let psnr = |mse: f64| 10f64 * (255f64 * 255f64 / mse).log10();
+        assert!(psnr(quadtree.collapse_error(&bitmap, 16, (0, 0))) > psnr(corner_error) + 1f64);
+    }
+
+    #[test]
+    fn bicubic_fill_matches_per_pixel_sampling() {
+        let bitmap: Vec<u8> = (0..32 * 32).map(|i| ((i % 32) * (i / 32) / 4) as u8).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        for clamp in [false, true].iter() {
+            let mut plane = vec![0u8; 32 * 32];
+            quadtree.fill(&12u8, *clamp, Interpolation::Bicubic, &mut plane);
+            for (i, value) in plane.iter().enumerate() {
+                assert_eq!(*value, quadtree.sample((i % 32, i / 32), &12u8, *clamp, Interpolation::Bicubic));
+            }
+        }
+        let mut bilinear = vec![0u8; 32 * 32];
+        quadtree.fill(&12u8, false, Interpolation::Bilinear, &mut bilinear);
+        let mut bicubic = vec![0u8; 32 * 32];
+        quadtree.fill(&12u8, false, Interpolation::Bicubic, &mut bicubic);
+        assert_ne!(bicubic, bilinear);
+        assert_eq!(bicubic[..8], bilinear[..8]);
+    }
+
+    #[test]
+    fn flat_tree_matches_the_boxed_tree() {
+        let plane: Vec<u8> = (0..64 * 64).map(|i| ((i % 64) * 3 + (i / 64) * (i % 7)) as u8).collect();
+        let (boxed, flat) = (Quadtree::new(&plane), FlatQuadtree::new(&plane));
+        assert_eq!((flat.size(), flat.node_count()), (64, boxed.node_count()));
+        let rect = Rect { x: 5, y: 9, width: 40, height: 31 };
+        for cutoff in [0u8, 8, 40, 255].iter() {
+            let (mut expected, mut actual) = (vec![0; 40 * 31], vec![0; 40 * 31]);
+            boxed.get_rect(rect, *cutoff, &mut expected);
+            flat.get_rect(rect, *cutoff, &mut actual);
+            assert_eq!(actual, expected);
+            assert!((0..64 * 64).all(|i| flat.get_approx((i % 64, i / 64), *cutoff) == boxed.get_approx((i % 64, i / 64), *cutoff)));
+        }
+    }
+
+    #[test]
+    fn get_rect_agrees_with_per_pixel_lookups() {
+        let bitmap: Vec<u8> = (0..32 * 32).map(|i| ((i % 32) * 5 + (i / 32) * (i % 7)) as u8).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        let rect = Rect { x: 5, y: 3, width: 20, height: 27 };
+        for cutoff in [0u8, 9, 40, 255].iter() {
+            let mut out = vec![0u8; rect.width * rect.height];
+            quadtree.get_rect(rect, *cutoff, &mut out);
+            for (i, value) in out.iter().enumerate() {
+                assert_eq!(*value, quadtree.get_approx((rect.x + i % rect.width, rect.y + i / rect.width), *cutoff));
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn get_rect_outpaces_per_pixel_lookups() {
+        let bitmap: Vec<u8> = (0..1024 * 1024).map(|i| ((i % 1024) / 8 + (i / 1024) % 37) as u8).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        let start = std::time::Instant::now();
+        let mut out = vec![0u8; 1024 * 1024];
+        quadtree.get_rect(Rect { x: 0, y: 0, width: 1024, height: 1024 }, 6, &mut out);
+        let rect_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let per_pixel = Quadtree::from_fn(1024, |p| quadtree.get_approx(p, 6));
+        let pixel_time = start.elapsed();
+        println!("get_rect {:?}, per-pixel {:?}", rect_time, pixel_time);
+        assert_eq!(Quadtree::new(&out), per_pixel);
+        assert!(rect_time < pixel_time);
+    }
+
+    #[test]
+    fn stats_count_the_nodes_a_cutoff_keeps() {
+        let bitmap = vec![
+            1u8, 1u8, 255u8, 255u8,
+            1u8, 1u8, 255u8, 255u8,
+            3u8, 0u8, 4u8, 4u8,
+            0u8, 0u8, 4u8, 4u8
+        ];
+        let quadtree = Quadtree::new(&bitmap);
+        let stats = quadtree.stats(&3u8);
+        assert_eq!(stats, TreeStats { total_nodes: 5, collapsed_leaves: 3, real_leaves: 1, max_depth: 1, depth_histogram: vec![0, 4] });
+        assert_eq!(quadtree.stats(&0u8).real_leaves, 4);
+        let flat = Quadtree::new(&vec![7u8; 16]).stats(&1u8);
+        assert_eq!(flat, TreeStats { total_nodes: 1, collapsed_leaves: 1, real_leaves: 0, max_depth: 0, depth_histogram: vec![1] });
+        let combined = TreeStats::combined(&[stats, flat]);
+        assert_eq!((combined.total_nodes, combined.depth_histogram), (6, vec![1, 4]));
+    }
+
+    #[test]
+    fn leaves_walk_the_serialized_regions() {
+        let bitmap = vec![
+            1u8, 1u8, 255u8, 255u8,
+            1u8, 1u8, 255u8, 255u8,
+            3u8, 0u8, 4u8, 4u8,
+            0u8, 0u8, 4u8, 4u8
+        ];
+        let quadtree = Quadtree::new(&bitmap);
+        assert_eq!(quadtree.leaves(&3u8).collect::<Vec<_>>(), vec![
+            LeafRegion { x: 0, y: 0, size: 2, value: LeafValue::Average(1) },
+            LeafRegion { x: 2, y: 0, size: 2, value: LeafValue::Average(255) },
+            LeafRegion { x: 0, y: 2, size: 2, value: LeafValue::Pixels((3, 0, 0, 0)) },
+            LeafRegion { x: 2, y: 2, size: 2, value: LeafValue::Average(4) },
+        ]);
+        let faint: Vec<u8> = bitmap.iter().map(|v| *v.min(&9u8)).collect();
+        let faint = Quadtree::new(&faint);
+        let mut leaves = faint.leaves(&10u8);
+        assert!(matches!(leaves.next(), Some(LeafRegion { x: 0, y: 0, size: 4, value: LeafValue::Corners(_) })));
+        assert_eq!(leaves.next(), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn tree_round_trips_through_json() {
+        let bitmap = vec![
+            1u8, 1u8, 255u8, 255u8,
+            1u8, 1u8, 255u8, 255u8,
+            3u8, 0u8, 4u8, 4u8,
+            0u8, 0u8, 4u8, 4u8
+        ];
+        let quadtree = Quadtree::new(&bitmap);
+        let json = serde_json::to_string(&quadtree).unwrap();
+        assert!(json.contains("\"area_average\""));
+        let parsed: Box<Quadtree> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, quadtree);
+    }
 }
 
 fn average(a: u8, b: u8, c: u8, d: u8) -> u8 {
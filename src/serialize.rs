@@ -1,45 +1,1255 @@
 use bitvec::prelude::Local;
+use bitvec::slice::BitSlice;
 use bitvec::vec::BitVec;
-use crate::quadtree::{range, Quadtree};
+use image::ColorType;
+use crate::entropy::EntropyError;
+use crate::quadtree::{average_wide, branch_summary, interpolate, interpolate_wide, range, wide_cutoff, CutoffMap, Point, Quad, QuadMeta, Quadtree, Rect, Region, WideTree};
 
-pub fn build_leaf_index(quadtree: &Quadtree, quad_index: &mut BitVec<Local, u8>, cutoff: u8) {
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    UnexpectedEof,
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    UnknownColorSpace(u8),
+    OutOfBounds { offset: usize, len: usize, end: usize },
+    UnsupportedColorType(ColorType),
+    UnknownDitherMode(u8),
+    CutoffBelowStored { stored: (u8, u8, u8), requested: (u8, u8, u8) },
+    UnsupportedBitDepth(u8),
+    Io(std::io::ErrorKind),
+    ChecksumMismatch { stored: u32, computed: u32 },
+    InvalidDimensions { rank: u32, width: u32, height: u32 },
+    InvalidHuffmanTable,
+    InvalidResidualCode,
+    NoSuchTile { tx: u32, ty: u32 },
+    Entropy(EntropyError),
+}
+
+impl From<EntropyError> for DecodeError {
+    fn from(error: EntropyError) -> DecodeError {
+        return DecodeError::Entropy(error);
+    }
+}
+
+pub struct BoundedReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BoundedReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BoundedReader<'a> {
+        return BoundedReader { bytes, offset: 0 };
+    }
+
+    pub fn section(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.bytes.len();
+        if len > end - self.offset {
+            return Err(DecodeError::OutOfBounds { offset: self.offset, len, end });
+        }
+        self.offset += len;
+        return Ok(&self.bytes[self.offset-len..self.offset]);
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.offset..];
+        self.offset = self.bytes.len();
+        return rest;
+    }
+
+    pub fn blob(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.section(4)?;
+        return self.section(u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize);
+    }
+}
+
+pub const PACKED_LEAF_RANGE: u8 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LeafCoding {
+    pub packed: bool,
+    pub dead_zone: u8,
+    pub sibling_deltas: bool,
+}
+
+pub fn dead_zone(value: u8, band: u8) -> u8 {
+    if band <= 1 {
+        return value;
+    }
+    return (value / band * band).saturating_add(band / 2);
+}
+
+fn leaf_values(quadtree: &Quadtree, cutoff: &dyn CutoffMap, coding: LeafCoding, offset: Point) -> [u8; 4] {
+    let values = match quadtree {
+        Quadtree::Leaf(a, b, c, d) if !cutoff.collapses(offset, 2, range(a, b, c, d)) => [*a, *b, *c, *d],
+        _ => [quadtree.average(); 4],
+    };
+    return [
+        dead_zone(values[0], coding.dead_zone),
+        dead_zone(values[1], coding.dead_zone),
+        dead_zone(values[2], coding.dead_zone),
+        dead_zone(values[3], coding.dead_zone),
+    ];
+}
+
+fn packable(values: [u8; 4]) -> bool {
+    return range(&values[0], &values[1], &values[2], &values[3]) < PACKED_LEAF_RANGE;
+}
+
+fn pack_leaf(values: [u8; 4]) -> [u8; 3] {
+    let delta = |v: u8| -> u16 { return (v as i16 - values[0] as i16 + 16) as u16; };
+    let bits = delta(values[1]) << 10 | delta(values[2]) << 5 | delta(values[3]);
+    return [values[0], (bits >> 8) as u8, bits as u8];
+}
+
+fn unpack_leaf(first: u8, bits: u16) -> (u8, u8, u8, u8) {
+    let value = |shift: u16| -> u8 { return (first as i16 + (bits >> shift & 31) as i16 - 16) as u8; };
+    return (first, value(10), value(5), value(0));
+}
+
+pub fn build_leaf_index(quadtree: &Quadtree, quad_index: &mut BitVec<Local, u8>, cutoff: &dyn CutoffMap, coding: LeafCoding) {
+    index_node(quadtree, quad_index, cutoff, coding, (0, 0));
+}
+
+fn index_node(quadtree: &Quadtree, quad_index: &mut BitVec<Local, u8>, cutoff: &dyn CutoffMap, coding: LeafCoding, (x, y): Point) {
     match quadtree {
         Quadtree::Leaf(..) => {
-            quad_index.push(false);
+            quad_index.push(coding.packed && packable(leaf_values(quadtree, cutoff, coding, (x, y))));
         },
-        Quadtree::Branch(a, b, c, d, (a_val, b_val, c_val, d_val), _) => {
-            let contrast = range(a_val, b_val, c_val, d_val);
-            if contrast < cutoff {
+        Quadtree::Branch(a, b, c, d, _, meta) => {
+            let contrast = meta.high - meta.low;
+            if cutoff.collapses((x, y), quadtree.size(), contrast) {
                 quad_index.push(false);
             } else {
+                let s = quadtree.size() / 2;
                 quad_index.push(true);
-                build_leaf_index(a, quad_index, cutoff);
-                build_leaf_index(b, quad_index, cutoff);
-                build_leaf_index(c, quad_index, cutoff);
-                build_leaf_index(d, quad_index, cutoff);
+                index_node(a, quad_index, cutoff, coding, (x, y));
+                index_node(b, quad_index, cutoff, coding, (x+s, y));
+                index_node(c, quad_index, cutoff, coding, (x, y+s));
+                index_node(d, quad_index, cutoff, coding, (x+s, y+s));
             }
         },
     }
 }
 
-pub fn build_leaf_data(quadtree: &Quadtree, leaf_data: &mut Vec<u8>, cutoff: u8) {
+/// With `coding.sibling_deltas` every value is stored as the zig-zagged
+/// difference from its parent's average, and each split branch stores its
+/// own average the same way before its children.
+pub fn build_leaf_data(quadtree: &Quadtree, leaf_data: &mut Vec<u8>, cutoff: &dyn CutoffMap, coding: LeafCoding) {
+    data_node(quadtree, leaf_data, cutoff, coding, (0, 0), 128);
+}
+
+fn data_node(quadtree: &Quadtree, leaf_data: &mut Vec<u8>, cutoff: &dyn CutoffMap, coding: LeafCoding, (x, y): Point, parent: u8) {
+    let delta = |value: u8| if coding.sibling_deltas { zigzag(value.wrapping_sub(parent)) } else { value };
     match quadtree {
-        Quadtree::Leaf(a, b, c, d) => {
-            leaf_data.push(*a);
-            leaf_data.push(*b);
-            leaf_data.push(*c);
-            leaf_data.push(*d);
+        Quadtree::Leaf(..) => {
+            let values = leaf_values(quadtree, cutoff, coding, (x, y));
+            if coding.packed && packable(values) {
+                let packed = pack_leaf(values);
+                leaf_data.extend_from_slice(&[delta(packed[0]), packed[1], packed[2]]);
+            } else {
+                leaf_data.extend_from_slice(&values.map(delta));
+            }
         },
-        Quadtree::Branch(a, b, c, d, _, meta) => {
+        Quadtree::Branch(a, b, c, d, quad, meta) => {
             let contrast = meta.high - meta.low;
-            if contrast < cutoff {
-                leaf_data.push(quadtree.average());
+            if cutoff.collapses((x, y), quadtree.size(), contrast) {
+                leaf_data.extend_from_slice(&[delta(quad.0), delta(quad.1), delta(quad.2), delta(quad.3)]);
             } else {
-                build_leaf_data(a, leaf_data, cutoff);
-                build_leaf_data(b, leaf_data, cutoff);
-                build_leaf_data(c, leaf_data, cutoff);
-                build_leaf_data(d, leaf_data, cutoff);
+                let s = quadtree.size() / 2;
+                let average = quadtree.average();
+                if coding.sibling_deltas {
+                    leaf_data.push(delta(average));
+                }
+                data_node(a, leaf_data, cutoff, coding, (x, y), average);
+                data_node(b, leaf_data, cutoff, coding, (x+s, y), average);
+                data_node(c, leaf_data, cutoff, coding, (x, y+s), average);
+                data_node(d, leaf_data, cutoff, coding, (x+s, y+s), average);
             }
         },
     }
 }
+
+/// Turns leaf data written with `sibling_deltas` back into absolute values,
+/// dropping the branch averages.
+pub fn undo_sibling_deltas(index: &BitSlice<Local, u8>, deltas: &[u8], rank: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut leaf_data = Vec::with_capacity(deltas.len());
+    absolute_node(index, &mut 0, &mut deltas.iter().copied(), rank, 128, &mut leaf_data)?;
+    return Ok(leaf_data);
+}
+
+pub fn undo_sibling_deltas_lenient(index: &BitSlice<Local, u8>, deltas: &[u8], rank: usize) -> Vec<u8> {
+    let mut leaf_data = Vec::with_capacity(deltas.len());
+    let _ = absolute_node(index, &mut 0, &mut deltas.iter().copied(), rank, 128, &mut leaf_data);
+    return leaf_data;
+}
+
+fn absolute_node(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    deltas: &mut impl Iterator<Item=u8>,
+    size: usize,
+    parent: u8,
+    leaf_data: &mut Vec<u8>
+) -> Result<(), DecodeError> {
+    let branch = *index.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    let mut next = || deltas.next().ok_or(DecodeError::UnexpectedEof);
+    if size == 2 && branch {
+        let first = parent.wrapping_add(unzigzag(next()?));
+        leaf_data.extend_from_slice(&[first, next()?, next()?]);
+        return Ok(());
+    }
+    if size == 2 || !branch {
+        for _ in 0..4 {
+            leaf_data.push(parent.wrapping_add(unzigzag(next()?)));
+        }
+        return Ok(());
+    }
+    let average = parent.wrapping_add(unzigzag(next()?));
+    for _ in 0..4 {
+        absolute_node(index, position, deltas, size / 2, average, leaf_data)?;
+    }
+    return Ok(());
+}
+
+pub fn read_leaf_tree(
+    index: &BitSlice<Local, u8>,
+    data: &mut impl Iterator<Item=u8>,
+    rank: usize
+) -> Result<Box<Quadtree>, DecodeError> {
+    return read_node(index, &mut 0, data, rank);
+}
+
+fn read_node(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    size: usize
+) -> Result<Box<Quadtree>, DecodeError> {
+    let branch = *index.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    if size == 2 || !branch {
+        return read_terminal(branch, size, data);
+    }
+    let s = size / 2;
+    let a = read_node(index, position, data, s)?;
+    let b = read_node(index, position, data, s)?;
+    let c = read_node(index, position, data, s)?;
+    let d = read_node(index, position, data, s)?;
+    return Ok(Quadtree::join(a, b, c, d));
+}
+
+pub fn read_terminal(branch: bool, size: usize, data: &mut impl Iterator<Item=u8>) -> Result<Box<Quadtree>, DecodeError> {
+    if size == 2 && branch {
+        let mut next = || data.next().ok_or(DecodeError::UnexpectedEof);
+        let (first, high, low) = (next()?, next()?, next()?);
+        let (a, b, c, d) = unpack_leaf(first, (high as u16) << 8 | low as u16);
+        return Ok(Box::new(Quadtree::Leaf(a, b, c, d)));
+    }
+    if size == 2 {
+        let (a, b, c, d) = read_quad(data)?;
+        return Ok(Box::new(Quadtree::Leaf(a, b, c, d)));
+    }
+    let quad = read_quad(data)?;
+    return Ok(Quadtree::from_fn(size, |p| interpolate(quad, size, p)));
+}
+
+/// Re-serializes a stored tree at a `cutoff` no finer than the one it was
+/// written with, without expanding it to pixels: stored terminals are copied
+/// and stored branches whose decoded range now falls under the cutoff are
+/// collapsed to the corner quad `Quadtree::join` would give them.
+pub fn coarsen_leaf_tree(
+    index: &BitSlice<Local, u8>,
+    data: &mut impl Iterator<Item=u8>,
+    rank: usize,
+    cutoff: &dyn CutoffMap,
+    coding: LeafCoding
+) -> Result<(BitVec<Local, u8>, Vec<u8>), DecodeError> {
+    let root = read_stored(index, &mut 0, data, rank)?;
+    let mut quad_index = BitVec::with_capacity(index.len());
+    let mut leaf_data = vec![];
+    coarsen_node(&root, &mut quad_index, &mut leaf_data, cutoff, coding, (0, 0), 128);
+    return Ok((quad_index, leaf_data));
+}
+
+/// A stored node with the statistics of what it decodes to: `Block` is a
+/// collapsed branch and its stored quad, `Branch` carries the corner quad
+/// and meta `Quadtree::join` computes.
+enum StoredNode {
+    Leaf(Box<Quadtree>),
+    Block(Quad, QuadMeta),
+    Branch(Box<[StoredNode; 4]>, Quad, QuadMeta),
+}
+
+impl StoredNode {
+    fn low(&self) -> u8 {
+        return match self {
+            StoredNode::Leaf(leaf) => leaf.low(),
+            StoredNode::Block(_, meta) | StoredNode::Branch(_, _, meta) => meta.low,
+        };
+    }
+
+    fn high(&self) -> u8 {
+        return match self {
+            StoredNode::Leaf(leaf) => leaf.high(),
+            StoredNode::Block(_, meta) | StoredNode::Branch(_, _, meta) => meta.high,
+        };
+    }
+
+    fn sum(&self) -> u64 {
+        return match self {
+            StoredNode::Leaf(leaf) => leaf.sum(),
+            StoredNode::Block(_, meta) | StoredNode::Branch(_, _, meta) => meta.sum,
+        };
+    }
+
+    /// The decoded value at corner `i`, in `a b c d` order.
+    fn corner(&self, i: usize) -> u8 {
+        return match self {
+            StoredNode::Leaf(leaf) => leaf.get(corner(i, 2)),
+            StoredNode::Block(quad, meta) => interpolate(*quad, meta.size as usize, corner(i, meta.size as usize)),
+            StoredNode::Branch(children, _, _) => children[i].corner(i),
+        };
+    }
+}
+
+fn corner(i: usize, size: usize) -> Point {
+    return [(0, 0), (size - 1, 0), (0, size - 1), (size - 1, size - 1)][i];
+}
+
+fn read_stored(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    size: usize
+) -> Result<StoredNode, DecodeError> {
+    let branch = *index.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    if size == 2 {
+        return Ok(StoredNode::Leaf(read_terminal(branch, size, data)?));
+    }
+    if !branch {
+        let quad = read_quad(data)?;
+        let (mut low, mut high, mut sum) = (u8::MAX, 0, 0);
+        for i in 0..size*size {
+            let value = interpolate(quad, size, (i % size, i / size));
+            low = low.min(value);
+            high = high.max(value);
+            sum += value as u64;
+        }
+        let meta = QuadMeta { low, high, area_average: (sum / (size * size) as u64) as u8, size: size as u32, sum };
+        return Ok(StoredNode::Block(quad, meta));
+    }
+    let s = size / 2;
+    let children = [
+        read_stored(index, position, data, s)?,
+        read_stored(index, position, data, s)?,
+        read_stored(index, position, data, s)?,
+        read_stored(index, position, data, s)?,
+    ];
+    let low = children.iter().map(StoredNode::low).fold(u8::MAX, u8::min);
+    let high = children.iter().map(StoredNode::high).fold(0, u8::max);
+    let sum = children.iter().map(StoredNode::sum).sum();
+    let corners = (children[0].corner(0), children[1].corner(1), children[2].corner(2), children[3].corner(3));
+    let (quad, meta) = branch_summary(corners, low, high, sum, size as u32);
+    return Ok(StoredNode::Branch(Box::new(children), quad, meta));
+}
+
+fn coarsen_node(
+    node: &StoredNode,
+    quad_index: &mut BitVec<Local, u8>,
+    leaf_data: &mut Vec<u8>,
+    cutoff: &dyn CutoffMap,
+    coding: LeafCoding,
+    (x, y): Point,
+    parent: u8
+) {
+    let delta = |value: u8| if coding.sibling_deltas { zigzag(value.wrapping_sub(parent)) } else { value };
+    let collapses = |meta: &QuadMeta| cutoff.collapses((x, y), meta.size as usize, meta.high - meta.low);
+    match node {
+        StoredNode::Leaf(leaf) => {
+            index_node(leaf, quad_index, cutoff, coding, (x, y));
+            data_node(leaf, leaf_data, cutoff, coding, (x, y), parent);
+        },
+        StoredNode::Block(quad, meta) | StoredNode::Branch(_, quad, meta) if collapses(meta) => {
+            quad_index.push(false);
+            leaf_data.extend_from_slice(&[delta(quad.0), delta(quad.1), delta(quad.2), delta(quad.3)]);
+        },
+        StoredNode::Block(quad, meta) => {
+            let size = meta.size as usize;
+            let block = Quadtree::from_fn(size, |p| interpolate(*quad, size, p));
+            index_node(&block, quad_index, cutoff, coding, (x, y));
+            data_node(&block, leaf_data, cutoff, coding, (x, y), parent);
+        },
+        StoredNode::Branch(children, _, meta) => {
+            let s = meta.size as usize / 2;
+            quad_index.push(true);
+            if coding.sibling_deltas {
+                leaf_data.push(delta(meta.area_average));
+            }
+            for (child, (dx, dy)) in children.iter().zip([(0, 0), (s, 0), (0, s), (s, s)]) {
+                coarsen_node(child, quad_index, leaf_data, cutoff, coding, (x+dx, y+dy), meta.area_average);
+            }
+        },
+    }
+}
+
+/// Writes the pixels of every terminal node overlapping `rect` into `plane`,
+/// a `rect.width` x `rect.height` buffer, stepping over the other subtrees
+/// with `skip_subtree`.
+pub fn read_leaf_region(
+    index: &BitSlice<Local, u8>,
+    data: &mut impl Iterator<Item=u8>,
+    rank: usize,
+    rect: Rect,
+    plane: &mut [u8]
+) -> Result<(), DecodeError> {
+    return region_node(index, &mut 0, data, rank, (0, 0), rect, plane);
+}
+
+fn region_node(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    size: usize,
+    (x, y): Point,
+    rect: Rect,
+    plane: &mut [u8]
+) -> Result<(), DecodeError> {
+    if !rect.overlaps((x, y), size) {
+        return skip_subtree(index, position, data, size);
+    }
+    let branch = *index.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    if size == 2 || !branch {
+        let quad = if size == 2 {
+            let leaf = read_terminal(branch, size, data)?;
+            (leaf.get((0, 0)), leaf.get((1, 0)), leaf.get((0, 1)), leaf.get((1, 1)))
+        } else {
+            read_quad(data)?
+        };
+        for py in y.max(rect.y)..(y + size).min(rect.y + rect.height) {
+            for px in x.max(rect.x)..(x + size).min(rect.x + rect.width) {
+                plane[px - rect.x + (py - rect.y) * rect.width] = interpolate(quad, size, (px - x, py - y));
+            }
+        }
+        return Ok(());
+    }
+    let s = size / 2;
+    region_node(index, position, data, s, (x, y), rect, plane)?;
+    region_node(index, position, data, s, (x+s, y), rect, plane)?;
+    region_node(index, position, data, s, (x, y+s), rect, plane)?;
+    return region_node(index, position, data, s, (x+s, y+s), rect, plane);
+}
+
+/// Advances past one subtree's index bits and leaf bytes without building it.
+pub fn skip_subtree(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    size: usize
+) -> Result<(), DecodeError> {
+    let branch = *index.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    if size == 2 || !branch {
+        for _ in 0..node_bytes(branch, size) {
+            data.next().ok_or(DecodeError::UnexpectedEof)?;
+        }
+        return Ok(());
+    }
+    for _ in 0..4 {
+        skip_subtree(index, position, data, size / 2)?;
+    }
+    return Ok(());
+}
+
+/// One value per `pitch` x `pitch` block of a `rank` tree, in row-major
+/// order: the mean of the terminal nodes below each block, summed while
+/// stepping over them instead of building the tree.
+pub fn read_leaf_thumbnail(
+    index: &BitSlice<Local, u8>,
+    data: &mut impl Iterator<Item=u8>,
+    rank: usize,
+    pitch: usize
+) -> Result<Vec<u8>, DecodeError> {
+    let side = rank / pitch;
+    let mut plane = vec![0; side * side];
+    thumbnail_node(index, &mut 0, data, rank, (0, 0), pitch, &mut plane)?;
+    return Ok(plane);
+}
+
+fn thumbnail_node(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    size: usize,
+    (x, y): Point,
+    pitch: usize,
+    plane: &mut [u8]
+) -> Result<(), DecodeError> {
+    let side = (plane.len() as f32).sqrt() as usize;
+    if size <= pitch {
+        let sum = subtree_sum(index, position, data, size)?;
+        plane[x / pitch + y / pitch * side] = (sum / (size * size) as u64) as u8;
+        return Ok(());
+    }
+    let branch = *index.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    if !branch {
+        let quad = read_quad(data)?;
+        for by in (0..size).step_by(pitch) {
+            for bx in (0..size).step_by(pitch) {
+                let corners = [(bx, by), (bx + pitch - 1, by), (bx, by + pitch - 1), (bx + pitch - 1, by + pitch - 1)];
+                let sum: u16 = corners.iter().map(|p| interpolate(quad, size, *p) as u16).sum();
+                plane[(x + bx) / pitch + (y + by) / pitch * side] = (sum / 4) as u8;
+            }
+        }
+        return Ok(());
+    }
+    let s = size / 2;
+    thumbnail_node(index, position, data, s, (x, y), pitch, plane)?;
+    thumbnail_node(index, position, data, s, (x+s, y), pitch, plane)?;
+    thumbnail_node(index, position, data, s, (x, y+s), pitch, plane)?;
+    return thumbnail_node(index, position, data, s, (x+s, y+s), pitch, plane);
+}
+
+fn subtree_sum(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    size: usize
+) -> Result<u64, DecodeError> {
+    let branch = *index.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    if size == 2 {
+        let leaf = read_terminal(branch, size, data)?;
+        return Ok(leaf.sum());
+    }
+    if !branch {
+        let (a, b, c, d) = read_quad(data)?;
+        return Ok((a as u64 + b as u64 + c as u64 + d as u64) * (size * size / 4) as u64);
+    }
+    let mut sum = 0;
+    for _ in 0..4 {
+        sum += subtree_sum(index, position, data, size / 2)?;
+    }
+    return Ok(sum);
+}
+
+pub fn build_wide_leaf_index(tree: &WideTree, quad_index: &mut BitVec<Local, u8>, cutoff: &dyn CutoffMap) {
+    wide_index_node(tree, quad_index, cutoff, (0, 0));
+}
+
+fn wide_index_node(tree: &WideTree, quad_index: &mut BitVec<Local, u8>, cutoff: &dyn CutoffMap, (x, y): Point) {
+    match tree {
+        WideTree::Leaf(..) => quad_index.push(false),
+        WideTree::Branch(a, b, c, d, _, meta) => {
+            if meta.high - meta.low < wide_cutoff(cutoff.cutoff_at((x, y), tree.size())) {
+                quad_index.push(false);
+            } else {
+                let s = tree.size() / 2;
+                quad_index.push(true);
+                wide_index_node(a, quad_index, cutoff, (x, y));
+                wide_index_node(b, quad_index, cutoff, (x+s, y));
+                wide_index_node(c, quad_index, cutoff, (x, y+s));
+                wide_index_node(d, quad_index, cutoff, (x+s, y+s));
+            }
+        },
+    }
+}
+
+/// Like `build_leaf_data`, with every value written as two little-endian bytes.
+pub fn build_wide_leaf_data(tree: &WideTree, leaf_data: &mut Vec<u8>, cutoff: &dyn CutoffMap) {
+    wide_data_node(tree, leaf_data, cutoff, (0, 0));
+}
+
+fn wide_data_node(tree: &WideTree, leaf_data: &mut Vec<u8>, cutoff: &dyn CutoffMap, (x, y): Point) {
+    let collapsed = tree.high() - tree.low() < wide_cutoff(cutoff.cutoff_at((x, y), tree.size()));
+    let values = match tree {
+        WideTree::Leaf(a, b, c, d) if collapsed => [average_wide(*a, *b, *c, *d); 4],
+        WideTree::Leaf(a, b, c, d) => [*a, *b, *c, *d],
+        WideTree::Branch(_, _, _, _, quad, _) if collapsed => [quad.0, quad.1, quad.2, quad.3],
+        WideTree::Branch(a, b, c, d, _, _) => {
+            let s = tree.size() / 2;
+            wide_data_node(a, leaf_data, cutoff, (x, y));
+            wide_data_node(b, leaf_data, cutoff, (x+s, y));
+            wide_data_node(c, leaf_data, cutoff, (x, y+s));
+            wide_data_node(d, leaf_data, cutoff, (x+s, y+s));
+            return;
+        },
+    };
+    for value in values.iter() {
+        leaf_data.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+pub fn read_wide_leaf_tree(
+    index: &BitSlice<Local, u8>,
+    data: &mut impl Iterator<Item=u8>,
+    rank: usize
+) -> Result<Box<WideTree>, DecodeError> {
+    return read_wide_node(index, &mut 0, data, rank);
+}
+
+fn read_wide_node(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    size: usize
+) -> Result<Box<WideTree>, DecodeError> {
+    let branch = *index.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+    *position += 1;
+    if size == 2 || !branch {
+        let mut next = || -> Result<u16, DecodeError> {
+            let low = data.next().ok_or(DecodeError::UnexpectedEof)?;
+            let high = data.next().ok_or(DecodeError::UnexpectedEof)?;
+            return Ok(u16::from_le_bytes([low, high]));
+        };
+        let quad = (next()?, next()?, next()?, next()?);
+        if size == 2 {
+            return Ok(Box::new(WideTree::Leaf(quad.0, quad.1, quad.2, quad.3)));
+        }
+        return Ok(WideTree::from_fn(size, |p| interpolate_wide(quad, size, p)));
+    }
+    let s = size / 2;
+    let a = read_wide_node(index, position, data, s)?;
+    let b = read_wide_node(index, position, data, s)?;
+    let c = read_wide_node(index, position, data, s)?;
+    let d = read_wide_node(index, position, data, s)?;
+    return Ok(WideTree::join(a, b, c, d));
+}
+
+pub fn salvage_leaf_tree(
+    index: &BitSlice<Local, u8>,
+    data: &mut impl Iterator<Item=u8>,
+    rank: usize
+) -> (Box<Quadtree>, Vec<Region>) {
+    let mut damaged = vec![];
+    let tree = salvage_node(index, &mut 0, data, (0, 0), rank, &mut 128, &mut damaged);
+    return (tree, damaged);
+}
+
+fn salvage_node(
+    index: &BitSlice<Local, u8>,
+    position: &mut usize,
+    data: &mut impl Iterator<Item=u8>,
+    (x, y): Point,
+    size: usize,
+    last: &mut u8,
+    damaged: &mut Vec<Region>
+) -> Box<Quadtree> {
+    let branch = index.get(*position).cloned();
+    *position += 1;
+    let node = match branch {
+        Some(true) if size > 2 => {
+            let s = size / 2;
+            let a = salvage_node(index, position, data, (x, y), s, last, damaged);
+            let b = salvage_node(index, position, data, (x+s, y), s, last, damaged);
+            let c = salvage_node(index, position, data, (x, y+s), s, last, damaged);
+            let d = salvage_node(index, position, data, (x+s, y+s), s, last, damaged);
+            return Quadtree::join(a, b, c, d);
+        },
+        Some(branch) => read_terminal(branch, size, data).ok(),
+        None => None,
+    };
+    return match node {
+        Some(node) => {
+            *last = node.average();
+            node
+        },
+        None => {
+            damaged.push(Region { x, y, size });
+            let fill = *last;
+            Quadtree::from_fn(size, |_| fill)
+        },
+    };
+}
+
+fn node_bytes(branch: bool, size: usize) -> usize {
+    return match (size, branch) {
+        (2, true) => 3,
+        (_, true) => 0,
+        _ => 4,
+    };
+}
+
+pub fn join_stream(index: &BitSlice<Local, u8>, data: &[u8], rank: usize) -> Vec<u8> {
+    let mut stream: BitVec<Local, u8> = BitVec::with_capacity(index.len() + data.len() * 8);
+    stream_node(index, data, (&mut 0, &mut 0), rank, &mut stream);
+    return stream.into_vec();
+}
+
+fn stream_node(
+    index: &BitSlice<Local, u8>,
+    data: &[u8],
+    (position, at): (&mut usize, &mut usize),
+    size: usize,
+    stream: &mut BitVec<Local, u8>
+) {
+    let branch = index[*position];
+    *position += 1;
+    stream.push(branch);
+    let n = node_bytes(branch, size);
+    for byte in data[*at..*at + n].iter() {
+        for bit in (0..8).rev() {
+            stream.push(byte >> bit & 1 == 1);
+        }
+    }
+    *at += n;
+    if branch && size > 2 {
+        for _ in 0..4 {
+            stream_node(index, data, (&mut *position, &mut *at), size / 2, stream);
+        }
+    }
+}
+
+pub fn split_stream(bytes: &[u8], rank: usize) -> Result<(BitVec<Local, u8>, Vec<u8>), DecodeError> {
+    let stream = BitSlice::<Local, u8>::from_slice(bytes);
+    let mut index: BitVec<Local, u8> = BitVec::new();
+    let mut data = vec![];
+    split_node(stream, &mut 0, rank, &mut index, &mut data)?;
+    return Ok((index, data));
+}
+
+pub fn split_stream_lenient(bytes: &[u8], rank: usize) -> (BitVec<Local, u8>, Vec<u8>) {
+    let stream = BitSlice::<Local, u8>::from_slice(bytes);
+    let mut index: BitVec<Local, u8> = BitVec::new();
+    let mut data = vec![];
+    let _ = split_node(stream, &mut 0, rank, &mut index, &mut data);
+    return (index, data);
+}
+
+fn split_node(
+    stream: &BitSlice<Local, u8>,
+    position: &mut usize,
+    size: usize,
+    index: &mut BitVec<Local, u8>,
+    data: &mut Vec<u8>
+) -> Result<(), DecodeError> {
+    let mut next = || -> Result<bool, DecodeError> {
+        let bit = *stream.get(*position).ok_or(DecodeError::UnexpectedEof)?;
+        *position += 1;
+        return Ok(bit);
+    };
+    let branch = next()?;
+    index.push(branch);
+    for _ in 0..node_bytes(branch, size) {
+        let mut byte = 0u8;
+        for _ in 0..8 {
+            byte = byte << 1 | next()? as u8;
+        }
+        data.push(byte);
+    }
+    if branch && size > 2 {
+        for _ in 0..4 {
+            split_node(stream, position, size / 2, index, data)?;
+        }
+    }
+    return Ok(());
+}
+
+fn read_quad(data: &mut impl Iterator<Item=u8>) -> Result<(u8, u8, u8, u8), DecodeError> {
+    let mut next = || data.next().ok_or(DecodeError::UnexpectedEof);
+    return Ok((next()?, next()?, next()?, next()?));
+}
+
+pub fn planarize(leaf_data: &[u8]) -> Vec<u8> {
+    return (0..4).flat_map(|corner| leaf_data.iter().skip(corner).step_by(4).cloned()).collect();
+}
+
+pub fn interleave(planar: &[u8]) -> Vec<u8> {
+    let quads = planar.len() / 4;
+    return (0..quads * 4).map(|i| planar[(i % 4) * quads + i / 4]).collect();
+}
+
+fn zigzag(delta: u8) -> u8 {
+    let signed = delta as i8;
+    return ((signed << 1) ^ (signed >> 7)) as u8;
+}
+
+fn unzigzag(code: u8) -> u8 {
+    return (code >> 1) ^ (code & 1).wrapping_neg();
+}
+
+pub fn residuals(leaf_data: &[u8]) -> Vec<u8> {
+    let mut previous = 0u8;
+    return leaf_data.iter().map(|value| {
+        let delta = value.wrapping_sub(previous);
+        previous = *value;
+        return zigzag(delta);
+    }).collect();
+}
+
+pub fn encode_residuals(leaf_data: &[u8]) -> Vec<u8> {
+    let mut bits: BitVec<Local, u8> = BitVec::with_capacity(leaf_data.len() * 2);
+    for code in residuals(leaf_data) {
+        let value = code as u16 + 1;
+        let width = 16 - value.leading_zeros() as usize;
+        for _ in 1..width {
+            bits.push(false);
+        }
+        for bit in (0..width).rev() {
+            bits.push(value >> bit & 1 == 1);
+        }
+    }
+    return bits.into_vec();
+}
+
+/// Codes are at most 9 bits wide, since they store a byte plus one; a wider
+/// one can only come from a corrupt section.
+pub fn decode_residuals(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let bits = BitSlice::<Local, u8>::from_slice(bytes);
+    let mut leaf_data = vec![];
+    let mut previous = 0u8;
+    let mut pos = 0;
+    while let Some(width) = bits[pos..].iter().position(|bit| *bit).map(|zeros| zeros + 1) {
+        if width > 9 {
+            return Err(DecodeError::InvalidResidualCode);
+        }
+        if pos + 2 * width - 1 > bits.len() {
+            break;
+        }
+        pos += width - 1;
+        let value = bits[pos..pos + width].iter().fold(0u16, |value, bit| value << 1 | *bit as u16);
+        pos += width;
+        if value > 256 {
+            return Err(DecodeError::InvalidResidualCode);
+        }
+        previous = previous.wrapping_add(unzigzag((value - 1) as u8));
+        leaf_data.push(previous);
+    }
+    return Ok(leaf_data);
+}
+
+/// Stores each value as a fixed-width offset from the smallest one, after a
+/// prefix of the low value, the bit width and the u32 value count.
+pub fn pack_values(values: &[u8]) -> Vec<u8> {
+    let low = values.iter().min().cloned().unwrap_or(0);
+    let high = values.iter().max().cloned().unwrap_or(0);
+    let width = 8 - (high - low).leading_zeros() as usize;
+    let mut bits: BitVec<Local, u8> = BitVec::with_capacity(values.len() * width);
+    for value in values.iter() {
+        for bit in (0..width).rev() {
+            bits.push((value - low) >> bit & 1 == 1);
+        }
+    }
+    return [&[low, width as u8][..], &(values.len() as u32).to_le_bytes(), &bits.into_vec()].concat();
+}
+
+pub fn unpack_values(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut reader = BoundedReader::new(bytes);
+    let prefix = reader.section(6)?;
+    let (low, width) = (prefix[0], (prefix[1] as usize).min(8));
+    let count = u32::from_le_bytes([prefix[2], prefix[3], prefix[4], prefix[5]]) as usize;
+    let bits = BitSlice::<Local, u8>::from_slice(reader.rest());
+    if bits.len() < count * width {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    return Ok((0..count).map(|i| {
+        let offset = bits[i * width..(i + 1) * width].iter().fold(0u8, |value, bit| value << 1 | *bit as u8);
+        return low.wrapping_add(offset);
+    }).collect());
+}
+
+fn push_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 128 {
+        bytes.push(value as u8 | 128);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint<'a>(bytes: &mut impl Iterator<Item=&'a u8>) -> Result<Option<usize>, DecodeError> {
+    let (mut value, mut shift) = (0usize, 0);
+    for byte in bytes.by_ref() {
+        if shift >= usize::BITS {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        value |= ((byte & 127) as usize) << shift;
+        if byte & 128 == 0 {
+            return Ok(Some(value));
+        }
+        shift += 7;
+    }
+    return if shift == 0 { Ok(None) } else { Err(DecodeError::UnexpectedEof) };
+}
+
+/// Codes an index as varint run lengths alternating between runs of leaves
+/// and runs of branches, starting with a (possibly empty) run of leaves.
+pub fn rle_encode_index(bits: &BitSlice<Local, u8>) -> Vec<u8> {
+    let mut bytes = vec![];
+    let (mut current, mut run) = (false, 0);
+    for bit in bits.iter() {
+        if *bit != current {
+            push_varint(&mut bytes, run);
+            current = !current;
+            run = 0;
+        }
+        run += 1;
+    }
+    push_varint(&mut bytes, run);
+    return bytes;
+}
+
+pub fn rle_decode_index(bytes: &[u8]) -> Result<BitVec<Local, u8>, DecodeError> {
+    let mut bits: BitVec<Local, u8> = BitVec::new();
+    let mut bytes = bytes.iter();
+    let mut value = false;
+    while let Some(run) = read_varint(&mut bytes)? {
+        for _ in 0..run {
+            bits.push(value);
+        }
+        value = !value;
+    }
+    return Ok(bits);
+}
+
+/// Canonical Huffman code lengths, one `(symbol, length)` pair per byte
+/// value that occurs; the codes themselves follow from the lengths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HuffTable {
+    pub lengths: Vec<(u8, u8)>,
+}
+
+impl HuffTable {
+    pub fn from_data(data: &[u8]) -> HuffTable {
+        let mut counts = [0u64; 256];
+        for value in data.iter() {
+            counts[*value as usize] += 1;
+        }
+        let mut nodes: Vec<(u64, Vec<u8>)> = (0..256)
+            .filter(|symbol| counts[*symbol] > 0)
+            .map(|symbol| (counts[symbol], vec![symbol as u8]))
+            .collect();
+        let mut lengths = [0u8; 256];
+        if nodes.len() == 1 {
+            lengths[nodes[0].1[0] as usize] = 1;
+        }
+        while nodes.len() > 1 {
+            nodes.sort_by_key(|node| std::cmp::Reverse(node.0));
+            let (low_count, low) = nodes.pop().unwrap();
+            let (next_count, next) = nodes.pop().unwrap();
+            for symbol in low.iter().chain(next.iter()) {
+                lengths[*symbol as usize] += 1;
+            }
+            nodes.push((low_count + next_count, [low, next].concat()));
+        }
+        let mut table: Vec<(u8, u8)> = (0..256).filter(|s| lengths[*s] > 0).map(|s| (s as u8, lengths[s])).collect();
+        table.sort_by_key(|(symbol, length)| (*length, *symbol));
+        return HuffTable { lengths: table };
+    }
+
+    fn codes(&self) -> Vec<(u8, u8, u64)> {
+        let mut code = 0u64;
+        let mut previous = 0;
+        return self.lengths.iter().map(|(symbol, length)| {
+            code = code.checked_shl((length - previous) as u32).unwrap_or(0);
+            previous = *length;
+            code = code.wrapping_add(1);
+            return (*symbol, *length, code.wrapping_sub(1));
+        }).collect();
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.lengths.len() as u16).to_le_bytes().to_vec();
+        for (symbol, length) in self.lengths.iter() {
+            bytes.extend_from_slice(&[*symbol, *length]);
+        }
+        return bytes;
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<(HuffTable, &[u8]), DecodeError> {
+        if bytes.len() < 2 {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let count = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        if bytes.len() < 2 + 2 * count {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let lengths: Vec<(u8, u8)> = bytes[2..2 + 2 * count].chunks(2).map(|pair| (pair[0], pair[1])).collect();
+        let sorted = lengths.windows(2).all(|pair| (pair[0].1, pair[0].0) < (pair[1].1, pair[1].0));
+        let bounded = lengths.iter().all(|(_, length)| (1..=64).contains(length));
+        if !sorted || !bounded || lengths.iter().map(|(_, length)| 1u128 << (64 - length)).sum::<u128>() > 1 << 64 {
+            return Err(DecodeError::InvalidHuffmanTable);
+        }
+        return Ok((HuffTable { lengths }, &bytes[2 + 2 * count..]));
+    }
+}
+
+/// Codes `data` with a Huffman table built from its own byte frequencies,
+/// returning a u32 value count and the code bits, plus the table.
+pub fn huffman_encode(data: &[u8]) -> (Vec<u8>, HuffTable) {
+    let table = HuffTable::from_data(data);
+    let mut codes = [(0u8, 0u64); 256];
+    for (symbol, length, code) in table.codes() {
+        codes[symbol as usize] = (length, code);
+    }
+    let mut bits: BitVec<Local, u8> = BitVec::new();
+    for value in data.iter() {
+        let (length, code) = codes[*value as usize];
+        for bit in (0..length).rev() {
+            bits.push(code >> bit & 1 == 1);
+        }
+    }
+    return ([&(data.len() as u32).to_le_bytes()[..], &bits.into_vec()].concat(), table);
+}
+
+pub fn huffman_decode(bytes: &[u8], table: &HuffTable) -> Result<Vec<u8>, DecodeError> {
+    if bytes.len() < 4 {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let count = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let codes = table.codes();
+    let longest = codes.last().map_or(0, |entry| entry.1) as usize;
+    let mut starts = vec![(0u64, 0usize, 0u64); longest + 1];
+    for (i, (_, length, code)) in codes.iter().enumerate().rev() {
+        let start = &mut starts[*length as usize];
+        *start = (*code, i, start.2 + 1);
+    }
+    let bits = BitSlice::<Local, u8>::from_slice(&bytes[4..]);
+    let mut values = Vec::with_capacity(count.min(bits.len()));
+    let mut position = 0;
+    while values.len() < count {
+        let (mut code, mut length) = (0u64, 0);
+        loop {
+            code = code << 1 | *bits.get(position).ok_or(DecodeError::UnexpectedEof)? as u64;
+            position += 1;
+            length += 1;
+            if length > longest {
+                return Err(DecodeError::UnexpectedEof);
+            }
+            let (first, index, n) = starts[length];
+            if code >= first && code - first < n {
+                values.push(codes[index + (code - first) as usize].0);
+                break;
+            }
+        }
+    }
+    return Ok(values);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quadtree::LeafValue;
+    use super::*;
+
+    #[test]
+    fn four_by_four_tree_round_trips_at_each_cutoff() {
+        let bitmap = vec![
+            1u8, 1u8, 255u8, 255u8,
+            1u8, 1u8, 255u8, 255u8,
+            3u8, 0u8, 4u8, 4u8,
+            0u8, 0u8, 4u8, 4u8
+        ];
+        let quadtree = Quadtree::new(&bitmap);
+        for cutoff in [0u8, 2, 5, 255].iter() {
+            let mut index: BitVec<Local, u8> = BitVec::new();
+            let mut data = vec![];
+            build_leaf_index(&quadtree, &mut index, cutoff, LeafCoding::default());
+            build_leaf_data(&quadtree, &mut data, cutoff, LeafCoding::default());
+            let decoded = read_leaf_tree(&index, &mut data.into_iter(), 4).unwrap();
+            for i in 0..16 {
+                let p = (i % 4, i / 4);
+                assert_eq!(decoded.get(p), quadtree.get_approx(p, *cutoff));
+            }
+        }
+    }
+
+    #[test]
+    fn planarize_groups_corners_and_interleave_inverts_it() {
+        let data = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(planarize(&data), vec![1, 5, 2, 6, 3, 7, 4, 8]);
+        assert_eq!(interleave(&planarize(&data)), data);
+    }
+
+    #[test]
+    fn residual_code_round_trips() {
+        let data: Vec<u8> = vec![0, 1, 255, 128, 127, 127, 3, 200, 0, 0];
+        assert_eq!(unzigzag(zigzag(128)), 128);
+        assert_eq!(residuals(&[5, 3]), vec![10, 3]);
+        assert_eq!(decode_residuals(&encode_residuals(&data)), Ok(data));
+        assert_eq!(decode_residuals(&encode_residuals(&[])), Ok(vec![]));
+        assert_eq!(decode_residuals(&[0, 0, 1, 0, 0]), Err(DecodeError::InvalidResidualCode));
+        assert_eq!(decode_residuals(&[0, 1, 1]), Err(DecodeError::InvalidResidualCode));
+    }
+
+    #[test]
+    fn variable_length_residuals_are_smaller_than_fixed_bytes() {
+        let smooth: Vec<u8> = (0..4096).map(|i| (100 + (i / 64) % 7 + i % 3) as u8).collect();
+        let fixed = residuals(&smooth);
+        let coded = encode_residuals(&smooth);
+        assert_eq!(fixed.len(), smooth.len());
+        assert!(coded.len() * 2 < fixed.len());
+        assert_eq!(decode_residuals(&coded), Ok(smooth));
+    }
+
+    #[test]
+    fn bounded_reader_refuses_to_cross_the_end() {
+        let mut reader = BoundedReader::new(&[1, 2, 3, 4, 5]);
+        assert_eq!(reader.section(2), Ok(&[1u8, 2][..]));
+        assert_eq!(reader.section(4), Err(DecodeError::OutOfBounds { offset: 2, len: 4, end: 5 }));
+        assert_eq!(reader.section(3), Ok(&[3u8, 4, 5][..]));
+    }
+
+    #[test]
+    fn near_flat_leaves_pack_into_three_bytes() {
+        let plane: Vec<u8> = (0..16 * 16).map(|i| (100 + (i * 7) % 13 + if i % 16 < 8 { 0 } else { 90 }) as u8).collect();
+        let quadtree = Quadtree::new(&plane);
+        let (mut index, mut packed_index): (BitVec<Local, u8>, BitVec<Local, u8>) = (BitVec::new(), BitVec::new());
+        let (mut data, mut packed_data) = (vec![], vec![]);
+        build_leaf_index(&quadtree, &mut index, &0u8, LeafCoding::default());
+        build_leaf_data(&quadtree, &mut data, &0u8, LeafCoding::default());
+        build_leaf_index(&quadtree, &mut packed_index, &0u8, LeafCoding { packed: true, ..LeafCoding::default() });
+        build_leaf_data(&quadtree, &mut packed_data, &0u8, LeafCoding { packed: true, ..LeafCoding::default() });
+        assert_eq!(packed_data.len() * 4, data.len() * 3);
+        let unpacked = read_leaf_tree(&index, &mut data.into_iter(), 16).unwrap();
+        let packed = read_leaf_tree(&packed_index, &mut packed_data.into_iter(), 16).unwrap();
+        for (i, value) in plane.iter().enumerate() {
+            let p = (i % 16, i / 16);
+            assert_eq!(packed.get(p), *value);
+            assert_eq!(unpacked.get(p), *value);
+        }
+        assert_eq!(unpack_leaf(200, 0b0_00000_11111_10000), (200, 184, 215, 200));
+    }
+
+    #[test]
+    fn dead_zone_snaps_to_band_center() {
+        assert_eq!(dead_zone(17, 0), 17);
+        assert_eq!(dead_zone(17, 8), 20);
+        assert_eq!(dead_zone(23, 8), 20);
+        assert_eq!(dead_zone(254, 8), 252);
+        assert_eq!(dead_zone(255, 6), 255);
+    }
+
+    #[test]
+    fn single_stream_splits_back_into_index_and_data() {
+        let plane: Vec<u8> = (0..8 * 8).map(|i| ((i % 8) * 30 + (i / 8) * (i % 3)) as u8).collect();
+        let quadtree = Quadtree::new(&plane);
+        let coding = LeafCoding { packed: true, ..LeafCoding::default() };
+        let mut index: BitVec<Local, u8> = BitVec::new();
+        let mut data = vec![];
+        build_leaf_index(&quadtree, &mut index, &20u8, coding);
+        build_leaf_data(&quadtree, &mut data, &20u8, coding);
+        let stream = join_stream(&index, &data, 8);
+        assert_eq!(stream.len(), (index.len() + data.len() * 8).div_ceil(8));
+        let (split_index, split_data) = split_stream(&stream, 8).unwrap();
+        assert_eq!(split_index, index);
+        assert_eq!(split_data, data);
+        assert_eq!(split_stream(&stream[..1], 8).map(|_| ()), Err(DecodeError::UnexpectedEof));
+    }
+
+    #[test]
+    fn packed_values_use_the_narrowest_width() {
+        let values = vec![120u8, 121, 127, 124, 120, 123];
+        let packed = pack_values(&values);
+        assert_eq!(packed[..2], [120, 3]);
+        assert_eq!(packed.len(), 6 + 3);
+        assert_eq!(unpack_values(&packed), Ok(values));
+        assert_eq!(pack_values(&[9u8; 40]).len(), 6);
+        assert_eq!(unpack_values(&pack_values(&[9u8; 40])), Ok(vec![9u8; 40]));
+        assert_eq!(unpack_values(&pack_values(&[0u8, 255])), Ok(vec![0u8, 255]));
+        assert_eq!(unpack_values(&packed[..7]), Err(DecodeError::UnexpectedEof));
+    }
+
+    #[test]
+    fn sibling_deltas_round_trip() {
+        let plane: Vec<u8> = (0..256).map(|i| (i % 16 * 9 + i / 16 * 5) as u8).collect();
+        let quadtree = Quadtree::new(&plane);
+        for packed in [false, true].iter() {
+            let coding = LeafCoding { packed: *packed, ..LeafCoding::default() };
+            let mut index: BitVec<Local, u8> = BitVec::new();
+            let (mut absolute, mut deltas) = (vec![], vec![]);
+            build_leaf_index(&quadtree, &mut index, &6u8, coding);
+            build_leaf_data(&quadtree, &mut absolute, &6u8, coding);
+            build_leaf_data(&quadtree, &mut deltas, &6u8, LeafCoding { sibling_deltas: true, ..coding });
+            assert_ne!(deltas, absolute);
+            assert_eq!(undo_sibling_deltas(&index, &deltas, 16).unwrap(), absolute);
+            assert_eq!(undo_sibling_deltas(&index, &deltas[..deltas.len() - 1], 16), Err(DecodeError::UnexpectedEof));
+        }
+    }
+
+    #[test]
+    fn coarsened_trees_match_a_fresh_build_and_copy_stored_terminals() {
+        let plane: Vec<u8> = (0..256).map(|i| (i % 16 * 9 + i / 16 * (i % 5)) as u8).collect();
+        let quadtree = Quadtree::new(&plane);
+        let write = |cutoff: u8, coding: LeafCoding| {
+            let (mut index, mut data): (BitVec<Local, u8>, _) = (BitVec::new(), vec![]);
+            build_leaf_index(&quadtree, &mut index, &cutoff, coding);
+            build_leaf_data(&quadtree, &mut data, &cutoff, coding);
+            return (index, data);
+        };
+        let (index, data) = write(0, LeafCoding::default());
+        for coding in [LeafCoding::default(), LeafCoding { packed: true, ..LeafCoding::default() }, LeafCoding { sibling_deltas: true, ..LeafCoding::default() }].iter() {
+            assert_eq!(coarsen_leaf_tree(&index, &mut data.iter().copied(), 16, &6u8, *coding).unwrap(), write(6, *coding));
+        }
+        let (index, data) = write(6, LeafCoding::default());
+        assert_eq!(coarsen_leaf_tree(&index, &mut data.iter().copied(), 16, &6u8, LeafCoding::default()).unwrap(), (index.clone(), data.clone()));
+        let (coarse_index, _) = coarsen_leaf_tree(&index, &mut data.iter().copied(), 16, &40u8, LeafCoding::default()).unwrap();
+        assert!(coarse_index.len() < index.len());
+        assert_eq!(coarsen_leaf_tree(&index, &mut data[1..].iter().copied(), 16, &6u8, LeafCoding::default()), Err(DecodeError::UnexpectedEof));
+    }
+
+    #[test]
+    fn huffman_codes_round_trip() {
+        let data: Vec<u8> = (0..5000u32).map(|i| [7u8, 7, 7, 7, 9, 9, 200, (i % 251) as u8][(i * 31 % 8) as usize]).collect();
+        let (bits, table) = huffman_encode(&data);
+        assert!(bits.len() < data.len() / 2);
+        let (read, rest) = HuffTable::read(&[table.to_bytes(), vec![1]].concat()).map(|(t, r)| (t, r.to_vec())).unwrap();
+        assert_eq!((&read, rest), (&table, vec![1]));
+        assert_eq!(huffman_decode(&bits, &table).unwrap(), data);
+        let (single, table) = huffman_encode(&[5; 9]);
+        assert_eq!(huffman_decode(&single, &table).unwrap(), vec![5; 9]);
+        assert_eq!(huffman_decode(&bits[..bits.len() / 2], &HuffTable::from_data(&data)), Err(DecodeError::UnexpectedEof));
+    }
+
+    #[test]
+    fn huffman_tables_are_checked_on_read() {
+        let table = |pairs: &[(u8, u8)]| -> Vec<u8> { return HuffTable { lengths: pairs.to_vec() }.to_bytes(); };
+        assert!(HuffTable::read(&table(&[(3, 1), (1, 2), (2, 2)])).is_ok());
+        let (deep, _) = HuffTable::read(&table(&[(9, 64)])).unwrap();
+        assert_eq!(huffman_decode(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &deep), Ok(vec![9]));
+        for pairs in [vec![(1, 2), (2, 2), (3, 1)], vec![(1, 1), (1, 1)], vec![(1, 0)], vec![(1, 65)], vec![(1, 1), (2, 1), (3, 2)]].iter() {
+            assert_eq!(HuffTable::read(&table(pairs)).map(|_| ()), Err(DecodeError::InvalidHuffmanTable), "{:?}", pairs);
+        }
+        let data: Vec<u8> = (0..600u32).map(|i| (i * i % 7) as u8).collect();
+        let (bits, table) = huffman_encode(&data);
+        let section = [table.to_bytes(), bits].concat();
+        for i in 0..section.len() {
+            for flip in [1u8, 0x80, 0xFF].iter() {
+                let mut corrupt = section.clone();
+                corrupt[i] ^= flip;
+                if let Ok((table, rest)) = HuffTable::read(&corrupt) {
+                    let _ = huffman_decode(rest, &table);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn index_runs_round_trip() {
+        let mut bits: BitVec<Local, u8> = BitVec::new();
+        for (value, run) in [(true, 3), (false, 300), (true, 1), (false, 2)].iter() {
+            for _ in 0..*run {
+                bits.push(*value);
+            }
+        }
+        let encoded = rle_encode_index(&bits);
+        assert_eq!(encoded, vec![0, 3, 172, 2, 1, 2]);
+        assert_eq!(rle_decode_index(&encoded), Ok(bits));
+        assert_eq!(rle_decode_index(&[]), Ok(BitVec::new()));
+        assert_eq!(rle_decode_index(&[0, 172]), Err(DecodeError::UnexpectedEof));
+    }
+
+    #[test]
+    fn leaf_iterator_matches_the_data_section() {
+        let bitmap: Vec<u8> = (0..16 * 16).map(|i| ((i % 16) * 9 + (i / 16) * (i % 5)) as u8).collect();
+        let quadtree = Quadtree::new(&bitmap);
+        let mut data = vec![];
+        build_leaf_data(&quadtree, &mut data, &12u8, LeafCoding::default());
+        let values: Vec<u8> = quadtree.leaves(&12u8).flat_map(|leaf| match leaf.value {
+            LeafValue::Average(v) => [v; 4],
+            LeafValue::Pixels((a, b, c, d)) | LeafValue::Corners((a, b, c, d)) => [a, b, c, d],
+        }).collect();
+        assert_eq!(values, data);
+    }
+}
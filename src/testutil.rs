@@ -0,0 +1,19 @@
+use image::{ DynamicImage, ImageBuffer, RgbImage };
+use crate::compressor::ImgCompressor;
+
+pub fn sample_image(rank: u32) -> RgbImage {
+    let r = rank as f32;
+    return ImageBuffer::from_fn(rank, rank, |x, y| {
+        let (fx, fy) = (x as f32 / r, y as f32 / r);
+        let ring = (((fx - 0.5).powi(2) + (fy - 0.5).powi(2)).sqrt() * 24f32).sin();
+        image::Rgb([
+            (fx * 255f32) as u8,
+            (128f32 + ring * 100f32) as u8,
+            if fx + fy > 1f32 { 200 } else { 40 },
+        ])
+    });
+}
+
+pub fn compressor(img: &RgbImage) -> ImgCompressor {
+    return ImgCompressor::new(DynamicImage::ImageRgb8(img.clone())).unwrap();
+}
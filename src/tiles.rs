@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use image::{ DynamicImage, GenericImage, GenericImageView, RgbImage };
+use crate::compressor::{ next_compressible, CompressError, Cutoff, ImgCompressor };
+use crate::quadtree::Quadtree;
+use crate::serialize::{ BoundedReader, DecodeError };
+
+pub const TILE_SIZE: u32 = 256;
+
+pub const MIN_TILE_SIZE: u32 = 16;
+
+pub const TILED_MAGIC: [u8; 4] = *b"SKIT";
+
+fn bytes_per_pixel() -> u64 {
+    let node = (std::mem::size_of::<Quadtree>() + std::mem::size_of::<Box<Quadtree>>()) as u64;
+    return 3 * (1 + node / 3) + 3;
+}
+
+pub fn suggest_tile_size(width: u32, height: u32, target_memory: u64) -> u32 {
+    let (largest, _) = next_compressible(width, height);
+    let mut tile = MIN_TILE_SIZE.min(largest);
+    while tile < largest && (2 * tile as u64).pow(2) * bytes_per_pixel() <= target_memory {
+        tile *= 2;
+    }
+    return tile;
+}
+
+pub struct TileDecoder {
+    compressor: ImgCompressor,
+    cache: HashMap<(u32, u32), RgbImage>,
+    pub tile_size: u32,
+}
+
+impl TileDecoder {
+    pub fn new(bytes: &[u8]) -> Result<TileDecoder, DecodeError> {
+        let compressor = ImgCompressor::from_file(bytes)?;
+        return Ok(TileDecoder { compressor, cache: HashMap::new(), tile_size: TILE_SIZE });
+    }
+
+    pub fn tiles(&self) -> (u32, u32) {
+        let size = self.tile_size;
+        return (self.compressor.width.div_ceil(size), self.compressor.height.div_ceil(size));
+    }
+
+    pub fn get_tile(&mut self, tx: u32, ty: u32) -> RgbImage {
+        let size = self.tile_size;
+        let compressor = &self.compressor;
+        return self.cache.entry((tx, ty))
+            .or_insert_with(|| compressor.to_image_region((0, 0, 0), (tx * size, ty * size), (size, size)))
+            .clone();
+    }
+
+    pub fn cached(&self) -> usize {
+        return self.cache.len();
+    }
+}
+
+/// Compresses `img` as independent `tile_size` square tiles, each a full
+/// `to_file` payload with its own quadtrees. The container holds magic
+/// `SKIT`, the u32 width, height and tile size, one u32 end offset per tile
+/// in row-major order, and then the tiles back to back.
+pub fn encode_tiled(img: &RgbImage, tile_size: u32, cutoffs: Cutoff) -> Result<Vec<u8>, CompressError> {
+    if tile_size == 0 {
+        return Err(CompressError::InvalidTileSize(tile_size));
+    }
+    let (width, height) = img.dimensions();
+    let mut tiles = vec![];
+    for ty in 0..height.div_ceil(tile_size) {
+        for tx in 0..width.div_ceil(tile_size) {
+            let (x, y) = (tx * tile_size, ty * tile_size);
+            let tile = img.view(x, y, tile_size.min(width - x), tile_size.min(height - y)).to_image();
+            tiles.push(ImgCompressor::new(DynamicImage::ImageRgb8(tile))?.to_file(cutoffs));
+        }
+    }
+    let mut bytes = TILED_MAGIC.to_vec();
+    for value in [width, height, tile_size].iter() {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    let mut end = 0;
+    for tile in tiles.iter() {
+        end += tile.len() as u32;
+        bytes.extend_from_slice(&end.to_le_bytes());
+    }
+    return Ok([bytes, tiles.concat()].concat());
+}
+
+/// A parsed `encode_tiled` container that decodes tiles on demand.
+pub struct TiledImage<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub tile_size: u32,
+    ends: Vec<usize>,
+    body: &'a [u8],
+}
+
+impl<'a> TiledImage<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<TiledImage<'a>, DecodeError> {
+        let mut reader = BoundedReader::new(bytes);
+        let mut u32 = || -> Result<u32, DecodeError> {
+            let b = reader.section(4)?;
+            return Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        };
+        let magic = u32()?.to_le_bytes();
+        if magic != TILED_MAGIC {
+            return Err(DecodeError::BadMagic(magic));
+        }
+        let (width, height, tile_size) = (u32()?, u32()?, u32()?);
+        if tile_size == 0 {
+            return Err(DecodeError::InvalidDimensions { rank: tile_size, width, height });
+        }
+        let count = width.div_ceil(tile_size) as u64 * height.div_ceil(tile_size) as u64;
+        let mut ends = vec![];
+        for _ in 0..count {
+            ends.push(u32()? as usize);
+        }
+        let body = reader.rest();
+        let mut start = 0;
+        for end in ends.iter() {
+            if *end < start || *end > body.len() {
+                return Err(DecodeError::OutOfBounds { offset: start, len: end.saturating_sub(start), end: body.len() });
+            }
+            start = *end;
+        }
+        return Ok(TiledImage { width, height, tile_size, ends, body });
+    }
+
+    pub fn tiles(&self) -> (u32, u32) {
+        return (self.width.div_ceil(self.tile_size), self.height.div_ceil(self.tile_size));
+    }
+
+    pub fn tile(&self, tx: u32, ty: u32) -> Result<RgbImage, DecodeError> {
+        let (columns, rows) = self.tiles();
+        if tx >= columns || ty >= rows {
+            return Err(DecodeError::NoSuchTile { tx, ty });
+        }
+        let i = tx as usize + ty as usize * columns as usize;
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        return Ok(ImgCompressor::from_file(&self.body[start..self.ends[i]])?.to_image((0, 0, 0)));
+    }
+
+    /// Decodes only the tiles overlapping the viewport and crops them to it.
+    pub fn decode_viewport(&self, (x, y): (u32, u32), (width, height): (u32, u32)) -> Result<RgbImage, DecodeError> {
+        let width = width.min(self.width.saturating_sub(x));
+        let height = height.min(self.height.saturating_sub(y));
+        let mut image = RgbImage::new(width, height);
+        if width == 0 || height == 0 {
+            return Ok(image);
+        }
+        let size = self.tile_size;
+        for ty in y / size..=(y + height - 1) / size {
+            for tx in x / size..=(x + width - 1) / size {
+                let tile = self.tile(tx, ty)?;
+                let (left, top) = (tx * size, ty * size);
+                let (x0, y0) = (x.max(left), y.max(top));
+                let (x1, y1) = ((x + width).min(left + tile.width()), (y + height).min(top + tile.height()));
+                let part = tile.view(x0 - left, y0 - top, x1 - x0, y1 - y0).to_image();
+                image.copy_from(&part, x0 - x, y0 - y).map_err(|_| DecodeError::UnexpectedEof)?;
+            }
+        }
+        return Ok(image);
+    }
+
+    pub fn to_image(&self) -> Result<RgbImage, DecodeError> {
+        return self.decode_viewport((0, 0), (self.width, self.height));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::GenericImageView;
+    use crate::testutil::{compressor, sample_image};
+    use super::*;
+
+    #[test]
+    fn tiles_match_full_decode() {
+        let bytes = compressor(&sample_image(40)).to_file((4, 8, 8));
+        let full = ImgCompressor::from_file(&bytes).unwrap().to_image((0, 0, 0));
+        let mut tiles = TileDecoder::new(&bytes).unwrap();
+        tiles.tile_size = 16;
+        assert_eq!(tiles.tiles(), (3, 3));
+        let inner = tiles.get_tile(1, 0);
+        assert_eq!(inner, full.view(16, 0, 16, 16).to_image());
+        let corner = tiles.get_tile(2, 2);
+        assert_eq!(corner.dimensions(), (8, 8));
+        assert_eq!(corner, full.view(32, 32, 8, 8).to_image());
+        tiles.get_tile(1, 0);
+        assert_eq!(tiles.cached(), 2);
+    }
+
+    #[test]
+    fn tiled_encoding_matches_the_single_tree() {
+        let img = sample_image(1024);
+        let single = compressor(&img).to_image((4, 8, 8));
+        let bytes = encode_tiled(&img, 512, (4, 8, 8)).unwrap();
+        let tiled = TiledImage::parse(&bytes).unwrap();
+        assert_eq!(tiled.tiles(), (2, 2));
+        let whole = tiled.to_image().unwrap();
+        for (x, y) in [(0, 0), (512, 0), (0, 512), (512, 512)].iter() {
+            let quadrant = |image: &RgbImage| image.view(*x, *y, 512, 512).to_image();
+            assert!(crate::metrics::psnr(&quadrant(&single), &quadrant(&whole)) > 40f64);
+        }
+        assert_eq!(tiled.decode_viewport((500, 300), (40, 400)).unwrap(), whole.view(500, 300, 40, 400).to_image());
+        assert_eq!(TiledImage::parse(&bytes[..30]).map(|_| ()), Err(DecodeError::OutOfBounds { offset: 28, len: 4, end: 30 }));
+        assert_eq!(tiled.tile(2, 0).map(|_| ()), Err(DecodeError::NoSuchTile { tx: 2, ty: 0 }));
+    }
+
+    #[test]
+    fn malformed_tile_grids_are_rejected() {
+        assert_eq!(encode_tiled(&sample_image(8), 0, (0, 0, 0)), Err(CompressError::InvalidTileSize(0)));
+        let header = |width: u32, height: u32, tile_size: u32| -> Vec<u8> {
+            return [&TILED_MAGIC[..], &width.to_le_bytes(), &height.to_le_bytes(), &tile_size.to_le_bytes()].concat();
+        };
+        assert_eq!(TiledImage::parse(&header(8, 8, 0)).map(|_| ()), Err(DecodeError::InvalidDimensions { rank: 0, width: 8, height: 8 }));
+        let huge = header(u32::MAX, u32::MAX, 1);
+        assert_eq!(TiledImage::parse(&huge).map(|_| ()), Err(DecodeError::OutOfBounds { offset: 16, len: 4, end: 16 }));
+    }
+
+    #[test]
+    fn suggested_tile_sizes_fit_the_budget() {
+        for (width, height) in [(100, 100), (1000, 700), (8192, 8192)].iter() {
+            for budget in [0u64, 1 << 20, 64 << 20, 1 << 40].iter() {
+                let tile = suggest_tile_size(*width, *height, *budget);
+                assert!(tile.is_power_of_two());
+                assert!(tile >= MIN_TILE_SIZE && tile <= next_compressible(*width, *height).0);
+                assert!(tile == MIN_TILE_SIZE || (tile as u64).pow(2) * bytes_per_pixel() <= *budget);
+            }
+        }
+        assert_eq!(suggest_tile_size(100, 100, 1 << 40), 128);
+        assert!(suggest_tile_size(8192, 8192, 1 << 20) < suggest_tile_size(8192, 8192, 64 << 20));
+    }
+}
@@ -0,0 +1,57 @@
+//! Byte-slice entry points shaped for a JavaScript wrapper: plain RGBA in
+//! and out, and string errors so they can become exceptions. This is plain
+//! Rust only; there is no `wasm-bindgen` dependency, no `#[wasm_bindgen]`
+//! export and no `wasm32` test target yet, so a caller still has to write the
+//! binding layer over these functions.
+
+use image::DynamicImage;
+use crate::compressor::{ CompressConfig, ImgCompressor };
+use crate::header::parse_header;
+use crate::entropy;
+
+/// Compresses `width * height` RGBA pixels into a `.ski` file; alpha is
+/// dropped.
+pub fn compress_rgba(pixels: &[u8], width: u32, height: u32, cutoff: u8) -> Result<Vec<u8>, String> {
+    let expected = width as usize * height as usize * 4;
+    if pixels.len() != expected {
+        return Err(format!("expected {} RGBA bytes, got {}", expected, pixels.len()));
+    }
+    let rgb: Vec<u8> = pixels.chunks(4).flat_map(|pixel| pixel[..3].to_vec()).collect();
+    let compressor = ImgCompressor::from_rgb(&rgb, width, height).map_err(|error| format!("{:?}", error))?;
+    let config = CompressConfig::new();
+    return Ok(entropy::encode(&*config.backend.codec(config.level), &compressor.to_file((cutoff, cutoff, cutoff))));
+}
+
+/// Decodes a `.ski` file to opaque RGBA pixels, row by row.
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let compressor = ImgCompressor::from_compressed_file(bytes).map_err(|error| format!("{:?}", error))?;
+    return Ok(DynamicImage::ImageRgb8(compressor.to_image((0, 0, 0))).to_rgba8().into_raw());
+}
+
+/// Width and height of a `.ski` file, to size the canvas for `decompress`.
+pub fn dimensions(bytes: &[u8]) -> Result<Vec<u32>, String> {
+    let payload = entropy::decode(bytes).map_err(|error| format!("{:?}", error))?;
+    let (header, _) = parse_header(&payload).map_err(|error| format!("{:?}", error))?;
+    return Ok(vec![header.width, header.height]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgba_round_trips_through_the_exports() {
+        let pixels: Vec<u8> = (0..24 * 10).flat_map(|i| [(i * 7) as u8, (i * 3) as u8, 90, 17]).collect();
+        let file = compress_rgba(&pixels, 24, 10, 0).unwrap();
+        assert_eq!(dimensions(&file).unwrap(), vec![24, 10]);
+        let decoded = decompress(&file).unwrap();
+        assert_eq!(decoded.len(), pixels.len());
+        assert!(decoded.chunks(4).all(|pixel| pixel[3] == 255));
+        let error = decoded.chunks(4).zip(pixels.chunks(4))
+            .flat_map(|(a, b)| (0..3).map(move |i| (a[i] as i32 - b[i] as i32).abs()))
+            .max().unwrap();
+        assert!(error <= 3, "max error {}", error);
+        assert!(compress_rgba(&pixels[1..], 24, 10, 0).is_err());
+        assert!(decompress(&[0, 1, 2]).is_err());
+    }
+}
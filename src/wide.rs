@@ -0,0 +1,137 @@
+use bitvec::prelude::Local;
+use bitvec::slice::BitSlice;
+use bitvec::vec::BitVec;
+use image::{ DynamicImage, ImageBuffer, Rgb };
+use crate::compressor::{ ColorSpace, CompressError, Cutoff };
+use crate::header::{ parse_header, FileHeader, FLAG_WIDE_SAMPLES, MAGIC, VERSION };
+use crate::quadtree::{ pad_to_pow2, WideTree };
+use crate::serialize::{ build_wide_leaf_data, build_wide_leaf_index, read_wide_leaf_tree, BoundedReader, DecodeError };
+
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+
+/// Compresses 16-bit RGB images without truncating them to 8 bits. The
+/// channels are stored directly as red, green and blue trees, and cutoffs
+/// are given in the same 8-bit units as `ImgCompressor`.
+pub struct WideCompressor {
+    roots: [Box<WideTree>; 3],
+    rank: u32,
+    width: u32,
+    height: u32,
+}
+
+impl WideCompressor {
+    pub fn new(img: DynamicImage) -> Result<WideCompressor, CompressError> {
+        return WideCompressor::from_rgb16(&img.to_rgb16());
+    }
+
+    pub fn from_rgb16(rgb: &Rgb16Image) -> Result<WideCompressor, CompressError> {
+        let (width, height) = rgb.dimensions();
+        if width == 0 || height == 0 {
+            return Err(CompressError::EmptyImage);
+        }
+        let mut planes = [vec![], vec![], vec![]];
+        for pixel in rgb.pixels() {
+            for (plane, value) in planes.iter_mut().zip(pixel.0.iter()) {
+                plane.push(*value);
+            }
+        }
+        let roots = planes.map(|plane| WideTree::new(&pad_to_pow2(&plane, width as usize, height as usize).0));
+        let rank = roots[0].size() as u32;
+        return Ok(WideCompressor { roots, rank, width, height });
+    }
+
+    pub fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    pub fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    pub fn to_image(&self, cutoffs: Cutoff) -> Rgb16Image {
+        let cutoffs = [cutoffs.0, cutoffs.1, cutoffs.2];
+        return ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let p = (x as usize, y as usize);
+            return Rgb([0, 1, 2].map(|i| self.roots[i].get_mapped(p, &cutoffs[i])));
+        });
+    }
+
+    /// Same layout as `ImgCompressor::to_file` with `FLAG_WIDE_SAMPLES` set
+    /// and two bytes per leaf value.
+    pub fn to_file(&self, cutoffs: Cutoff) -> Vec<u8> {
+        let channel_cutoffs = [cutoffs.0, cutoffs.1, cutoffs.2];
+        let mut indexes = vec![];
+        let mut sections = vec![];
+        for (root, cutoff) in self.roots.iter().zip(channel_cutoffs.iter()) {
+            let mut index: BitVec<Local, u8> = BitVec::new();
+            build_wide_leaf_index(root, &mut index, cutoff);
+            let mut data = vec![];
+            build_wide_leaf_data(root, &mut data, cutoff);
+            indexes.push(index.into_vec());
+            sections.push(data);
+        }
+        let header = FileHeader {
+            magic: MAGIC,
+            version: VERSION,
+            color_space: ColorSpace::Rgb.id(),
+            flags: FLAG_WIDE_SAMPLES,
+            rank: self.rank,
+            width: self.width,
+            height: self.height,
+            cutoffs,
+            prediction: (128, 128, 128),
+            index_lens: [0, 1, 2].map(|i| indexes[i].len() as u32),
+            data_lens: [0, 1, 2].map(|i| sections[i].len() as u32),
+        };
+        let mut bytes = header.to_bytes();
+        for section in indexes.iter().chain(sections.iter()) {
+            bytes.extend_from_slice(section);
+        }
+        return bytes;
+    }
+
+    pub fn from_file(bytes: &[u8]) -> Result<WideCompressor, DecodeError> {
+        let (header, body) = parse_header(bytes)?;
+        if header.flags & FLAG_WIDE_SAMPLES == 0 {
+            return Err(DecodeError::UnsupportedBitDepth(8));
+        }
+        let mut reader = BoundedReader::new(body);
+        let mut sections = vec![];
+        for len in header.index_lens.iter().chain(header.data_lens.iter()) {
+            sections.push(reader.section(*len as usize)?);
+        }
+        let rank = header.rank as usize;
+        let channel = |i: usize| read_wide_leaf_tree(BitSlice::from_slice(sections[i]), &mut sections[i + 3].iter().copied(), rank);
+        let roots = [channel(0)?, channel(1)?, channel(2)?];
+        return Ok(WideCompressor { roots, rank: header.rank, width: header.width, height: header.height });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compressor::ImgCompressor;
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> Rgb16Image {
+        return ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb([(x * 997 + y * 3) as u16, (y * 1013) as u16, 65535 - (x * 701 + y * 389) as u16])
+        });
+    }
+
+    #[test]
+    fn sixteen_bit_gradient_survives_at_cutoff_zero() {
+        let image = gradient(40, 24);
+        let compressor = WideCompressor::from_rgb16(&image).unwrap();
+        let decoded = WideCompressor::from_file(&compressor.to_file((0, 0, 0))).unwrap();
+        assert_eq!(decoded.to_image((0, 0, 0)), image);
+        assert!(image.pixels().any(|pixel| pixel.0[0] % 257 != 0));
+    }
+
+    #[test]
+    fn bit_depth_is_checked_on_both_paths() {
+        let wide = WideCompressor::from_rgb16(&gradient(8, 8)).unwrap().to_file((4, 4, 4));
+        assert_eq!(ImgCompressor::from_file(&wide).map(|_| ()), Err(DecodeError::UnsupportedBitDepth(16)));
+        let narrow = crate::testutil::compressor(&crate::testutil::sample_image(8)).to_file((4, 4, 4));
+        assert_eq!(WideCompressor::from_file(&narrow).map(|_| ()), Err(DecodeError::UnsupportedBitDepth(8)));
+    }
+}